@@ -0,0 +1,322 @@
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+use std::{env, fmt, fs, process};
+
+use rusty_gigabyte::gameboy::boot::{Boot, load_boot_rom, Model, parse_model};
+use rusty_gigabyte::gameboy::cartridge::new_cartridge_from_file;
+use rusty_gigabyte::gameboy::serial::LinkPartner;
+use rusty_gigabyte::gameboy::symbols::{find_symbols, load_symbols};
+use rusty_gigabyte::gameboy::trace::{new_tracer, parse_trace_option, TRACE_OPTIONS, TraceConfig};
+use rusty_gigabyte::gameboy::{GameBoy, GameBoyConfig, new_game_boy};
+
+/*
+    Runs a ROM without a window until it finishes, for running test ROMs in CI.
+
+    The exit code says how the run ended, see Outcome. When a ROM stops at LD B,B it follows the
+    Mooneye test convention: B, C, D, E, H and L hold the Fibonacci numbers 3, 5, 8, 13, 21 and 34
+    if the test passed.
+ */
+
+const USAGE: &str = "\
+Usage: rusty-gigabyte-headless [options] <rom>
+
+Runs until one of the conditions is met, or gives up after --frames.
+
+Conditions:
+    --until-serial <text>    Pass once the serial output contains text, e.g. Passed
+    --fail-serial <text>     Fail once the serial output contains text, e.g. Failed
+    --until-pc <addr>        Pass once PC reaches addr, in hex
+    --until-ld-b-b           Stop at LD B,B, passing if the registers hold the Mooneye pass values
+
+Options:
+    --frames <n>             Give up after n frames (default 3600, a minute of GB time)
+    --screenshot <file>      Write the screen to a PNG once finished
+    --serial-log <file>      Write everything sent over the serial port to a file
+    --boot-rom <file>        Run a dump of the model's boot ROM instead of the built in one
+    --skip-boot              Start the cartridge straight away, as the model's boot ROM would leave it
+    --model <model>          dmg0, dmg, mgb, sgb or cgb (default dmg)
+    --trace <file>           Log every instruction, see rusty-gigabyte --help for the --trace-* filters
+    --trace-ring <n>         Only write the last n instructions, when the CPU crashes or --until-pc or
+                             --until-ld-b-b stops the run
+    --sym <file>             Labels for the trace from an RGBDS .sym file (default the ROM's name with .sym)
+    --help                   Show this message
+
+Exit codes:
+    0    a pass condition was met, or the frames ran out when there are no conditions
+    1    a fail condition was met
+    2    the frames ran out before any condition was met
+    3    the arguments or ROM were invalid";
+
+const DEFAULT_FRAMES: u64 = 3600;
+
+// LD B,B, used by test ROMs as a breakpoint
+const OPCODE_LD_B_B: u8 = 0x40;
+
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+struct Args {
+    rom: String,
+    frames: u64,
+    until_serial: Option<String>,
+    fail_serial: Option<String>,
+    until_pc: Option<u16>,
+    until_ld_b_b: bool,
+    screenshot: Option<String>,
+    serial_log: Option<String>,
+    boot_rom: Option<String>,
+    skip_boot: bool,
+    model: Model,
+    trace: Option<String>,
+    trace_config: TraceConfig,
+    sym: Option<String>,
+}
+
+enum Outcome {
+    Passed(String),
+    Failed(String),
+    TimedOut,
+}
+
+impl Outcome {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Passed(_) => 0,
+            Outcome::Failed(_) => 1,
+            Outcome::TimedOut => 2,
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Passed(why) => write!(f, "passed: {}", why),
+            Outcome::Failed(why) => write!(f, "failed: {}", why),
+            Outcome::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+/*
+    Nothing on the other end of the cable, but keeps everything the GB sends
+ */
+struct SerialCapture {
+    sent: Arc<Mutex<Vec<u8>>>,
+}
+
+impl LinkPartner for SerialCapture {
+    fn exchange(&mut self, out: u8) -> u8 {
+        self.sent.lock().unwrap().push(out);
+
+        // No partner pulls the line high
+        0xFF
+    }
+
+    fn poll(&mut self, _out: u8) -> Option<u8> {
+        None
+    }
+}
+
+fn main() {
+    let args = match parse_args(env::args().skip(1).collect()) {
+        Ok(args) => args,
+        Err(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(Some(e)) => {
+            eprintln!("error: {}", e);
+            process::exit(3);
+        }
+    };
+
+    match run(&args) {
+        Ok(outcome) => {
+            println!("{}", outcome);
+            process::exit(outcome.exit_code());
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(3);
+        }
+    }
+}
+
+fn run(args: &Args) -> Result<Outcome, Box<dyn std::error::Error>> {
+    let cart = new_cartridge_from_file(&args.rom).map_err(|e| format!("unable to load ROM {}: {}", args.rom, e))?;
+
+    let boot = match &args.boot_rom {
+        Some(path) => Some(Boot::Rom(load_boot_rom(path, args.model)?)),
+        None if args.skip_boot => Some(Boot::Skip(args.model)),
+        None if args.model != Model::Dmg => return Err(format!("there's no built in {} boot ROM, use --boot-rom or --skip-boot", args.model).into()),
+        None => None,
+    };
+
+    let symbols = match args.sym.clone().or_else(|| find_symbols(&args.rom)) {
+        Some(path) => Some(load_symbols(&path)?),
+        None => None,
+    };
+
+    let serial = Arc::new(Mutex::new(vec!()));
+
+    let mut game_boy = new_game_boy(cart, GameBoyConfig {
+        boot,
+        palette: None,
+        link: Some(Box::new(SerialCapture { sent: serial.clone() })),
+        symbols,
+        ..Default::default()
+    });
+
+    if let Some(path) = &args.trace {
+        let file = File::create(path).map_err(|e| format!("unable to create trace {}: {}", path, e))?;
+        game_boy.set_trace(new_tracer(Box::new(file), args.trace_config.clone()));
+    }
+
+    let outcome = run_until(&mut game_boy, args, &serial);
+
+    game_boy.flush_trace();
+
+    if let Some(path) = &args.screenshot {
+        game_boy.write_screenshot(path).map_err(|e| format!("unable to write screenshot {}: {}", path, e))?;
+    }
+
+    if let Some(path) = &args.serial_log {
+        fs::write(path, &*serial.lock().unwrap()).map_err(|e| format!("unable to write serial log {}: {}", path, e))?;
+    }
+
+    Ok(outcome)
+}
+
+fn run_until(game_boy: &mut GameBoy, args: &Args, serial: &Mutex<Vec<u8>>) -> Outcome {
+    let has_condition = args.until_serial.is_some() || args.fail_serial.is_some() || args.until_pc.is_some() || args.until_ld_b_b;
+
+    // How much of the serial output has been checked
+    let mut serial_checked = 0;
+
+    for frame in 0..args.frames {
+        loop {
+            let pc = game_boy.pc();
+
+            if args.until_pc == Some(pc) {
+                game_boy.dump_trace(&format!("reaching PC {:04X}", pc));
+
+                return Outcome::Passed(format!("reached PC {:#06x} on frame {}", pc, frame));
+            }
+
+            if args.until_ld_b_b && game_boy.read_byte(pc) == OPCODE_LD_B_B {
+                let r = game_boy.registers();
+
+                game_boy.dump_trace(&format!("LD B,B at {:04X}", pc));
+
+                return if [r.b, r.c, r.d, r.e, r.h, r.l] == MOONEYE_PASS {
+                    Outcome::Passed(format!("LD B,B at {:#06x} on frame {}", pc, frame))
+                } else {
+                    Outcome::Failed(format!("LD B,B at {:#06x} on frame {} with B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X}", pc, frame, r.b, r.c, r.d, r.e, r.h, r.l))
+                };
+            }
+
+            game_boy.step_instruction();
+
+            if !game_boy.mid_frame() {
+                break;
+            }
+        }
+
+        let sent = serial.lock().unwrap();
+
+        if sent.len() > serial_checked {
+            let output = String::from_utf8_lossy(&sent);
+
+            if let Some(text) = args.fail_serial.as_ref().filter(|text| output.contains(text.as_str())) {
+                return Outcome::Failed(format!("serial output contained \"{}\" on frame {}", text, frame));
+            }
+
+            if let Some(text) = args.until_serial.as_ref().filter(|text| output.contains(text.as_str())) {
+                return Outcome::Passed(format!("serial output contained \"{}\" on frame {}", text, frame));
+            }
+
+            serial_checked = sent.len();
+        }
+    }
+
+    if has_condition {
+        Outcome::TimedOut
+    } else {
+        Outcome::Passed(format!("ran {} frames", args.frames))
+    }
+}
+
+/*
+    Err(None) when help was asked for
+ */
+fn parse_args(args: Vec<String>) -> Result<Args, Option<String>> {
+    let mut parsed = Args {
+        rom: String::new(),
+        frames: DEFAULT_FRAMES,
+        until_serial: None,
+        fail_serial: None,
+        until_pc: None,
+        until_ld_b_b: false,
+        screenshot: None,
+        serial_log: None,
+        boot_rom: None,
+        skip_boot: false,
+        model: Model::Dmg,
+        trace: None,
+        trace_config: TraceConfig::default(),
+        sym: None,
+    };
+
+    let mut rom = None;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| Some(format!("{} needs a value", arg)));
+        let invalid = |value: &str, expected: &str| Some(format!("invalid value \"{}\" for {}, expected {}", value, arg, expected));
+
+        match arg.as_str() {
+            "--help" | "-h" => return Err(None),
+            "--frames" => {
+                let n = value()?;
+                parsed.frames = n.parse().map_err(|_| invalid(&n, "a number of frames"))?;
+            }
+            "--until-serial" => parsed.until_serial = Some(value()?),
+            "--fail-serial" => parsed.fail_serial = Some(value()?),
+            "--until-pc" => {
+                let addr = value()?;
+                parsed.until_pc = Some(u16::from_str_radix(addr.trim_start_matches("0x"), 16).map_err(|_| invalid(&addr, "a hex address"))?);
+            }
+            "--until-ld-b-b" => parsed.until_ld_b_b = true,
+            "--screenshot" => parsed.screenshot = Some(value()?),
+            "--serial-log" => parsed.serial_log = Some(value()?),
+            "--boot-rom" => parsed.boot_rom = Some(value()?),
+            "--skip-boot" => parsed.skip_boot = true,
+            "--model" => {
+                let name = value()?;
+                parsed.model = parse_model(&name).map_err(|expected| invalid(&name, expected))?;
+            }
+            "--trace" => parsed.trace = Some(value()?),
+            "--sym" => parsed.sym = Some(value()?),
+            _ if TRACE_OPTIONS.contains(&arg.as_str()) => {
+                let v = value()?;
+                parse_trace_option(&mut parsed.trace_config, &arg, &v).map_err(|expected| invalid(&v, expected))?;
+            }
+            _ if arg.starts_with('-') => return Err(Some(format!("unknown option {}, see --help", arg))),
+            _ if rom.is_some() => return Err(Some(format!("unexpected argument \"{}\", only one ROM can be given", arg))),
+            _ => rom = Some(arg),
+        }
+    }
+
+    parsed.rom = rom.ok_or_else(|| Some(format!("no ROM given\n\n{}", USAGE)))?;
+
+    if parsed.skip_boot && parsed.boot_rom.is_some() {
+        return Err(Some("--skip-boot can't be used with --boot-rom".to_string()));
+    }
+
+    if parsed.trace.is_none() && parsed.trace_config.needs_trace() {
+        return Err(Some("--trace-* needs --trace".to_string()));
+    }
+
+    Ok(parsed)
+}
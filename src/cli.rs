@@ -0,0 +1,384 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use crate::gameboy::{Palette, PALETTES};
+use crate::gameboy::boot::{Model, parse_model};
+use crate::gameboy::debug::{DebugConfig, gb_doctor};
+use crate::gameboy::state::DEFAULT_SAVE_DIR;
+use crate::gameboy::trace::{parse_trace_option, TRACE_OPTIONS, TraceConfig};
+
+/*
+    Command line arguments. Flags can come before or after the ROM.
+ */
+
+pub const USAGE: &str = "\
+Usage: rusty-gigabyte [options] <rom>
+       rusty-gigabyte disasm [--bank <n>] <rom>
+
+<rom> is a path to a ROM file, or an http(s) URL to download one from.
+
+Options:
+    --boot-rom <file>        Run a dump of the model's boot ROM instead of the built in one
+    --skip-boot              Start the cartridge straight away, as the model's boot ROM would leave it
+    --model <model>          The GB to emulate: dmg0, dmg, mgb, sgb or cgb (default dmg)
+    --scale <n>              Scale the window by n (default 1)
+    --palette <palette>      grey, green, pocket, or 4 colours lightest first, e.g. ffffff,aaaaaa,555555,000000
+    --headless               Run without a window
+    --frames <n>             Stop after n frames
+    --screenshot <file>      Write the screen to a PNG once finished, needs --frames
+    --trace <file>           Write a Gameboy Doctor log of every instruction
+    --trace-format <format>  doctor, or full to add the frame, bank, cycles and interrupts
+    --trace-pc <from-to>     Only trace instructions between two hex addresses, e.g. 0150-01FF
+    --trace-bank <n>         Only trace instructions in ROM bank n
+    --trace-frames <from-to> Only trace these frames, e.g. 60-120, or 60- for 60 onwards
+    --trace-ring <n>         Only write the last n instructions, when the CPU crashes
+    --dump-frames <file>     Write every frame to a file as raw RGB, e.g. to make a video
+    --save-dir <dir>         Where save states are kept (default saves)
+    --config <file>          Key bindings and settings (default config.toml)
+    --record <file>          Record the joypad to a movie
+    --play <file>            Play back a movie
+    --link-listen <addr>     Wait for a link cable partner, e.g. 127.0.0.1:5000
+    --link-connect <addr>    Connect the link cable to a partner
+    --printer <dir>          Plug in a Game Boy Printer, writing prints to dir
+    --linked                 Run two GBs linked together, side by side
+    --gb-doctor              Print a Gameboy Doctor log to stdout, skipping the boot ROM with LY stuck at 0x90
+    --stub-ly                Always read LY as 0x90
+    --debug                  Stop in the debugger before the first instruction, type help for commands
+    --break <addr>           Stop in the debugger at an address, or BANK:ADDR, in hex, or a label with --sym
+    --watch <watchpoint>     Stop in the debugger on a memory access, e.g. c000-c0ff,rw (see watch in the debugger help)
+    --sym <file>             Labels for traces and the debugger from an RGBDS .sym file (default the ROM's name with .sym)
+    --gdb <addr>             Wait for GDB to connect, e.g. 127.0.0.1:2159, stopped before the first instruction
+    --help                   Show this message";
+
+pub const DISASM_USAGE: &str = "\
+Usage: rusty-gigabyte disasm [--bank <n>] [--sym <file>] <rom>
+
+Disassembles a ROM into RGBDS syntax, one bank after another. Data is disassembled as if it were code.
+
+Options:
+    --bank <n>               Only disassemble bank n
+    --sym <file>             Use the labels in an RGBDS .sym file (default the ROM's name with .sym, if there is one)
+    --help                   Show this message";
+
+/*
+    What to plug into the link port
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkArg {
+    Listen(String),
+    Connect(String),
+    Printer(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieArg {
+    Record(String),
+    Play(String),
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub rom: String,
+    pub boot_rom: Option<String>,
+    pub skip_boot: bool,
+    pub model: Model,
+    pub scale: u32,
+    pub palette: Palette,
+    pub headless: bool,
+    pub frames: Option<u64>,
+    pub screenshot: Option<String>,
+    pub trace: Option<String>,
+    pub trace_config: TraceConfig,
+
+    // An RGBDS .sym file
+    pub sym: Option<String>,
+    pub dump_frames: Option<String>,
+    pub save_dir: String,
+    pub config: Option<String>,
+    pub movie: Option<MovieArg>,
+    pub link: Option<LinkArg>,
+    pub linked: bool,
+    pub debug: DebugConfig,
+    pub debugger: bool,
+    // Read once the symbols are loaded, as they can use labels
+    pub breakpoints: Vec<String>,
+    pub watchpoints: Vec<String>,
+
+    // Where to listen for GDB
+    pub gdb: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct DisasmArgs {
+    pub rom: String,
+    pub bank: Option<usize>,
+    pub sym: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    Help,
+    MissingValue(String),
+    InvalidValue(String, String, &'static str),
+    UnknownOption(String),
+    NoRom,
+    ExtraArgument(String),
+    Conflict(&'static str, &'static str),
+    Needs(&'static str, &'static str),
+}
+
+impl Display for CliError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Help => write!(f, "{}", USAGE),
+            CliError::MissingValue(flag) => write!(f, "{} needs a value", flag),
+            CliError::InvalidValue(flag, value, expected) => write!(f, "invalid value \"{}\" for {}, expected {}", value, flag, expected),
+            CliError::UnknownOption(flag) => write!(f, "unknown option {}, see --help", flag),
+            CliError::NoRom => write!(f, "no ROM given\n\n{}", USAGE),
+            CliError::ExtraArgument(arg) => write!(f, "unexpected argument \"{}\", only one ROM can be given", arg),
+            CliError::Conflict(a, b) => write!(f, "{} can't be used with {}", a, b),
+            CliError::Needs(a, b) => write!(f, "{} needs {}", a, b),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+/*
+    Parses the arguments, not including the program name
+ */
+pub fn parse_args(args: Vec<String>) -> Result<Args, CliError> {
+    let mut parsed = Args {
+        rom: String::new(),
+        boot_rom: None,
+        skip_boot: false,
+        model: Model::Dmg,
+        scale: 1,
+        palette: PALETTES[0].1,
+        headless: false,
+        frames: None,
+        screenshot: None,
+        trace: None,
+        trace_config: TraceConfig::default(),
+        sym: None,
+        dump_frames: None,
+        save_dir: DEFAULT_SAVE_DIR.to_string(),
+        config: None,
+        movie: None,
+        link: None,
+        linked: false,
+        debug: DebugConfig::default(),
+        debugger: false,
+        breakpoints: vec!(),
+        watchpoints: vec!(),
+        gdb: None,
+    };
+
+    let mut rom = None;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| CliError::MissingValue(arg.clone()));
+
+        match arg.as_str() {
+            "--help" | "-h" => return Err(CliError::Help),
+            "--boot-rom" => parsed.boot_rom = Some(value()?),
+            "--skip-boot" => parsed.skip_boot = true,
+            "--model" => {
+                let name = value()?;
+                parsed.model = parse_model(&name).map_err(|expected| CliError::InvalidValue(arg.clone(), name, expected))?;
+            }
+            "--scale" => {
+                let n = value()?;
+                parsed.scale = n.parse().ok().filter(|&s| s > 0)
+                    .ok_or_else(|| CliError::InvalidValue(arg.clone(), n, "a whole number of at least 1"))?;
+            }
+            "--palette" => {
+                let name = value()?;
+                parsed.palette = parse_palette(&name)
+                    .ok_or_else(|| CliError::InvalidValue(arg.clone(), name, "grey, green, pocket or 4 hex colours like ffffff,aaaaaa,555555,000000"))?;
+            }
+            "--headless" => parsed.headless = true,
+            "--frames" => {
+                let n = value()?;
+                parsed.frames = Some(n.parse().map_err(|_| CliError::InvalidValue(arg.clone(), n, "a number of frames"))?);
+            }
+            "--screenshot" => parsed.screenshot = Some(value()?),
+            "--trace" => parsed.trace = Some(value()?),
+            _ if TRACE_OPTIONS.contains(&arg.as_str()) => {
+                let v = value()?;
+                parse_trace_option(&mut parsed.trace_config, &arg, &v).map_err(|expected| CliError::InvalidValue(arg.clone(), v, expected))?;
+            }
+            "--dump-frames" => parsed.dump_frames = Some(value()?),
+            "--save-dir" => parsed.save_dir = value()?,
+            "--config" => parsed.config = Some(value()?),
+            "--record" => parsed.movie = Some(MovieArg::Record(value()?)),
+            "--play" => parsed.movie = Some(MovieArg::Play(value()?)),
+            "--link-listen" => parsed.link = Some(LinkArg::Listen(value()?)),
+            "--link-connect" => parsed.link = Some(LinkArg::Connect(value()?)),
+            "--printer" => parsed.link = Some(LinkArg::Printer(value()?)),
+            "--linked" => parsed.linked = true,
+            "--gb-doctor" => parsed.debug = gb_doctor(),
+            "--stub-ly" => parsed.debug.stub_ly = true,
+            "--debug" => parsed.debugger = true,
+            "--break" => parsed.breakpoints.push(value()?),
+            "--watch" => parsed.watchpoints.push(value()?),
+            "--sym" => parsed.sym = Some(value()?),
+            "--gdb" => parsed.gdb = Some(value()?),
+            _ if arg.starts_with('-') => return Err(CliError::UnknownOption(arg)),
+            _ if rom.is_some() => return Err(CliError::ExtraArgument(arg)),
+            _ => rom = Some(arg),
+        }
+    }
+
+    parsed.rom = rom.ok_or(CliError::NoRom)?;
+
+    if parsed.screenshot.is_some() && parsed.frames.is_none() {
+        return Err(CliError::Needs("--screenshot", "--frames"));
+    }
+
+    if parsed.trace.is_none() && parsed.trace_config.needs_trace() {
+        return Err(CliError::Needs("--trace-*", "--trace"));
+    }
+
+    if parsed.skip_boot && parsed.boot_rom.is_some() {
+        return Err(CliError::Conflict("--skip-boot", "--boot-rom"));
+    }
+
+    if parsed.debug.skip_boot && parsed.boot_rom.is_some() {
+        return Err(CliError::Conflict("--gb-doctor", "--boot-rom"));
+    }
+
+    if parsed.gdb.is_some() && (parsed.debugger || !parsed.breakpoints.is_empty() || !parsed.watchpoints.is_empty()) {
+        return Err(CliError::Conflict("--gdb", "--debug, --break or --watch"));
+    }
+
+    if parsed.linked {
+        if parsed.headless {
+            return Err(CliError::Conflict("--linked", "--headless"));
+        }
+
+        if parsed.link.is_some() {
+            return Err(CliError::Conflict("--linked", "--link-listen, --link-connect or --printer"));
+        }
+
+        if parsed.movie.is_some() {
+            return Err(CliError::Conflict("--linked", "--record or --play"));
+        }
+
+        if parsed.debugger || !parsed.breakpoints.is_empty() || !parsed.watchpoints.is_empty() {
+            return Err(CliError::Conflict("--linked", "--debug, --break or --watch"));
+        }
+
+        if parsed.gdb.is_some() {
+            return Err(CliError::Conflict("--linked", "--gdb"));
+        }
+
+        // Both GBs would be logging to the same place
+        if parsed.trace.is_some() {
+            return Err(CliError::Conflict("--linked", "--trace"));
+        }
+
+        if parsed.debug.trace {
+            return Err(CliError::Conflict("--linked", "--gb-doctor"));
+        }
+    }
+
+    Ok(parsed)
+}
+
+/*
+    Parses the arguments after disasm
+ */
+pub fn parse_disasm_args(args: Vec<String>) -> Result<DisasmArgs, CliError> {
+    let mut bank = None;
+    let mut sym = None;
+    let mut rom = None;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" | "-h" => return Err(CliError::Help),
+            "--bank" => {
+                let n = args.next().ok_or_else(|| CliError::MissingValue(arg.clone()))?;
+                bank = Some(n.parse().map_err(|_| CliError::InvalidValue(arg.clone(), n, "a bank number"))?);
+            }
+            "--sym" => sym = Some(args.next().ok_or_else(|| CliError::MissingValue(arg.clone()))?),
+            _ if arg.starts_with('-') => return Err(CliError::UnknownOption(arg)),
+            _ if rom.is_some() => return Err(CliError::ExtraArgument(arg)),
+            _ => rom = Some(arg),
+        }
+    }
+
+    Ok(DisasmArgs { rom: rom.ok_or(CliError::NoRom)?, bank, sym })
+}
+
+/*
+    A named palette, or 4 comma separated hex colours
+ */
+fn parse_palette(value: &str) -> Option<Palette> {
+    if let Some((_, palette)) = PALETTES.iter().find(|(n, _)| n.eq_ignore_ascii_case(value)) {
+        return Some(*palette);
+    }
+
+    let colors: Vec<&str> = value.split(',').collect();
+
+    if colors.len() != 4 {
+        return None;
+    }
+
+    let mut palette = [[0; 3]; 4];
+
+    for (shade, color) in palette.iter_mut().zip(colors) {
+        let color = color.trim().trim_start_matches('#');
+
+        if color.len() != 6 {
+            return None;
+        }
+
+        let rgb = u32::from_str_radix(color, 16).ok()?;
+
+        *shade = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+    }
+
+    Some(palette)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::trace::TraceFormat;
+
+    fn parse(args: &str) -> Result<Args, CliError> {
+        parse_args(args.split_whitespace().map(str::to_string).collect())
+    }
+
+    #[test]
+    fn parses_models_and_trace_filters() {
+        let args = parse("rom.gb --model DMG0 --trace t.log --trace-format full --trace-pc 0150-01ff --trace-bank 2 --trace-frames 60- --trace-ring 100").unwrap();
+        let c = &args.trace_config;
+
+        assert_eq!(args.model, Model::Dmg0);
+        assert_eq!((c.format, c.pcs.clone(), c.bank, c.frames.clone(), c.ring), (TraceFormat::Full, Some(0x150..=0x1FF), Some(2), Some(60..u64::MAX), Some(100)));
+
+        assert!(matches!(parse("rom.gb --model gba"), Err(CliError::InvalidValue(flag, _, _)) if flag == "--model"));
+        assert!(matches!(parse("rom.gb --trace t.log --trace-pc 0200-0100"), Err(CliError::InvalidValue(flag, _, "hex addresses like 0150-01FF")) if flag == "--trace-pc"));
+        assert!(matches!(parse("rom.gb --trace-ring 10"), Err(CliError::Needs("--trace-*", "--trace"))));
+    }
+
+    #[test]
+    fn linked_gbs_share_how_they_start() {
+        let args = parse("rom.gb --linked --model mgb --skip-boot --palette green --frames 60 --screenshot both.png").unwrap();
+
+        assert!(args.linked && args.skip_boot);
+        assert_eq!(args.model, Model::Mgb);
+        assert_eq!(args.frames, Some(60));
+    }
+
+    #[test]
+    fn linked_gbs_cant_be_traced() {
+        assert!(matches!(parse("rom.gb --linked --trace trace.log"), Err(CliError::Conflict("--linked", "--trace"))));
+        assert!(matches!(parse("rom.gb --linked --gb-doctor"), Err(CliError::Conflict("--linked", "--gb-doctor"))));
+        assert!(matches!(parse("rom.gb --linked --headless"), Err(CliError::Conflict("--linked", "--headless"))));
+    }
+}
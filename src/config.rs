@@ -0,0 +1,868 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::hash::Hash;
+use std::{fmt, fs, io};
+
+use serde::Deserialize;
+use speedy2d::window::VirtualKeyCode;
+
+use crate::gameboy::control::SpeedConfig;
+use crate::gameboy::input::{FRAMES_PER_SECOND, MacroStep};
+use crate::gameboy::keys::{key_bit, Keys};
+use crate::gameboy::rewind::RewindConfig;
+use crate::gamepad::{PAD_BUTTON_NAMES, PadButton};
+
+/*
+    Frontend configuration, loaded from a TOML file at startup. See config.example.toml.
+
+    Each section maps a button or action to a list of key names, e.g:
+        [keys]
+        A = ["A", "Z"]
+
+        [hotkeys]
+        pause = ["P"]
+
+        [gamepad]
+        deadzone = 0.5
+        stick = "left"
+
+        [gamepad.buttons]
+        A = ["East"]
+
+    Turbo buttons and macros are set up in the same way:
+        [turbo]
+        rate = 10
+
+        [turbo.keys]
+        A = ["Q"]
+
+        [[macros]]
+        name = "soft_reset"
+        keys = ["F2"]
+        steps = [{ buttons = ["A", "B", "START", "SELECT"], frames = 10 }]
+
+    As is rewinding:
+        [rewind]
+        interval = 5
+        length = 60
+        speed = 1
+
+        [speed]
+        fast_forward = 4
+        slow_motion = 2
+
+    A section that is present replaces all of the default bindings for that section, so that
+    defaults can't unexpectedly clash with the new bindings.
+ */
+
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/*
+    Emulator actions that can be bound to keys
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotkey {
+    Pause,
+    // Runs one frame then pauses
+    FrameAdvance,
+    // Runs one CPU instruction then pauses
+    StepInstruction,
+    // Restarts the CPU, keeping memory
+    Reset,
+    // Power cycles the GB
+    HardReset,
+    SaveState,
+    LoadState,
+    // Runs faster while held
+    FastForward,
+    SlowMotion,
+    // Moves the keyboard on to the next GB when several are linked
+    NextPlayer,
+    // Starts or stops recording the player's input as a macro
+    RecordMacro,
+    PlayMacro,
+    // Choose the save state slot used by SaveState and LoadState
+    NextSlot,
+    PreviousSlot,
+    // Steps back in time while held
+    Rewind,
+    // Stops in the debugger, which reads commands from the terminal
+    Debug,
+    // Shows or hides the tiles in VRAM next to the screen
+    TileViewer,
+    // Shows or hides a whole BG or window tile map next to the screen
+    MapViewer,
+    // Changes the palette the viewers draw tiles in
+    ViewerPalette,
+    // Changes which map the map viewer shows
+    ViewerMap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    Button(Keys),
+    // Presses and releases the button over and over while held
+    Turbo(Keys),
+    Hotkey(Hotkey),
+    // Runs the macro at this index in Config::macros
+    Macro(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Stick {
+    Left,
+    Right,
+}
+
+pub struct GamepadConfig {
+    pub buttons: HashMap<PadButton, Binding>,
+
+    // How far a stick has to be pushed, from 0.0 to 1.0, before it counts as a direction
+    pub deadzone: f32,
+
+    // Which stick works as the D-pad, if any
+    pub stick: Option<Stick>,
+}
+
+pub struct InputMacro {
+    pub name: String,
+    pub steps: Vec<MacroStep>,
+}
+
+pub struct Config {
+    pub keyboard: HashMap<VirtualKeyCode, Binding>,
+
+    pub gamepad: GamepadConfig,
+
+    // How many times per second turbo buttons are pressed
+    pub turbo_rate: u32,
+
+    pub macros: Vec<InputMacro>,
+
+    pub rewind: RewindConfig,
+
+    pub speed: SpeedConfig,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, io::Error),
+    Parse(String, toml::de::Error),
+    UnknownButton(String, String),
+    NotTurboButton(String, String),
+    UnknownHotkey(String, String),
+    UnknownKey(String, String),
+    UnknownPadButton(String, String),
+    UnknownStick(String),
+    InvalidDeadzone(f32),
+    InvalidTurboRate(u32),
+    EmptyMacroStep(String),
+    InvalidRewind(&'static str),
+    InvalidSlowMotion,
+    Duplicate(String, String, String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "unable to read config {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {}", path, e),
+            ConfigError::UnknownButton(section, name) => write!(f, "unknown button \"{}\" in {}, expected one of: {}", name, section, BUTTON_NAMES.map(|(n, _)| n).join(", ")),
+            ConfigError::NotTurboButton(section, name) => write!(f, "\"{}\" in {} can't be a turbo button, only A and B can", name, section),
+            ConfigError::UnknownHotkey(section, name) => write!(f, "unknown action \"{}\" in {}, expected one of: {}", name, section, HOTKEY_NAMES.map(|(n, _)| n).join(", ")),
+            ConfigError::UnknownKey(binding, key) => write!(f, "unknown key \"{}\" bound to {}, keys are named like \"A\", \"Key1\", \"F5\", \"Return\", \"Space\", \"Left\" or \"LShift\"", key, binding),
+            ConfigError::UnknownPadButton(binding, button) => write!(f, "unknown gamepad button \"{}\" bound to {}, expected one of: {}", button, binding, PAD_BUTTON_NAMES.map(|(n, _)| n).join(", ")),
+            ConfigError::UnknownStick(name) => write!(f, "unknown gamepad stick \"{}\", expected one of: left, right, none", name),
+            ConfigError::InvalidDeadzone(deadzone) => write!(f, "gamepad deadzone must be at least 0.0 and less than 1.0, got {}", deadzone),
+            ConfigError::InvalidTurboRate(rate) => write!(f, "turbo rate must be from 1 to {} presses per second, got {}", FRAMES_PER_SECOND / 2, rate),
+            ConfigError::EmptyMacroStep(name) => write!(f, "every step of macro {} must last at least 1 frame", name),
+            ConfigError::InvalidRewind(field) => write!(f, "rewind {} must be at least 1", field),
+            ConfigError::InvalidSlowMotion => write!(f, "slow motion must be at least 1 times slower"),
+            ConfigError::Duplicate(input, first, second) => write!(f, "\"{}\" is bound to both {} and {}", input, first, second),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/*
+    The config file as written, before names are checked
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    keys: Option<HashMap<String, Vec<String>>>,
+    hotkeys: Option<HashMap<String, Vec<String>>>,
+    gamepad: Option<RawGamepad>,
+    turbo: Option<RawTurbo>,
+    macros: Option<Vec<RawMacro>>,
+    rewind: Option<RawRewind>,
+    speed: Option<RawSpeed>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawGamepad {
+    deadzone: Option<f32>,
+    stick: Option<String>,
+    buttons: Option<HashMap<String, Vec<String>>>,
+    turbo: Option<HashMap<String, Vec<String>>>,
+    hotkeys: Option<HashMap<String, Vec<String>>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTurbo {
+    rate: Option<u32>,
+    keys: Option<HashMap<String, Vec<String>>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSpeed {
+    fast_forward: Option<u32>,
+    slow_motion: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRewind {
+    interval: Option<u32>,
+    length: Option<u32>,
+    speed: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMacro {
+    name: String,
+    keys: Option<Vec<String>>,
+    pad_buttons: Option<Vec<String>>,
+    steps: Vec<RawMacroStep>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMacroStep {
+    buttons: Vec<String>,
+    frames: u32,
+}
+
+const BUTTON_NAMES: [(&str, Keys); 8] = [
+    ("A", Keys::A),
+    ("B", Keys::B),
+    ("START", Keys::START),
+    ("SELECT", Keys::SELECT),
+    ("UP", Keys::UP),
+    ("DOWN", Keys::DOWN),
+    ("LEFT", Keys::LEFT),
+    ("RIGHT", Keys::RIGHT),
+];
+
+// Only A and B have turbo versions
+const TURBO_BUTTON_NAMES: [(&str, Keys); 2] = [
+    ("A", Keys::A),
+    ("B", Keys::B),
+];
+
+const HOTKEY_NAMES: [(&str, Hotkey); 20] = [
+    ("pause", Hotkey::Pause),
+    ("frame_advance", Hotkey::FrameAdvance),
+    ("step_instruction", Hotkey::StepInstruction),
+    ("reset", Hotkey::Reset),
+    ("hard_reset", Hotkey::HardReset),
+    ("save_state", Hotkey::SaveState),
+    ("load_state", Hotkey::LoadState),
+    ("fast_forward", Hotkey::FastForward),
+    ("slow_motion", Hotkey::SlowMotion),
+    ("next_player", Hotkey::NextPlayer),
+    ("record_macro", Hotkey::RecordMacro),
+    ("play_macro", Hotkey::PlayMacro),
+    ("next_slot", Hotkey::NextSlot),
+    ("previous_slot", Hotkey::PreviousSlot),
+    ("rewind", Hotkey::Rewind),
+    ("debug", Hotkey::Debug),
+    ("tile_viewer", Hotkey::TileViewer),
+    ("map_viewer", Hotkey::MapViewer),
+    ("viewer_palette", Hotkey::ViewerPalette),
+    ("viewer_map", Hotkey::ViewerMap),
+];
+
+const DEFAULT_KEYS: [(Keys, &[&str]); 8] = [
+    (Keys::A, &["A"]),
+    (Keys::B, &["S"]),
+    (Keys::START, &["Return"]),
+    (Keys::SELECT, &["Space"]),
+    (Keys::UP, &["Up"]),
+    (Keys::DOWN, &["Down"]),
+    (Keys::LEFT, &["Left"]),
+    (Keys::RIGHT, &["Right"]),
+];
+
+const DEFAULT_TURBO_KEYS: [(Keys, &[&str]); 2] = [
+    (Keys::A, &["Q"]),
+    (Keys::B, &["W"]),
+];
+
+const DEFAULT_HOTKEYS: [(Hotkey, &[&str]); 20] = [
+    (Hotkey::Pause, &["P"]),
+    (Hotkey::FrameAdvance, &["Period"]),
+    (Hotkey::StepInstruction, &["Comma"]),
+    (Hotkey::Reset, &["R"]),
+    (Hotkey::HardReset, &["F12"]),
+    (Hotkey::SaveState, &["F5"]),
+    (Hotkey::LoadState, &["F8"]),
+    (Hotkey::FastForward, &["Grave"]),
+    (Hotkey::SlowMotion, &["Minus"]),
+    (Hotkey::NextPlayer, &["Tab"]),
+    (Hotkey::RecordMacro, &["F3"]),
+    (Hotkey::PlayMacro, &["F4"]),
+    (Hotkey::NextSlot, &["F7"]),
+    (Hotkey::PreviousSlot, &["F6"]),
+    (Hotkey::Rewind, &["Backspace"]),
+    (Hotkey::Debug, &["F9"]),
+    (Hotkey::TileViewer, &["F10"]),
+    (Hotkey::MapViewer, &["M"]),
+    (Hotkey::ViewerPalette, &["F11"]),
+    (Hotkey::ViewerMap, &["N"]),
+];
+
+const DEFAULT_PAD_BUTTONS: [(Keys, &[&str]); 8] = [
+    (Keys::A, &["East"]),
+    (Keys::B, &["South"]),
+    (Keys::START, &["Start"]),
+    (Keys::SELECT, &["Select"]),
+    (Keys::UP, &["DPadUp"]),
+    (Keys::DOWN, &["DPadDown"]),
+    (Keys::LEFT, &["DPadLeft"]),
+    (Keys::RIGHT, &["DPadRight"]),
+];
+
+const DEFAULT_PAD_TURBO: [(Keys, &[&str]); 2] = [
+    (Keys::A, &["North"]),
+    (Keys::B, &["West"]),
+];
+
+const DEFAULT_PAD_HOTKEYS: [(Hotkey, &[&str]); 2] = [
+    (Hotkey::FastForward, &["RightTrigger2"]),
+    (Hotkey::Rewind, &["LeftTrigger2"]),
+];
+
+const DEFAULT_DEADZONE: f32 = 0.5;
+
+const DEFAULT_TURBO_RATE: u32 = 10;
+
+const DEFAULT_SPEED: SpeedConfig = SpeedConfig { fast_forward: 4, slow_motion: 2 };
+
+const DEFAULT_REWIND: RewindConfig = RewindConfig { interval: 5, length: 60, speed: 1 };
+
+// Holding all four buttons resets most games
+const SOFT_RESET: &[Keys] = &[Keys::A, Keys::B, Keys::START, Keys::SELECT];
+
+pub fn default_config() -> Config {
+    build_config(RawConfig { keys: None, hotkeys: None, gamepad: None, turbo: None, macros: None, rewind: None, speed: None }).unwrap()
+}
+
+/*
+    Loads the config at path, falling back to the defaults if there isn't a file at the default path
+ */
+pub fn load_config(path: Option<&str>) -> Result<Config, ConfigError> {
+    let path = match path {
+        Some(path) => path,
+        None if fs::metadata(DEFAULT_CONFIG_PATH).is_ok() => DEFAULT_CONFIG_PATH,
+        None => return Ok(default_config()),
+    };
+
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_string(), e))?;
+
+    let raw = toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_string(), e))?;
+
+    build_config(raw)
+}
+
+fn build_config(raw: RawConfig) -> Result<Config, ConfigError> {
+    let raw_turbo = raw.turbo.unwrap_or(RawTurbo { rate: None, keys: None });
+
+    let turbo_rate = raw_turbo.rate.unwrap_or(DEFAULT_TURBO_RATE);
+
+    if !(1..=FRAMES_PER_SECOND / 2).contains(&turbo_rate) {
+        return Err(ConfigError::InvalidTurboRate(turbo_rate));
+    }
+
+    let raw_rewind = raw.rewind.unwrap_or(RawRewind { interval: None, length: None, speed: None });
+
+    let rewind = RewindConfig {
+        interval: raw_rewind.interval.unwrap_or(DEFAULT_REWIND.interval),
+        length: raw_rewind.length.unwrap_or(DEFAULT_REWIND.length),
+        speed: raw_rewind.speed.unwrap_or(DEFAULT_REWIND.speed),
+    };
+
+    if rewind.interval == 0 {
+        return Err(ConfigError::InvalidRewind("interval"));
+    }
+
+    if rewind.speed == 0 {
+        return Err(ConfigError::InvalidRewind("speed"));
+    }
+
+    let raw_speed = raw.speed.unwrap_or(RawSpeed { fast_forward: None, slow_motion: None });
+
+    let speed = SpeedConfig {
+        fast_forward: raw_speed.fast_forward.unwrap_or(DEFAULT_SPEED.fast_forward),
+        slow_motion: raw_speed.slow_motion.unwrap_or(DEFAULT_SPEED.slow_motion),
+    };
+
+    if speed.slow_motion == 0 {
+        return Err(ConfigError::InvalidSlowMotion);
+    }
+
+    let raw_macros = raw.macros.unwrap_or_else(default_macros);
+
+    let mut macros = vec!();
+    let mut macro_keys = vec!();
+    let mut macro_pad_buttons = vec!();
+
+    for (i, raw_macro) in raw_macros.iter().enumerate() {
+        macros.push(build_macro(raw_macro)?);
+
+        let name = format!("macro {}", raw_macro.name);
+
+        for key in raw_macro.keys.iter().flatten() {
+            macro_keys.push((name.clone(), key.as_str(), Binding::Macro(i)));
+        }
+
+        for button in raw_macro.pad_buttons.iter().flatten() {
+            macro_pad_buttons.push((name.clone(), button.as_str(), Binding::Macro(i)));
+        }
+    }
+
+    let keyboard = resolve_bindings(
+        [
+            button_bindings("[keys]", &raw.keys, &DEFAULT_KEYS, Binding::Button)?,
+            button_bindings("[turbo.keys]", &raw_turbo.keys, &DEFAULT_TURBO_KEYS, Binding::Turbo)?,
+            hotkey_bindings("[hotkeys]", &raw.hotkeys, &DEFAULT_HOTKEYS)?,
+            macro_keys,
+        ].concat(),
+        parse_key,
+        ConfigError::UnknownKey,
+    )?;
+
+    let raw_gamepad = raw.gamepad.unwrap_or(RawGamepad { deadzone: None, stick: None, buttons: None, turbo: None, hotkeys: None });
+
+    let deadzone = raw_gamepad.deadzone.unwrap_or(DEFAULT_DEADZONE);
+
+    if !(0.0..1.0).contains(&deadzone) {
+        return Err(ConfigError::InvalidDeadzone(deadzone));
+    }
+
+    let stick = match raw_gamepad.stick.as_deref() {
+        None => Some(Stick::Left),
+        Some(name) if name.eq_ignore_ascii_case("left") => Some(Stick::Left),
+        Some(name) if name.eq_ignore_ascii_case("right") => Some(Stick::Right),
+        Some(name) if name.eq_ignore_ascii_case("none") => None,
+        Some(name) => return Err(ConfigError::UnknownStick(name.to_string())),
+    };
+
+    let buttons = resolve_bindings(
+        [
+            button_bindings("[gamepad.buttons]", &raw_gamepad.buttons, &DEFAULT_PAD_BUTTONS, Binding::Button)?,
+            button_bindings("[gamepad.turbo]", &raw_gamepad.turbo, &DEFAULT_PAD_TURBO, Binding::Turbo)?,
+            hotkey_bindings("[gamepad.hotkeys]", &raw_gamepad.hotkeys, &DEFAULT_PAD_HOTKEYS)?,
+            macro_pad_buttons,
+        ].concat(),
+        parse_pad_button,
+        ConfigError::UnknownPadButton,
+    )?;
+
+    Ok(Config {
+        keyboard,
+        gamepad: GamepadConfig { buttons, deadzone, stick },
+        turbo_rate,
+        macros,
+        rewind,
+        speed,
+    })
+}
+
+fn default_macros() -> Vec<RawMacro> {
+    vec![RawMacro {
+        name: "soft_reset".to_string(),
+        keys: Some(vec!["F2".to_string()]),
+        pad_buttons: None,
+        steps: vec![RawMacroStep { buttons: SOFT_RESET.iter().map(|k| format!("{:?}", k)).collect(), frames: 10 }],
+    }]
+}
+
+fn build_macro(raw: &RawMacro) -> Result<InputMacro, ConfigError> {
+    let mut steps = vec!();
+
+    for step in &raw.steps {
+        if step.frames == 0 {
+            return Err(ConfigError::EmptyMacroStep(raw.name.clone()));
+        }
+
+        let mut pressed = 0;
+
+        for name in &step.buttons {
+            pressed |= key_bit(find_button(&BUTTON_NAMES, name).ok_or_else(|| ConfigError::UnknownButton(format!("macro {}", raw.name), name.clone()))?);
+        }
+
+        steps.push(MacroStep { pressed, frames: step.frames });
+    }
+
+    Ok(InputMacro { name: raw.name.clone(), steps })
+}
+
+fn find_button(names: &[(&str, Keys)], name: &str) -> Option<Keys> {
+    names.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, k)| *k)
+}
+
+/*
+    The bindings for a section of GB buttons, or the defaults if the section isn't present.
+    Each binding is described by what it's for, the input name and what it's bound to.
+ */
+fn button_bindings<'a>(
+    section: &str,
+    keys: &'a Option<HashMap<String, Vec<String>>>,
+    default_keys: &[(Keys, &'a [&'a str])],
+    bind: fn(Keys) -> Binding,
+) -> Result<Vec<(String, &'a str, Binding)>, ConfigError> {
+    let mut bindings = vec!();
+
+    match keys {
+        Some(keys) => {
+            // Sorted so the same config always gives the same error
+            let mut keys: Vec<_> = keys.iter().collect();
+            keys.sort();
+
+            for (name, inputs) in keys {
+                let button = find_button(&BUTTON_NAMES, name)
+                    .ok_or_else(|| ConfigError::UnknownButton(section.to_string(), name.clone()))?;
+
+                if let Binding::Turbo(_) = bind(button) {
+                    find_button(&TURBO_BUTTON_NAMES, name).ok_or_else(|| ConfigError::NotTurboButton(section.to_string(), name.clone()))?;
+                }
+
+                for input in inputs {
+                    bindings.push((describe(bind(button)), input.as_str(), bind(button)));
+                }
+            }
+        }
+        None => {
+            for (button, inputs) in default_keys {
+                for input in inputs.iter() {
+                    bindings.push((describe(bind(*button)), *input, bind(*button)));
+                }
+            }
+        }
+    }
+
+    Ok(bindings)
+}
+
+fn describe(binding: Binding) -> String {
+    match binding {
+        Binding::Button(button) => format!("button {:?}", button),
+        Binding::Turbo(button) => format!("turbo {:?}", button),
+        Binding::Hotkey(hotkey) => format!("action {:?}", hotkey),
+        Binding::Macro(i) => format!("macro {}", i),
+    }
+}
+
+/*
+    The bindings for a section of hotkeys, or the defaults if the section isn't present
+ */
+fn hotkey_bindings<'a>(
+    section: &str,
+    hotkeys: &'a Option<HashMap<String, Vec<String>>>,
+    default_hotkeys: &[(Hotkey, &'a [&'a str])],
+) -> Result<Vec<(String, &'a str, Binding)>, ConfigError> {
+    let mut bindings = vec!();
+
+    match hotkeys {
+        Some(hotkeys) => {
+            // Sorted so the same config always gives the same error
+            let mut hotkeys: Vec<_> = hotkeys.iter().collect();
+            hotkeys.sort();
+
+            for (name, inputs) in hotkeys {
+                let hotkey = HOTKEY_NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name))
+                    .ok_or_else(|| ConfigError::UnknownHotkey(section.to_string(), name.clone()))?.1;
+
+                for input in inputs {
+                    bindings.push((format!("action {}", name), input.as_str(), Binding::Hotkey(hotkey)));
+                }
+            }
+        }
+        None => {
+            for (hotkey, inputs) in default_hotkeys {
+                for input in inputs.iter() {
+                    bindings.push((format!("action {:?}", hotkey), *input, Binding::Hotkey(*hotkey)));
+                }
+            }
+        }
+    }
+
+    Ok(bindings)
+}
+
+/*
+    Resolves the bindings into a map from each input to what it is bound to, checking that no
+    input is bound twice
+ */
+fn resolve_bindings<T: Copy + Eq + Hash>(
+    bindings: Vec<(String, &str, Binding)>,
+    parse: fn(&str) -> Option<T>,
+    unknown: fn(String, String) -> ConfigError,
+) -> Result<HashMap<T, Binding>, ConfigError> {
+    let mut resolved = HashMap::new();
+    let mut bound_to: HashMap<T, String> = HashMap::new();
+
+    for (name, input, binding) in bindings {
+        let parsed = parse(input).ok_or_else(|| unknown(name.clone(), input.to_string()))?;
+
+        if let Some(first) = bound_to.get(&parsed) {
+            return Err(ConfigError::Duplicate(input.to_string(), first.clone(), name));
+        }
+
+        bound_to.insert(parsed, name);
+        resolved.insert(parsed, binding);
+    }
+
+    Ok(resolved)
+}
+
+fn parse_pad_button(name: &str) -> Option<PadButton> {
+    PAD_BUTTON_NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, b)| *b)
+}
+
+fn parse_key(name: &str) -> Option<VirtualKeyCode> {
+    KEY_NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, vkc)| *vkc)
+}
+
+// Every key speedy2d knows, named as in VirtualKeyCode
+const KEY_NAMES: [(&str, VirtualKeyCode); 163] = [
+    ("Key1", VirtualKeyCode::Key1),
+    ("Key2", VirtualKeyCode::Key2),
+    ("Key3", VirtualKeyCode::Key3),
+    ("Key4", VirtualKeyCode::Key4),
+    ("Key5", VirtualKeyCode::Key5),
+    ("Key6", VirtualKeyCode::Key6),
+    ("Key7", VirtualKeyCode::Key7),
+    ("Key8", VirtualKeyCode::Key8),
+    ("Key9", VirtualKeyCode::Key9),
+    ("Key0", VirtualKeyCode::Key0),
+    ("A", VirtualKeyCode::A),
+    ("B", VirtualKeyCode::B),
+    ("C", VirtualKeyCode::C),
+    ("D", VirtualKeyCode::D),
+    ("E", VirtualKeyCode::E),
+    ("F", VirtualKeyCode::F),
+    ("G", VirtualKeyCode::G),
+    ("H", VirtualKeyCode::H),
+    ("I", VirtualKeyCode::I),
+    ("J", VirtualKeyCode::J),
+    ("K", VirtualKeyCode::K),
+    ("L", VirtualKeyCode::L),
+    ("M", VirtualKeyCode::M),
+    ("N", VirtualKeyCode::N),
+    ("O", VirtualKeyCode::O),
+    ("P", VirtualKeyCode::P),
+    ("Q", VirtualKeyCode::Q),
+    ("R", VirtualKeyCode::R),
+    ("S", VirtualKeyCode::S),
+    ("T", VirtualKeyCode::T),
+    ("U", VirtualKeyCode::U),
+    ("V", VirtualKeyCode::V),
+    ("W", VirtualKeyCode::W),
+    ("X", VirtualKeyCode::X),
+    ("Y", VirtualKeyCode::Y),
+    ("Z", VirtualKeyCode::Z),
+    ("Escape", VirtualKeyCode::Escape),
+    ("F1", VirtualKeyCode::F1),
+    ("F2", VirtualKeyCode::F2),
+    ("F3", VirtualKeyCode::F3),
+    ("F4", VirtualKeyCode::F4),
+    ("F5", VirtualKeyCode::F5),
+    ("F6", VirtualKeyCode::F6),
+    ("F7", VirtualKeyCode::F7),
+    ("F8", VirtualKeyCode::F8),
+    ("F9", VirtualKeyCode::F9),
+    ("F10", VirtualKeyCode::F10),
+    ("F11", VirtualKeyCode::F11),
+    ("F12", VirtualKeyCode::F12),
+    ("F13", VirtualKeyCode::F13),
+    ("F14", VirtualKeyCode::F14),
+    ("F15", VirtualKeyCode::F15),
+    ("F16", VirtualKeyCode::F16),
+    ("F17", VirtualKeyCode::F17),
+    ("F18", VirtualKeyCode::F18),
+    ("F19", VirtualKeyCode::F19),
+    ("F20", VirtualKeyCode::F20),
+    ("F21", VirtualKeyCode::F21),
+    ("F22", VirtualKeyCode::F22),
+    ("F23", VirtualKeyCode::F23),
+    ("F24", VirtualKeyCode::F24),
+    ("PrintScreen", VirtualKeyCode::PrintScreen),
+    ("ScrollLock", VirtualKeyCode::ScrollLock),
+    ("PauseBreak", VirtualKeyCode::PauseBreak),
+    ("Insert", VirtualKeyCode::Insert),
+    ("Home", VirtualKeyCode::Home),
+    ("Delete", VirtualKeyCode::Delete),
+    ("End", VirtualKeyCode::End),
+    ("PageDown", VirtualKeyCode::PageDown),
+    ("PageUp", VirtualKeyCode::PageUp),
+    ("Left", VirtualKeyCode::Left),
+    ("Up", VirtualKeyCode::Up),
+    ("Right", VirtualKeyCode::Right),
+    ("Down", VirtualKeyCode::Down),
+    ("Backspace", VirtualKeyCode::Backspace),
+    ("Return", VirtualKeyCode::Return),
+    ("Space", VirtualKeyCode::Space),
+    ("Compose", VirtualKeyCode::Compose),
+    ("Caret", VirtualKeyCode::Caret),
+    ("Numlock", VirtualKeyCode::Numlock),
+    ("Numpad0", VirtualKeyCode::Numpad0),
+    ("Numpad1", VirtualKeyCode::Numpad1),
+    ("Numpad2", VirtualKeyCode::Numpad2),
+    ("Numpad3", VirtualKeyCode::Numpad3),
+    ("Numpad4", VirtualKeyCode::Numpad4),
+    ("Numpad5", VirtualKeyCode::Numpad5),
+    ("Numpad6", VirtualKeyCode::Numpad6),
+    ("Numpad7", VirtualKeyCode::Numpad7),
+    ("Numpad8", VirtualKeyCode::Numpad8),
+    ("Numpad9", VirtualKeyCode::Numpad9),
+    ("NumpadAdd", VirtualKeyCode::NumpadAdd),
+    ("NumpadDivide", VirtualKeyCode::NumpadDivide),
+    ("NumpadDecimal", VirtualKeyCode::NumpadDecimal),
+    ("NumpadComma", VirtualKeyCode::NumpadComma),
+    ("NumpadEnter", VirtualKeyCode::NumpadEnter),
+    ("NumpadEquals", VirtualKeyCode::NumpadEquals),
+    ("NumpadMultiply", VirtualKeyCode::NumpadMultiply),
+    ("NumpadSubtract", VirtualKeyCode::NumpadSubtract),
+    ("AbntC1", VirtualKeyCode::AbntC1),
+    ("AbntC2", VirtualKeyCode::AbntC2),
+    ("Apostrophe", VirtualKeyCode::Apostrophe),
+    ("Apps", VirtualKeyCode::Apps),
+    ("Asterisk", VirtualKeyCode::Asterisk),
+    ("At", VirtualKeyCode::At),
+    ("Ax", VirtualKeyCode::Ax),
+    ("Backslash", VirtualKeyCode::Backslash),
+    ("Calculator", VirtualKeyCode::Calculator),
+    ("Capital", VirtualKeyCode::Capital),
+    ("Colon", VirtualKeyCode::Colon),
+    ("Comma", VirtualKeyCode::Comma),
+    ("Convert", VirtualKeyCode::Convert),
+    ("Equals", VirtualKeyCode::Equals),
+    ("Grave", VirtualKeyCode::Grave),
+    ("Kana", VirtualKeyCode::Kana),
+    ("Kanji", VirtualKeyCode::Kanji),
+    ("LAlt", VirtualKeyCode::LAlt),
+    ("LBracket", VirtualKeyCode::LBracket),
+    ("LControl", VirtualKeyCode::LControl),
+    ("LShift", VirtualKeyCode::LShift),
+    ("LWin", VirtualKeyCode::LWin),
+    ("Mail", VirtualKeyCode::Mail),
+    ("MediaSelect", VirtualKeyCode::MediaSelect),
+    ("MediaStop", VirtualKeyCode::MediaStop),
+    ("Minus", VirtualKeyCode::Minus),
+    ("Mute", VirtualKeyCode::Mute),
+    ("MyComputer", VirtualKeyCode::MyComputer),
+    ("NavigateForward", VirtualKeyCode::NavigateForward),
+    ("NavigateBackward", VirtualKeyCode::NavigateBackward),
+    ("NextTrack", VirtualKeyCode::NextTrack),
+    ("NoConvert", VirtualKeyCode::NoConvert),
+    ("OEM102", VirtualKeyCode::OEM102),
+    ("Period", VirtualKeyCode::Period),
+    ("PlayPause", VirtualKeyCode::PlayPause),
+    ("Plus", VirtualKeyCode::Plus),
+    ("Power", VirtualKeyCode::Power),
+    ("PrevTrack", VirtualKeyCode::PrevTrack),
+    ("RAlt", VirtualKeyCode::RAlt),
+    ("RBracket", VirtualKeyCode::RBracket),
+    ("RControl", VirtualKeyCode::RControl),
+    ("RShift", VirtualKeyCode::RShift),
+    ("RWin", VirtualKeyCode::RWin),
+    ("Semicolon", VirtualKeyCode::Semicolon),
+    ("Slash", VirtualKeyCode::Slash),
+    ("Sleep", VirtualKeyCode::Sleep),
+    ("Stop", VirtualKeyCode::Stop),
+    ("Sysrq", VirtualKeyCode::Sysrq),
+    ("Tab", VirtualKeyCode::Tab),
+    ("Underline", VirtualKeyCode::Underline),
+    ("Unlabeled", VirtualKeyCode::Unlabeled),
+    ("VolumeDown", VirtualKeyCode::VolumeDown),
+    ("VolumeUp", VirtualKeyCode::VolumeUp),
+    ("Wake", VirtualKeyCode::Wake),
+    ("WebBack", VirtualKeyCode::WebBack),
+    ("WebFavorites", VirtualKeyCode::WebFavorites),
+    ("WebForward", VirtualKeyCode::WebForward),
+    ("WebHome", VirtualKeyCode::WebHome),
+    ("WebRefresh", VirtualKeyCode::WebRefresh),
+    ("WebSearch", VirtualKeyCode::WebSearch),
+    ("WebStop", VirtualKeyCode::WebStop),
+    ("Yen", VirtualKeyCode::Yen),
+    ("Copy", VirtualKeyCode::Copy),
+    ("Paste", VirtualKeyCode::Paste),
+    ("Cut", VirtualKeyCode::Cut),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        build_config(toml::from_str(text).unwrap())
+    }
+
+    #[test]
+    fn replaces_the_defaults_for_a_section() {
+        let config = parse("[keys]\nA = [\"Z\", \"numlock\"]\n\n[hotkeys]\npause = [\"WebHome\"]\n").unwrap();
+
+        assert!(matches!(config.keyboard.get(&VirtualKeyCode::Z), Some(Binding::Button(Keys::A))));
+        assert!(matches!(config.keyboard.get(&VirtualKeyCode::Numlock), Some(Binding::Button(Keys::A))));
+        assert!(matches!(config.keyboard.get(&VirtualKeyCode::WebHome), Some(Binding::Hotkey(Hotkey::Pause))));
+
+        // Only what the sections given say, the rest keep their defaults
+        assert!(!config.keyboard.contains_key(&VirtualKeyCode::A));
+        assert!(!config.keyboard.contains_key(&VirtualKeyCode::P));
+        assert!(matches!(config.keyboard.get(&VirtualKeyCode::Q), Some(Binding::Turbo(Keys::A))));
+    }
+
+    #[test]
+    fn every_key_has_a_name() {
+        for (name, key) in KEY_NAMES {
+            assert_eq!(parse_key(&name.to_lowercase()), Some(key));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let e = parse("[keys]\nA = [\"Banana\"]\n").err().unwrap();
+
+        assert!(matches!(&e, ConfigError::UnknownKey(binding, key) if binding == "button A" && key == "Banana"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_unknown_actions() {
+        let e = parse("[hotkeys]\nexplode = [\"F1\"]\n").err().unwrap();
+
+        assert!(matches!(&e, ConfigError::UnknownHotkey(_, name) if name == "explode"), "{}", e);
+    }
+
+    #[test]
+    fn reports_duplicates_in_the_same_order_every_time() {
+        let text = "[keys]\nSTART = [\"Z\"]\nA = [\"Z\"]\nB = [\"Z\"]\nSELECT = [\"Z\"]\n";
+
+        for _ in 0..10 {
+            assert_eq!(parse(text).err().unwrap().to_string(), "\"Z\" is bound to both button A and button B");
+        }
+    }
+
+    #[test]
+    fn rejects_a_key_bound_across_sections() {
+        let e = parse("[keys]\nA = [\"F5\"]\n").err().unwrap();
+
+        assert_eq!(e.to_string(), "\"F5\" is bound to both button A and action SaveState");
+    }
+}
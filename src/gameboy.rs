@@ -1,35 +1,195 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
 use std::sync::Arc;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::Receiver;
 use std::thread::sleep;
 use std::time::{Duration, SystemTime};
-use speedy2d::window::UserEventSender;
 
+use crate::gameboy::boot::{Boot, builtin_boot};
 use crate::gameboy::cartridge::Cartridge;
-use crate::gameboy::cpu::new_cpu;
-use crate::gameboy::gpu::new_gpu;
-use crate::gameboy::keys::KeyReg;
-use crate::gameboy::mmu::new_mmu;
+use crate::gameboy::control::{Command, SpeedConfig};
+use crate::gameboy::frame::FrameSink;
+use crate::gameboy::cpu::{CPU, new_cpu, new_cpu_after_boot};
+use crate::gameboy::debug::DebugConfig;
+use crate::gameboy::debugger::{Debugger, new_stdin_debugger};
+use crate::gameboy::disasm::{disassemble, Instruction};
+use crate::gameboy::gdb::GdbStub;
+pub use crate::gameboy::cpu::Registers;
+use crate::gameboy::gpu::{GPU, new_gpu, REG_BG_PALETTE, REG_LCD_GPU_CONTROL, REG_SCROLL_X, REG_SCROLL_Y, REG_SPR_PALETTE_0, REG_SPR_PALETTE_1, REG_WINDOW_X, REG_WINDOW_Y};
+pub use crate::gameboy::gpu::{Palette, PALETTES};
+use crate::gameboy::input::Input;
+use crate::gameboy::keys::{KeyReg, new_key_reg};
+use crate::gameboy::mmu::{MMU, new_mmu};
+use crate::gameboy::movie::Movie;
+use crate::gameboy::rewind::{new_rewind, Rewind, RewindConfig};
+use crate::gameboy::serial::{LinkPartner, new_serial, Serial};
+use crate::gameboy::symbols::Symbols;
+use crate::gameboy::trace::Tracer;
+use crate::gameboy::vram::{VramShare, VramSnapshot};
+use crate::gameboy::watch::{Access, MemoryHook, Watchpoint, WatchHit};
+use crate::gameboy::state::{new_state_reader, new_state_writer, slot_path, StateError, StateReader};
 
+pub mod boot;
 pub mod cartridge;
 mod cpu;
 mod mmu;
 mod gpu;
 pub mod keys;
+pub mod input;
+pub mod serial;
+pub mod link;
+pub mod linked;
+pub mod printer;
+pub mod movie;
+pub mod state;
+pub mod control;
+pub mod rewind;
+pub mod frame;
+pub mod debug;
+pub mod trace;
+pub mod disasm;
+pub mod debugger;
+pub mod watch;
+pub mod gdb;
+pub mod symbols;
+pub mod vram;
 
-pub fn start_game_boy(cart: Cartridge, image_sender: UserEventSender<Vec<u8>>, key_reg: Arc<KeyReg>) {
-    let mut mmu = new_mmu(cart, key_reg);
+// 70224 cycles per frame
+pub const CYCLES_PER_FRAME: u32 = 70224;
 
-    let mut cpu = new_cpu();
-    let mut gpu = new_gpu(image_sender);
+pub const FRAME_TIME: Duration = Duration::from_millis(1000 / 60);
 
-    let target_frame_time = Duration::from_millis(1000 / 60);
+pub struct GameBoy {
+    cpu: CPU,
+    mmu: MMU,
+    gpu: GPU,
+    serial: Serial,
 
-    loop {
-        let mut fclk = 70224; // 70224 cycles per frame
-        let start = SystemTime::now();
+    // The joypad, shared with the MMU
+    key_reg: Arc<KeyReg>,
+
+    // How the GB starts up when switched on or reset
+    boot: Boot,
+
+    // t cycles left in the current frame, 0 or less between frames
+    frame_clock: i32,
+
+    // Where every instruction is logged, if anywhere
+    trace: Option<Tracer>,
+
+    debug: DebugConfig,
+
+    // Labels for the ROM, shared with the tracer
+    symbols: Option<Arc<Symbols>>,
+}
+
+/*
+    Everything about a GB that is chosen before switching it on
+ */
+#[derive(Default)]
+pub struct GameBoyConfig {
+    // How the GB starts up, the built in boot ROM if None
+    pub boot: Option<Boot>,
+
+    pub palette: Option<Palette>,
+
+    // Whatever is plugged into the link port
+    pub link: Option<Box<dyn LinkPartner>>,
+
+    pub debug: DebugConfig,
+
+    // Labels for the ROM, from an RGBDS .sym file
+    pub symbols: Option<Symbols>,
+}
+
+/*
+    Completed frames are handed out by GameBoy::take_frame, the GB knows nothing of where they go
+ */
+pub fn new_game_boy(cart: Cartridge, config: GameBoyConfig) -> GameBoy {
+    let key_reg = Arc::new(new_key_reg());
+    let debug = config.debug;
+
+    let mut game_boy = GameBoy {
+        cpu: new_cpu(&debug),
+        mmu: new_mmu(cart, key_reg.clone(), &debug),
+        gpu: new_gpu(),
+        serial: new_serial(config.link),
+        key_reg,
+        boot: debug.boot(config.boot.unwrap_or_else(builtin_boot)),
+        frame_clock: 0,
+        trace: None,
+        debug,
+        symbols: config.symbols.map(Arc::new),
+    };
+
+    if let Some(palette) = config.palette {
+        game_boy.gpu.set_palette(palette);
+    }
+
+    game_boy.power_on();
+
+    game_boy
+}
+
+impl GameBoy {
+    /*
+        Runs until the end of the current frame, or for a whole frame if between frames
+     */
+    pub fn run_frame(&mut self) {
+        self.step_instruction();
+
+        while self.mid_frame() {
+            self.step_instruction();
+        }
+    }
+
+    /*
+        Executes a single CPU operation and moves the rest of the hardware on by the same time.
+
+        Returns the number of t cycles that passed.
+     */
+    pub fn step_instruction(&mut self) -> u32 {
+        if self.frame_clock <= 0 {
+            self.frame_clock = CYCLES_PER_FRAME as i32;
+        }
+
+        let delta_t = self.step();
+        self.frame_clock -= delta_t as i32;
+
+        if let (false, Some(trace)) = (self.mid_frame(), &mut self.trace) {
+            trace.frame_done();
+        }
+
+        delta_t
+    }
+
+    /*
+        Whether a frame has been started but not finished. Buttons set between frames land on the
+        same cycle every time, which keeps movies and rewinding in step.
+     */
+    pub fn mid_frame(&self) -> bool {
+        self.frame_clock > 0
+    }
 
-        while fclk > 0 {
-            /*
+    /*
+        Holds down the buttons, one bit per button as in keys::key_bit
+     */
+    pub fn set_buttons(&mut self, pressed: u8) {
+        self.key_reg.set_pressed(pressed);
+    }
+
+    /*
+        Sound produced since the last call, as interleaved stereo samples. There is no APU yet, so
+        this is always empty.
+     */
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        vec!()
+    }
+
+    fn step(&mut self) -> u32 {
+        /*
             Originally I wrote the CPU to contain MMU when it was constructed.
 
             Of course, both the CPU and GPU need access to the MMU. On top of this
@@ -52,20 +212,724 @@ pub fn start_game_boy(cart: Cartridge, image_sender: UserEventSender<Vec<u8>>, k
             compile time. This means that if the code in the future is refactored it may compile
             but actually contain the possibility of panicking.
          */
-            let (_, delta_t) = cpu.exec(&mut mmu);
-            gpu.step(&mut mmu, delta_t);
+        self.mmu.check_joypad_interrupt();
+
+        self.trace(|trace, cpu, mmu| trace.instruction(cpu, mmu));
+
+        // Only the CPU's accesses are watched, and only when something is watching
+        self.mmu.watching = !self.mmu.watch.get_mut().is_empty();
+
+        if self.mmu.watching {
+            self.mmu.watch.get_mut().pc = self.cpu.pc();
+        }
+
+        let (_, delta_t) = self.cpu.exec(&mut self.mmu);
+
+        if self.mmu.watching {
+            self.mmu.watching = false;
+
+            let pc = self.cpu.pc();
+            let opc = self.mmu.rb(pc);
+            let watch = self.mmu.watch.get_mut();
+
+            watch.pc = pc;
+            watch.access(Access::Execute, pc, opc);
+        }
+
+        self.trace(|trace, cpu, _| trace.executed(delta_t, cpu.interrupt()));
+
+        if let Some(pc) = self.cpu.take_crash() {
+            let why = format!("invalid opcode {:02X} at {:04X}", self.mmu.rb(pc), pc);
+
+            eprintln!("{}, locking up", why);
+            self.dump_trace(&why);
+        }
+
+        self.gpu.step(&mut self.mmu, delta_t);
+        self.serial.step(&mut self.mmu, delta_t);
+
+        delta_t
+    }
+
+    /*
+        Returns the framebuffer if a frame has been completed since the last call
+     */
+    pub fn take_frame(&mut self) -> Option<&[u8]> {
+        self.gpu.take_frame()
+    }
+
+    /*
+        Restarts the CPU from the bios, leaving memory as it is
+     */
+    pub fn soft_reset(&mut self) {
+        self.frame_clock = 0;
+
+        match &self.boot {
+            Boot::Rom(_) => {
+                self.cpu = new_cpu(&self.debug);
+                self.mmu.in_bios = true;
+            }
+            Boot::Skip(model) => {
+                self.cpu = new_cpu_after_boot(*model, self.mmu.cart().header_checksum(), &self.debug);
+                self.mmu.in_bios = false;
+            }
+        }
+    }
+
+    /*
+        Switches the GB off and on again
+     */
+    pub fn hard_reset(&mut self) {
+        self.mmu.reset();
+        self.gpu.reset();
+        self.serial.reset();
+        self.power_on();
+    }
+
+    /*
+        Changes how the GB starts up, and switches it off and on again to start that way
+     */
+    pub fn set_boot(&mut self, boot: Boot) {
+        self.boot = self.debug.boot(boot);
+        self.hard_reset();
+    }
+
+    /*
+        Gets the GB from freshly switched on memory to running the boot ROM, or to the
+        cartridge's entry point when booting is skipped
+     */
+    fn power_on(&mut self) {
+        self.frame_clock = 0;
+
+        match &self.boot {
+            Boot::Rom(rom) => {
+                self.cpu = new_cpu(&self.debug);
+                self.mmu.set_bios(rom.clone());
+            }
+            Boot::Skip(model) => {
+                self.cpu = new_cpu_after_boot(*model, self.mmu.cart().header_checksum(), &self.debug);
+                self.mmu.skip_boot(*model);
+            }
+        }
+    }
+
+    pub fn debug(&self) -> &DebugConfig {
+        &self.debug
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.pc()
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.cpu.set_registers(registers);
+    }
+
+    /*
+        Reads memory as the CPU would see it
+     */
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        self.mmu.rb(addr)
+    }
+
+    pub fn read_bytes(&self, addr: u16, len: usize) -> Vec<u8> {
+        (0..len).map(|i| self.mmu.rb(addr.wrapping_add(i as u16))).collect()
+    }
+
+    /*
+        Writes to memory as the CPU would, so writes to IO registers and ROM have their usual effects
+     */
+    pub fn write_byte(&mut self, addr: u16, value: u8) {
+        self.mmu.wb(addr, value);
+    }
+
+    /*
+        The ROM bank mapped at addr, 0 outside the switchable bank
+     */
+    pub fn rom_bank(&self, addr: u16) -> u16 {
+        self.mmu.rom_bank(addr)
+    }
+
+    /*
+        Stops the next time the CPU accesses memory the watchpoint covers, see take_watch_hit.
+        Returns its index in watchpoints.
+     */
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        let watchpoints = &mut self.mmu.watch.get_mut().watchpoints;
+        watchpoints.push(watchpoint);
+
+        watchpoints.len() - 1
+    }
+
+    /*
+        Removes the watchpoint at index n, moving those after it down by one
+     */
+    pub fn remove_watchpoint(&mut self, n: usize) -> Option<Watchpoint> {
+        let watchpoints = &mut self.mmu.watch.get_mut().watchpoints;
+
+        (n < watchpoints.len()).then(|| watchpoints.remove(n))
+    }
+
+    pub fn watchpoints(&self) -> Vec<Watchpoint> {
+        self.mmu.watch.borrow().watchpoints.clone()
+    }
+
+    /*
+        The first watchpoint hit since this was last called. Nothing actually stops, whatever is
+        running the GB should check after each instruction.
+     */
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.mmu.watch.get_mut().hit.take()
+    }
+
+    /*
+        Lets hook see every memory access the CPU makes from now on
+     */
+    pub fn add_memory_hook(&mut self, hook: Box<dyn MemoryHook>) {
+        self.mmu.watch.get_mut().hooks.push(hook);
+    }
+
+    /*
+        Decodes the instruction at addr, as the CPU would see it now. With symbols, addresses in
+        the operands are swapped for their labels.
+     */
+    pub fn disassemble(&self, addr: u16) -> Instruction {
+        let mut instruction = disassemble(addr, |a| self.mmu.rb(a));
+
+        if let Some(symbols) = self.symbols() {
+            instruction.operands = symbols.label_operands(self.rom_bank(addr), &instruction.operands);
+        }
+
+        instruction
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        self.gpu.framebuffer()
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.gpu.set_palette(palette);
+    }
+
+    /*
+        A copy of VRAM as it is now, see vram::VramSnapshot
+     */
+    pub fn vram_snapshot(&self) -> VramSnapshot {
+        let rb = |addr| self.mmu.rb(addr);
+
+        VramSnapshot {
+            vram: (0x8000..=0x9FFF).map(rb).collect(),
+            lcdc: rb(REG_LCD_GPU_CONTROL),
+            scy: rb(REG_SCROLL_Y),
+            scx: rb(REG_SCROLL_X),
+            wy: rb(REG_WINDOW_Y),
+            wx: rb(REG_WINDOW_X),
+            bgp: rb(REG_BG_PALETTE),
+            obp0: rb(REG_SPR_PALETTE_0),
+            obp1: rb(REG_SPR_PALETTE_1),
+            colors: self.gpu.colors(),
+        }
+    }
+
+    /*
+        Logs every instruction from now on, see trace::Tracer
+     */
+    pub fn set_trace(&mut self, mut tracer: Tracer) {
+        tracer.set_symbols(self.symbols.clone());
+        self.trace = Some(tracer);
+    }
+
+    pub fn symbols(&self) -> Option<&Symbols> {
+        self.symbols.as_deref()
+    }
+
+    /*
+        The label at or before addr, e.g. PlayerUpdate+$12, for addr as it's mapped now
+     */
+    pub fn describe_addr(&self, addr: u16) -> Option<String> {
+        self.symbols()?.describe(self.rom_bank(addr), addr)
+    }
+
+    /*
+        Writes out any trace that is still buffered
+     */
+    pub fn flush_trace(&mut self) {
+        self.trace(|trace, _, _| trace.flush());
+    }
+
+    /*
+        Something has gone wrong, or a breakpoint has been hit. Notes why in the trace, and in ring
+        mode writes out the last instructions run.
+     */
+    pub fn dump_trace(&mut self, why: &str) {
+        self.trace(|trace, _, _| {
+            trace.event(why)?;
+            trace.dump(why)
+        });
+    }
+
+    /*
+        Stops tracing if the trace can't be written
+     */
+    fn trace(&mut self, f: impl FnOnce(&mut Tracer, &CPU, &MMU) -> io::Result<()>) {
+        if let Some(Err(e)) = self.trace.as_mut().map(|trace| f(trace, &self.cpu, &self.mmu)) {
+            eprintln!("stopped tracing: {}", e);
+            self.trace = None;
+        }
+    }
+
+    /*
+        Writes the screen as it is now to a PNG
+     */
+    pub fn write_screenshot(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), 160, 144);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(self.framebuffer())?;
 
-            fclk -= delta_t as i32;
+        Ok(())
+    }
+
+    /*
+        Hands the framebuffer over again as a completed frame, e.g. after loading a state
+     */
+    pub fn redraw(&mut self) {
+        self.gpu.present();
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = new_state_writer(self.mmu.cart());
+
+        self.cpu.save_state(&mut w);
+        self.mmu.save_state(&mut w);
+        self.gpu.save_state(&mut w);
+        self.serial.save_state(&mut w);
+
+        w.finish()
+    }
+
+    /*
+        Restores a state from save_state. If the state can't be loaded the GB is left as it was.
+     */
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let backup = self.save_state();
+
+        let result = new_state_reader(state, self.mmu.cart()).and_then(|mut r| {
+            self.load_parts(&mut r)?;
+            r.finish()
+        });
+
+        match result {
+            // States don't record how far into a frame they were taken, so start a new one
+            Ok(()) => self.frame_clock = 0,
+            Err(_) => self.load_parts(&mut new_state_reader(&backup, self.mmu.cart()).unwrap()).unwrap(),
+        }
+
+        result
+    }
+
+    fn load_parts(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.cpu.load_state(r)?;
+        self.mmu.load_state(r)?;
+        self.gpu.load_state(r)?;
+        self.serial.load_state(r)?;
+
+        Ok(())
+    }
+}
+
+/*
+    How start_game_boy runs the GB
+ */
+pub struct RunOptions {
+    pub movie: Option<Movie>,
+    pub rewind: RewindConfig,
+    pub speed: SpeedConfig,
+
+    // Where save states are kept
+    pub save_dir: String,
+
+    // Stop after this many frames
+    pub frames: Option<u64>,
+
+    // Where to write the screen once stopped after `frames`
+    pub screenshot: Option<String>,
+
+    // Run as fast as possible rather than in real time
+    pub unpaced: bool,
+
+    // Where to log every instruction
+    pub trace: Option<Tracer>,
+
+    // Runs the GB an instruction at a time, stopping for commands
+    pub debugger: Option<Debugger>,
+
+    // Runs the GB an instruction at a time for GDB, in place of the debugger
+    pub gdb: Option<GdbStub>,
+
+    // Where VRAM is copied to each frame for the viewers, while they're open
+    pub vram: Option<VramShare>,
+}
+
+/*
+    Everything start_game_boy keeps from one frame to the next
+ */
+struct Runner {
+    game_boy: GameBoy,
+
+    // Where completed frames are sent
+    sinks: Vec<Box<dyn FrameSink>>,
+
+    input: Arc<Input>,
+
+    movie: Option<Movie>,
+
+    rewind: Rewind,
+
+    speed: SpeedConfig,
+
+    save_dir: String,
+
+    // Frames run since starting
+    frames: u64,
+
+    paused: bool,
+
+    // Whether the rewind key is held
+    rewinding: bool,
+
+    // Whether the fast forward key is held
+    fast_forward: bool,
+
+    slow_motion: bool,
+
+    // Created when --debug is given or the debug key is pressed
+    debugger: Option<Debugger>,
+
+    // Created when --gdb is given
+    gdb: Option<GdbStub>,
+
+    vram: Option<VramShare>,
+
+    // Whether a viewer is open, see Command::ViewVram
+    viewing_vram: bool,
+
+    // Set when the user quits from the debugger, or GDB kills the GB
+    quit: bool,
+}
+
+/*
+    input is written to by the window. The GB's joypad is only updated from it between frames, so
+    that inputs land on the same cycle when a movie is played back.
+
+    Commands from the window are handled between frames, or as soon as they arrive while paused.
+
+    Returns once options.frames have run, or if the window goes while paused.
+ */
+pub fn start_game_boy(cart: Cartridge, config: GameBoyConfig, sinks: Vec<Box<dyn FrameSink>>, input: Arc<Input>, options: RunOptions, commands: Receiver<Command>) {
+    let mut game_boy = new_game_boy(cart, config);
+
+    if let Some(trace) = options.trace {
+        game_boy.set_trace(trace);
+    }
+
+    let mut runner = Runner {
+        game_boy,
+        sinks,
+        input,
+        movie: options.movie,
+        rewind: new_rewind(options.rewind),
+        speed: options.speed,
+        save_dir: options.save_dir,
+        frames: 0,
+        paused: false,
+        rewinding: false,
+        fast_forward: false,
+        slow_motion: false,
+        debugger: options.debugger,
+        gdb: options.gdb,
+        vram: options.vram,
+        viewing_vram: false,
+        quit: false,
+    };
+
+    loop {
+        if runner.quit || options.frames.is_some_and(|frames| runner.frames >= frames) {
+            break;
+        }
+
+        let start = SystemTime::now();
+
+        // Nothing happens while paused, so wait for something to do rather than spinning
+        if runner.paused && !runner.rewinding {
+            match commands.recv() {
+                Ok(command) => runner.handle_command(command),
+                Err(_) => return, // The window has gone
+            }
+        }
+
+        for command in commands.try_iter() {
+            runner.handle_command(command);
+        }
+
+        if runner.rewinding {
+            runner.rewind.step_back(&mut runner.game_boy);
+            runner.game_boy.redraw();
+        } else if !runner.paused {
+            runner.run_frame();
+        }
+
+        runner.send_frame();
+
+        // Time spent stopped in the debugger doesn't count
+        let debugged = runner.debugger.as_mut().is_some_and(|debugger| debugger.take_prompted())
+            | runner.gdb.as_mut().is_some_and(|gdb| gdb.take_prompted());
+        let unpaced = options.unpaced || runner.game_boy.debug().unpaced || debugged;
+
+        if let (false, Some(frame_time)) = (unpaced, runner.frame_time()) {
+            wait_for_frame_end(start, frame_time);
         }
+    }
+
+    runner.game_boy.flush_trace();
+
+    if let Some(path) = options.screenshot {
+        match runner.game_boy.write_screenshot(&path) {
+            Ok(()) => println!("Saved screenshot to {}", path),
+            Err(e) => eprintln!("unable to save screenshot to {}: {}", path, e),
+        }
+    }
+}
+
+impl Runner {
+    /*
+        Runs a single instruction, starting a new frame first if the last one has finished.
+        Returns true once the frame has finished.
+     */
+    fn step(&mut self) -> bool {
+        if !self.game_boy.mid_frame() {
+            self.game_boy.set_buttons(next_input(&self.input, &mut self.movie));
+        }
+
+        let cycles = match (&mut self.gdb, &mut self.debugger) {
+            (Some(gdb), _) => gdb.step(&mut self.game_boy),
+            (None, Some(debugger)) => debugger.step(&mut self.game_boy),
+            (None, None) => Some(self.game_boy.step_instruction()),
+        };
+
+        if cycles.is_none() {
+            self.quit = true;
+            return true;
+        }
+
+        if self.game_boy.mid_frame() {
+            return false;
+        }
+
+        self.rewind.frame_done(&self.game_boy);
+        self.frames += 1;
+
+        // The window can close at any time, so don't leave much of the trace unwritten
+        self.game_boy.flush_trace();
+
+        true
+    }
 
-        let frame_time = SystemTime::now().duration_since(start).unwrap();
+    /*
+        Runs until the end of the current frame
+     */
+    fn run_frame(&mut self) {
+        while !self.step() {}
+    }
+
+    /*
+        How long each frame should take at the current speed, None to run as fast as possible
+     */
+    fn frame_time(&self) -> Option<Duration> {
+        if self.fast_forward && !self.rewinding {
+            return match self.speed.fast_forward {
+                0 => None,
+                n => Some(FRAME_TIME / n),
+            };
+        }
+
+        if self.slow_motion {
+            return Some(FRAME_TIME * self.speed.slow_motion);
+        }
+
+        Some(FRAME_TIME)
+    }
+
+    /*
+        Sends the frame on if one has been completed since the last was sent
+     */
+    fn send_frame(&mut self) {
+        if let Some(frame) = self.game_boy.take_frame() {
+            send_frame(&mut self.sinks, frame);
+        }
+
+        // Sent even when paused, so the viewers show what the debugger or a loaded state changed
+        if let Some(vram) = self.vram.as_ref().filter(|_| self.viewing_vram) {
+            vram.update(self.game_boy.vram_snapshot());
+        }
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        if paused != self.paused {
+            println!("{}", if paused { "Paused" } else { "Resumed" });
+        }
+
+        self.paused = paused;
+    }
 
-        if !mmu::DEBUG_GB_DOCTOR { // If debugging, gotta go fast
-            if frame_time < target_frame_time {
-                sleep(target_frame_time - frame_time)
-            } else {
-                eprintln!("slow frame: {}ms", frame_time.as_millis())
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::SaveState(slot) => {
+                let path = slot_path(&self.save_dir, self.game_boy.mmu.cart(), slot);
+
+                match fs::create_dir_all(&self.save_dir).and_then(|_| fs::write(&path, self.game_boy.save_state())) {
+                    Ok(()) => println!("Saved state to slot {}", slot),
+                    Err(e) => eprintln!("unable to save state to {}: {}", path.display(), e),
+                }
+            }
+            Command::LoadState(slot) => {
+                // The movie would no longer match what happens
+                if self.movie.is_some() {
+                    eprintln!("can't load a state while a movie is recording or playing");
+                    return;
+                }
+
+                let path = slot_path(&self.save_dir, self.game_boy.mmu.cart(), slot);
+
+                let state = match fs::read(&path) {
+                    Ok(state) => state,
+                    Err(e) => {
+                        eprintln!("unable to read state {}: {}", path.display(), e);
+                        return;
+                    }
+                };
+
+                match self.game_boy.load_state(&state) {
+                    Ok(()) => {
+                        println!("Loaded state from slot {}", slot);
+                        self.game_boy.redraw();
+                    }
+                    Err(e) => eprintln!("unable to load state {}: {}", path.display(), e),
+                }
+            }
+            Command::Rewind(held) => {
+                if held && self.movie.is_some() {
+                    eprintln!("can't rewind while a movie is recording or playing");
+                    return;
+                }
+
+                self.rewinding = held;
+            }
+            Command::FastForward(held) => self.fast_forward = held,
+            Command::TogglePause => self.set_paused(!self.paused),
+            Command::FrameAdvance => {
+                self.set_paused(true);
+                self.run_frame();
+            }
+            Command::StepInstruction => {
+                self.set_paused(true);
+                self.step();
+
+                // Show the screen as far as it has been drawn
+                self.game_boy.redraw();
+
+                println!("PC = {:#06x}", self.game_boy.pc());
+            }
+            Command::ToggleSlowMotion => {
+                self.slow_motion = !self.slow_motion;
+                println!("Slow motion {}", if self.slow_motion { "on" } else { "off" });
+            }
+            Command::SoftReset | Command::HardReset if self.movie.is_some() => {
+                eprintln!("can't reset while a movie is recording or playing");
+            }
+            Command::SoftReset => {
+                self.game_boy.soft_reset();
+            }
+            Command::HardReset => {
+                self.game_boy.hard_reset();
+            }
+            Command::Break => {
+                match &mut self.gdb {
+                    Some(gdb) => gdb.pause(),
+                    None => self.debugger.get_or_insert_with(new_stdin_debugger).pause(),
+                }
+
+                self.set_paused(false);
+            }
+            Command::ViewVram(viewing) => self.viewing_vram = viewing,
+        }
+    }
+}
+
+/*
+    The keys to hold for the next frame, taken from the movie while one is playing
+ */
+fn next_input(input: &Input, movie: &mut Option<Movie>) -> u8 {
+    match movie {
+        Some(Movie::Play(player)) => match player.next_input() {
+            Some(pressed) => return pressed,
+            None => println!("Movie finished"),
+        },
+        Some(Movie::Record(recorder)) => {
+            let pressed = input.next_frame();
+
+            match recorder.record(pressed) {
+                Ok(()) => return pressed,
+                Err(e) => eprintln!("stopped recording movie: {}", e),
             }
         }
+        None => return input.next_frame(),
+    }
+
+    // Playback finished or recording failed, carry on with the keyboard
+    *movie = None;
+
+    input.next_frame()
+}
+
+/*
+    A DMG that has skipped the boot ROM and is about to run code, for tests
+ */
+#[cfg(test)]
+pub(crate) fn new_test_game_boy(code: &[u8], link: Option<Box<dyn LinkPartner>>) -> GameBoy {
+    let config = GameBoyConfig { boot: Some(Boot::Skip(boot::Model::Dmg)), link, ..Default::default() };
+
+    new_game_boy(cartridge::new_test_cartridge(code), config)
+}
+
+/*
+    Sends a frame to every sink, dropping any that fail
+ */
+pub(crate) fn send_frame(sinks: &mut Vec<Box<dyn FrameSink>>, frame: &[u8]) {
+    sinks.retain_mut(|sink| match sink.frame(frame) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("stopped sending frames: {}", e);
+            false
+        }
+    });
+}
+
+/*
+    Sleeps out the rest of the frame so that emulation runs at the intended speed
+ */
+fn wait_for_frame_end(start: SystemTime, target_frame_time: Duration) {
+    let frame_time = SystemTime::now().duration_since(start).unwrap();
+
+    if frame_time < target_frame_time {
+        sleep(target_frame_time - frame_time)
+    } else {
+        eprintln!("slow frame: {}ms", frame_time.as_millis())
     }
-}
\ No newline at end of file
+}
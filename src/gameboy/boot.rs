@@ -0,0 +1,194 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::{fs, io};
+
+/*
+    How the GB gets from power on to the cartridge's entry point at 0x0100.
+
+    Either a boot ROM is run, which is mapped over the start of the cartridge until it writes to
+    0xFF50, or booting is skipped by setting the registers to the values each model's boot ROM
+    leaves behind (see https://gbdev.io/pandocs/Power_Up_Sequence.html).
+
+    Only the DMG is emulated so far. The other models' boot ROMs and post boot values are accepted
+    so that ROMs checking which model they are running on take the right path.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Cgb,
+}
+
+pub const MODELS: [(&str, Model); 5] = [
+    ("dmg0", Model::Dmg0),
+    ("dmg", Model::Dmg),
+    ("mgb", Model::Mgb),
+    ("sgb", Model::Sgb),
+    ("cgb", Model::Cgb),
+];
+
+/*
+    A model from its name in MODELS, e.g. for --model. Otherwise returns what was expected.
+ */
+pub fn parse_model(name: &str) -> Result<Model, &'static str> {
+    MODELS.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, m)| *m)
+        .ok_or("dmg0, dmg, mgb, sgb or cgb")
+}
+
+impl Display for Model {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = MODELS.iter().find(|(_, m)| m == self).unwrap().0;
+
+        write!(f, "{}", name.to_uppercase())
+    }
+}
+
+impl Model {
+    /*
+        The size of the model's boot ROM. The CGB's is mapped at 0x0000-0x00FF and 0x0200-0x08FF,
+        leaving the cartridge header visible in between.
+     */
+    pub fn boot_rom_size(&self) -> usize {
+        match self {
+            Model::Cgb => 0x900,
+            _ => 0x100,
+        }
+    }
+
+    /*
+        MD5 of the known good dump of the model's boot ROM
+     */
+    fn boot_rom_md5(&self) -> &'static str {
+        match self {
+            Model::Dmg0 => "a8f84a0ac44da5d3f0ee19f9cea80a8c",
+            Model::Dmg => "32fbbd84168d3482956eb3c5051637f5",
+            Model::Mgb => "71a378e71ff30b2d8a1f02bf5c7896aa",
+            Model::Sgb => "d574d4f9c12f305074798f54c091a8b4",
+            Model::Cgb => "dbfce9db9deaa2567f6a84fde55f9680",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BootRomError {
+    Io(String, io::Error),
+    WrongSize(String, Model, usize),
+    WrongModel(String, Model, Model),
+}
+
+impl Display for BootRomError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BootRomError::Io(path, e) => write!(f, "unable to read boot ROM {}: {}", path, e),
+            BootRomError::WrongSize(path, model, size) => write!(f, "boot ROM {} is {} bytes, a {} boot ROM is {}", path, size, model, model.boot_rom_size()),
+            BootRomError::WrongModel(path, model, found) => write!(f, "boot ROM {} is for the {}, not the {}, use --model {}", path, found, model, found.to_string().to_lowercase()),
+        }
+    }
+}
+
+impl std::error::Error for BootRomError {}
+
+/*
+    Loads a dump of a model's boot ROM. A dump that doesn't match the known good one is still run
+    (it may be a patched or homebrew boot ROM), unless it matches a different model.
+ */
+pub fn load_boot_rom(path: &str, model: Model) -> Result<Vec<u8>, BootRomError> {
+    let data = fs::read(path).map_err(|e| BootRomError::Io(path.to_string(), e))?;
+
+    if data.len() != model.boot_rom_size() {
+        return Err(BootRomError::WrongSize(path.to_string(), model, data.len()));
+    }
+
+    let md5 = format!("{:x}", md5::compute(&data));
+
+    if md5 != model.boot_rom_md5() {
+        if let Some((_, found)) = MODELS.iter().find(|(_, m)| m.boot_rom_md5() == md5) {
+            return Err(BootRomError::WrongModel(path.to_string(), model, *found));
+        }
+
+        eprintln!("boot ROM {} doesn't match the known {} boot ROM (MD5 {}), running it anyway", path, model, md5);
+    }
+
+    Ok(data)
+}
+
+/*
+    How the GB starts up
+ */
+#[derive(Debug, Clone)]
+pub enum Boot {
+    Rom(Vec<u8>),
+    Skip(Model),
+}
+
+/*
+    Runs the boot ROM built into the emulator
+ */
+pub fn builtin_boot() -> Boot {
+    Boot::Rom(BUILTIN_BOOT_ROM.to_vec())
+}
+
+/*
+    The IO registers as the boot ROM leaves them, anything not listed is 0
+ */
+pub fn post_boot_io(model: Model) -> Vec<(u16, u8)> {
+    let dmg0 = model == Model::Dmg0;
+    let cgb = model == Model::Cgb;
+
+    vec![
+        (0xFF00, 0xCF), // P1
+        (0xFF02, if cgb { 0x7F } else { 0x7E }), // SC
+        (0xFF04, if dmg0 { 0x18 } else { 0xAB }), // DIV
+        (0xFF07, 0xF8), // TAC
+        (0xFF0F, 0xE1), // IF
+        (0xFF10, 0x80), // NR10
+        (0xFF11, 0xBF), // NR11
+        (0xFF12, 0xF3), // NR12
+        (0xFF13, 0xFF), // NR13
+        (0xFF14, 0xBF), // NR14
+        (0xFF16, 0x3F), // NR21
+        (0xFF18, 0xFF), // NR23
+        (0xFF19, 0xBF), // NR24
+        (0xFF1A, 0x7F), // NR30
+        (0xFF1B, 0xFF), // NR31
+        (0xFF1C, 0x9F), // NR32
+        (0xFF1D, 0xFF), // NR33
+        (0xFF1E, 0xBF), // NR34
+        (0xFF20, 0xFF), // NR41
+        (0xFF23, 0xBF), // NR44
+        (0xFF24, 0x77), // NR50
+        (0xFF25, 0xF3), // NR51
+        (0xFF26, if model == Model::Sgb { 0xF0 } else { 0xF1 }), // NR52
+        (0xFF40, 0x91), // LCDC
+        (0xFF41, if dmg0 { 0x81 } else { 0x85 }), // STAT
+        (0xFF44, if dmg0 { 0x91 } else { 0x00 }), // LY
+        (0xFF46, if cgb { 0x00 } else { 0xFF }), // DMA
+        (0xFF47, 0xFC), // BGP
+        (0xFF50, 0x01), // Boot ROM disabled
+    ]
+}
+
+/*
+    A DMG boot ROM, from: http://imrannazar.com/content/files/jsgb.mmu.js
+ */
+const BUILTIN_BOOT_ROM: [u8; 0x100] = [
+    0x31, 0xFE, 0xFF, 0xAF, 0x21, 0xFF, 0x9F, 0x32, 0xCB, 0x7C, 0x20, 0xFB, 0x21, 0x26, 0xFF, 0x0E,
+    0x11, 0x3E, 0x80, 0x32, 0xE2, 0x0C, 0x3E, 0xF3, 0xE2, 0x32, 0x3E, 0x77, 0x77, 0x3E, 0xFC, 0xE0,
+    0x47, 0x11, 0x04, 0x01, 0x21, 0x10, 0x80, 0x1A, 0xCD, 0x95, 0x00, 0xCD, 0x96, 0x00, 0x13, 0x7B,
+    0xFE, 0x34, 0x20, 0xF3, 0x11, 0xD8, 0x00, 0x06, 0x08, 0x1A, 0x13, 0x22, 0x23, 0x05, 0x20, 0xF9,
+    0x3E, 0x19, 0xEA, 0x10, 0x99, 0x21, 0x2F, 0x99, 0x0E, 0x0C, 0x3D, 0x28, 0x08, 0x32, 0x0D, 0x20,
+    0xF9, 0x2E, 0x0F, 0x18, 0xF3, 0x67, 0x3E, 0x64, 0x57, 0xE0, 0x42, 0x3E, 0x91, 0xE0, 0x40, 0x04,
+    0x1E, 0x02, 0x0E, 0x0C, 0xF0, 0x44, 0xFE, 0x90, 0x20, 0xFA, 0x0D, 0x20, 0xF7, 0x1D, 0x20, 0xF2,
+    0x0E, 0x13, 0x24, 0x7C, 0x1E, 0x83, 0xFE, 0x62, 0x28, 0x06, 0x1E, 0xC1, 0xFE, 0x64, 0x20, 0x06,
+    0x7B, 0xE2, 0x0C, 0x3E, 0x87, 0xF2, 0xF0, 0x42, 0x90, 0xE0, 0x42, 0x15, 0x20, 0xD2, 0x05, 0x20,
+    0x4F, 0x16, 0x20, 0x18, 0xCB, 0x4F, 0x06, 0x04, 0xC5, 0xCB, 0x11, 0x17, 0xC1, 0xCB, 0x11, 0x17,
+    0x05, 0x20, 0xF5, 0x22, 0x23, 0x22, 0x23, 0xC9, 0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B,
+    0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC,
+    0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E, 0x3c, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x4C,
+    0x21, 0x04, 0x01, 0x11, 0xA8, 0x00, 0x1A, 0x13, 0xBE, 0x20, 0xFE, 0x23, 0x7D, 0xFE, 0x34, 0x20,
+    0xF5, 0x06, 0x19, 0x78, 0x86, 0x23, 0x05, 0x20, 0xFB, 0x86, 0x20, 0xFE, 0x3E, 0x01, 0xE0, 0x50
+];
@@ -1,23 +1,81 @@
 use std::{fs, io};
 
+#[derive(Clone)]
 pub struct Cartridge {
     file: Vec<u8>,
 }
 
+// Bank 0 and one switchable bank, the smallest a ROM can be
+const MIN_ROM_SIZE: usize = 0x8000;
+
 pub fn new_cartridge_from_file(path: &str) -> Result<Cartridge, io::Error> {
-    let file = fs::read(path)?;
+    new_cartridge_from_bytes(fs::read(path)?)
+}
 
-    Ok(Cartridge { file })
+#[cfg(feature = "download")]
+pub fn new_cartridge_from_url(path: &str) -> Result<Cartridge, Box<dyn std::error::Error>> {
+    let file = Vec::<u8>::from(reqwest::blocking::get(path)?.error_for_status()?.bytes()?);
+
+    Ok(new_cartridge_from_bytes(file)?)
 }
 
-pub fn new_cartridge_from_url(path: &str) -> Result<Cartridge, reqwest::Error> {
-    let file = Vec::<u8>::from(reqwest::blocking::get(path)?.bytes()?);
+pub fn new_cartridge_from_bytes(file: Vec<u8>) -> Result<Cartridge, io::Error> {
+    if file.len() < MIN_ROM_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("ROM is {} bytes, it should be at least {}", file.len(), MIN_ROM_SIZE)));
+    }
 
     Ok(Cartridge { file })
 }
 
+/*
+    A ROM that starts running code straight from the entry point, for tests that skip the boot ROM
+ */
+#[cfg(test)]
+pub(crate) fn new_test_cartridge(code: &[u8]) -> Cartridge {
+    let mut file = vec![0; MIN_ROM_SIZE];
+
+    file[0x100..0x100 + code.len()].copy_from_slice(code);
+
+    Cartridge { file }
+}
+
+#[cfg(test)]
+impl Cartridge {
+    /*
+        Places more code in a test cartridge, e.g. an interrupt handler
+     */
+    pub(crate) fn with_code(mut self, addr: usize, code: &[u8]) -> Cartridge {
+        self.file[addr..addr + code.len()].copy_from_slice(code);
+        self
+    }
+}
 
 impl Cartridge {
+    /*
+        Identifies the ROM, e.g. to check a movie is being played back on the game it was recorded with
+     */
+    pub fn crc32(&self) -> u32 {
+        crc32fast::hash(&self.file)
+    }
+
+    /*
+        The checksum of the header, set by the boot ROM in the flags
+     */
+    pub fn header_checksum(&self) -> u8 {
+        self.file[0x014D]
+    }
+
+    /*
+        How many 16KB ROM banks there are, the last may be short if the ROM is an odd size
+     */
+    pub fn rom_banks(&self) -> usize {
+        self.file.len().div_ceil(0x4000)
+    }
+
+    pub fn rom_bank(&self, bank: usize) -> Option<&[u8]> {
+        self.file.chunks(0x4000).nth(bank)
+    }
+
     pub fn read_bank_0(&self) -> [u8; 16384] {
         /*
             This will result in a clone, which probably isn't ideal for performance.
@@ -0,0 +1,34 @@
+/*
+    Commands sent from the frontend to the emulator thread, which handles them between frames
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    SaveState(u8),
+    LoadState(u8),
+    // Sent with true when the key is pressed and false when it is released
+    Rewind(bool),
+    FastForward(bool),
+    TogglePause,
+    // Runs one frame and then pauses
+    FrameAdvance,
+    // Runs one CPU instruction and then pauses
+    StepInstruction,
+    ToggleSlowMotion,
+    // Restarts the CPU but keeps the contents of memory
+    SoftReset,
+    // Power cycles the GB
+    HardReset,
+    // Stops in the debugger, see debugger::Debugger
+    Break,
+    // Starts or stops copying VRAM to RunOptions::vram every frame, for the viewers
+    ViewVram(bool),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SpeedConfig {
+    // How many times faster than normal fast forward runs, 0 runs as fast as possible
+    pub fast_forward: u32,
+
+    // How many times slower than normal slow motion runs
+    pub slow_motion: u32,
+}
@@ -1,5 +1,7 @@
-use crate::gameboy::mmu;
+use crate::gameboy::boot::Model;
+use crate::gameboy::debug::DebugConfig;
 use crate::gameboy::mmu::MMU;
+use crate::gameboy::state::{StateError, StateReader, StateWriter};
 
 /*
     Conventions used (from: https://rgbds.gbdev.io/docs/v0.6.0/gbz80.7):
@@ -112,6 +114,24 @@ pub const FLAG_INT_SERIAL: u8 = 0x08;
 pub const FLAG_INT_JOYP: u8 = 0x10;
 
 pub const REG_INTERRUPTS: u16 = 0xFF0F;
+pub const REG_JOYPAD: u16 = 0xFF00;
+
+/*
+    A copy of the registers, for looking at from outside the CPU
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
 
 pub struct CPU {
     // clocks
@@ -142,66 +162,196 @@ pub struct CPU {
     // Halt represents a low power mode until an interrupt occurs
     halt: bool,
 
-    // Represents stopped?
+    // Stopped by STOP until a button is pressed
     stop: bool,
+
+    // Locked up by an invalid opcode, which only a reset gets out of
+    locked: bool,
+
+    // Print the registers before every instruction, for Gameboy Doctor
+    trace: bool,
+
+    // The vector jumped to if an interrupt was taken during the last exec
+    interrupt: Option<u16>,
+
+    // Where an invalid opcode was run, until taken by take_crash
+    crashed_at: Option<u16>,
 }
 
-pub fn new_cpu() -> CPU {
-    if mmu::DEBUG_GB_DOCTOR {
-        CPU { // For use with: https://github.com/robert/gameboy-doctor
-            clock_m: 0,
-            clock_t: 0,
-            reg_a: 0x01,
-            reg_b: 0x00,
-            reg_c: 0x13,
-            reg_d: 0x00,
-            reg_e: 0xD8,
-            reg_f: 0xB0,
-            reg_h: 0x01,
-            reg_l: 0x4D,
-            reg_pc: 0x0100,
-            reg_sp: 0xFFFE,
-            ime: true,
-            halt: false,
-            stop: false,
-        }
-    } else {
-        CPU {
-            clock_m: 0,
-            clock_t: 0,
-            reg_a: 0,
-            reg_b: 0,
-            reg_c: 0,
-            reg_d: 0,
-            reg_e: 0,
-            reg_f: 0,
-            reg_h: 0,
-            reg_l: 0,
-            reg_pc: 0,
-            reg_sp: 0,
-            ime: true,
-            halt: false,
-            stop: false,
-        }
+pub fn new_cpu(debug: &DebugConfig) -> CPU {
+    CPU {
+        clock_m: 0,
+        clock_t: 0,
+        reg_a: 0,
+        reg_b: 0,
+        reg_c: 0,
+        reg_d: 0,
+        reg_e: 0,
+        reg_f: 0,
+        reg_h: 0,
+        reg_l: 0,
+        reg_pc: 0,
+        reg_sp: 0,
+        ime: true,
+        halt: false,
+        stop: false,
+        locked: false,
+        trace: debug.trace,
+        interrupt: None,
+        crashed_at: None,
+    }
+}
+
+/*
+    The CPU as the model's boot ROM leaves it when jumping to the cartridge.
+
+    The DMG and MGB set the half carry and carry flags unless the header checksum is 0, which is
+    what https://github.com/robert/gameboy-doctor expects for its test ROMs.
+ */
+pub fn new_cpu_after_boot(model: Model, header_checksum: u8, debug: &DebugConfig) -> CPU {
+    let checksum_flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+
+    // A, F, B, C, D, E, H, L
+    let regs = match model {
+        Model::Dmg0 => [0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03],
+        Model::Dmg => [0x01, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+        Model::Mgb => [0xFF, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+        Model::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+        Model::Cgb => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+    };
+
+    CPU {
+        reg_a: regs[0],
+        reg_f: regs[1],
+        reg_b: regs[2],
+        reg_c: regs[3],
+        reg_d: regs[4],
+        reg_e: regs[5],
+        reg_h: regs[6],
+        reg_l: regs[7],
+        reg_pc: 0x0100,
+        reg_sp: 0xFFFE,
+        ..new_cpu(debug)
     }
 }
 
 impl CPU {
+    /*
+        The registers and the next few bytes at PC, as logged by https://github.com/robert/gameboy-doctor
+     */
+    pub(crate) fn doctor_line(&self, mmu: &MMU) -> String {
+        format!("A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+                self.reg_a, self.reg_f, self.reg_b, self.reg_c, self.reg_d, self.reg_e, self.reg_h, self.reg_l, self.reg_sp, self.reg_pc,
+                mmu.rb_unwatched(self.reg_pc), mmu.rb_unwatched(self.reg_pc.wrapping_add(1)), mmu.rb_unwatched(self.reg_pc.wrapping_add(2)), mmu.rb_unwatched(self.reg_pc.wrapping_add(3)))
+    }
+
+    pub(crate) fn interrupt(&self) -> Option<u16> {
+        self.interrupt
+    }
+
+    /*
+        Where an invalid opcode was run, if one has been since the last call
+     */
+    pub(crate) fn take_crash(&mut self) -> Option<u16> {
+        self.crashed_at.take()
+    }
+
+    pub(crate) fn pc(&self) -> u16 {
+        self.reg_pc
+    }
+
+    pub(crate) fn registers(&self) -> Registers {
+        Registers {
+            a: self.reg_a,
+            f: self.reg_f,
+            b: self.reg_b,
+            c: self.reg_c,
+            d: self.reg_d,
+            e: self.reg_e,
+            h: self.reg_h,
+            l: self.reg_l,
+            sp: self.reg_sp,
+            pc: self.reg_pc,
+        }
+    }
+
+    pub(crate) fn set_registers(&mut self, r: Registers) {
+        self.reg_a = r.a;
+        // The low bits of F are always 0
+        self.reg_f = r.f & 0xF0;
+        self.reg_b = r.b;
+        self.reg_c = r.c;
+        self.reg_d = r.d;
+        self.reg_e = r.e;
+        self.reg_h = r.h;
+        self.reg_l = r.l;
+        self.reg_sp = r.sp;
+        self.reg_pc = r.pc;
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.clock_m);
+        w.write_u32(self.clock_t);
+        for reg in [self.reg_a, self.reg_b, self.reg_c, self.reg_d, self.reg_e, self.reg_f, self.reg_h, self.reg_l] {
+            w.write_u8(reg);
+        }
+        w.write_u16(self.reg_pc);
+        w.write_u16(self.reg_sp);
+        w.write_bool(self.ime);
+        w.write_bool(self.halt);
+        w.write_bool(self.stop);
+        w.write_bool(self.locked);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.clock_m = r.read_u32()?;
+        self.clock_t = r.read_u32()?;
+        for reg in [&mut self.reg_a, &mut self.reg_b, &mut self.reg_c, &mut self.reg_d, &mut self.reg_e, &mut self.reg_f, &mut self.reg_h, &mut self.reg_l] {
+            *reg = r.read_u8()?;
+        }
+        self.reg_pc = r.read_u16()?;
+        self.reg_sp = r.read_u16()?;
+        self.ime = r.read_bool()?;
+        self.halt = r.read_bool()?;
+        self.stop = r.read_bool()?;
+        self.locked = r.read_bool()?;
+
+        Ok(())
+    }
+
     /*
         Execute the next CPU operation
 
         Returns (delta_m, delta_t
      */
     pub fn exec(&mut self, mmu: &mut MMU) -> (u32, u32) {
-        if mmu.in_bios && self.reg_pc == 0x100 {
-            mmu.in_bios = false;
+        self.interrupt = None;
+
+        if self.locked {
+            // The real CPU hangs, time still passes for everything else
+            self.clock_m = self.clock_m.wrapping_add(1);
+            self.clock_t = self.clock_t.wrapping_add(4);
+
+            return (1, 4);
         }
 
-        let opc = mmu.rb(self.reg_pc);
+        if self.stop {
+            // Stopped until one of the selected joypad lines is pulled low by a key press
+            if mmu.rb_unwatched(REG_JOYPAD) & 0x0F == 0x0F {
+                self.clock_m = self.clock_m.wrapping_add(1);
+                self.clock_t = self.clock_t.wrapping_add(4);
 
-        if mmu::DEBUG_GB_DOCTOR {
-            println!("A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
-                     self.reg_a, self.reg_f, self.reg_b, self.reg_c, self.reg_d, self.reg_e, self.reg_h, self.reg_l, self.reg_sp, self.reg_pc, mmu.rb(self.reg_pc), mmu.rb(self.reg_pc + 1), mmu.rb(self.reg_pc + 2), mmu.rb(self.reg_pc + 3))
+                return (1, 4);
+            }
+
+            self.stop = false;
+        }
+
+        // Seen by watchpoints as an execute rather than a read
+        let opc = mmu.rb_unwatched(self.reg_pc);
+
+        if self.trace {
+            println!("{}", self.doctor_line(mmu));
         }
 
         self.reg_pc = self.reg_pc.wrapping_add(1);
@@ -211,33 +361,35 @@ impl CPU {
 
         // If global interrupts are enabled
         if self.ime {
-            let i_e = mmu.rb(0xFFFF); // Individual interrupts enabled
-            let i_f = mmu.rb(REG_INTERRUPTS); // Which interrupts have occurred
+            // Checked between instructions, not by them, so watchpoints don't see it
+            let i_e = mmu.rb_unwatched(0xFFFF); // Individual interrupts enabled
+            let i_f = mmu.rb_unwatched(REG_INTERRUPTS); // Which interrupts have occurred
 
             if i_e & i_f > 0 { // If any enabled interrupts have ocurred
                 if i_e & i_f & FLAG_INT_VBLANK > 0 {
-                    println!("VBLANK!");
-                    mmu.wb(REG_INTERRUPTS, i_f & !FLAG_INT_VBLANK); // reset the flag
+                    mmu.wb_unwatched(REG_INTERRUPTS, i_f & !FLAG_INT_VBLANK); // reset the flag
                     self.ime = false;
                     self.rst(mmu, RST::RST40); // Execute the RST op
                 } else if i_e & i_f & FLAG_INT_LCD_STAT > 0 {
-                    mmu.wb(REG_INTERRUPTS, i_f & !FLAG_INT_LCD_STAT); // reset the flag
+                    mmu.wb_unwatched(REG_INTERRUPTS, i_f & !FLAG_INT_LCD_STAT); // reset the flag
                     self.ime = false;
                     self.rst(mmu, RST::RST48); // Execute the RST op
                 } else if i_e & i_f & FLAG_INT_TIMER > 0 {
-                    mmu.wb(REG_INTERRUPTS, i_f & !FLAG_INT_TIMER); // reset the flag
+                    mmu.wb_unwatched(REG_INTERRUPTS, i_f & !FLAG_INT_TIMER); // reset the flag
                     self.ime = false;
                     self.rst(mmu, RST::RST50); // Execute the RST op
                 } else if i_e & i_f & FLAG_INT_SERIAL > 0 {
-                    mmu.wb(REG_INTERRUPTS, i_f & !FLAG_INT_SERIAL); // reset the flag
+                    mmu.wb_unwatched(REG_INTERRUPTS, i_f & !FLAG_INT_SERIAL); // reset the flag
                     self.ime = false;
                     self.rst(mmu, RST::RST58); // Execute the RST op
                 } else if i_e & i_f & FLAG_INT_JOYP > 0 {
-                    mmu.wb(REG_INTERRUPTS, i_f & !FLAG_INT_JOYP); // reset the flag
+                    mmu.wb_unwatched(REG_INTERRUPTS, i_f & !FLAG_INT_JOYP); // reset the flag
                     self.ime = false;
                     self.rst(mmu, RST::RST60); // Execute the RST op
                 }
 
+                self.interrupt = Some(self.reg_pc);
+
                 cycles += 5;
                 cycles_t += 20;
             }
@@ -2462,17 +2614,20 @@ impl CPU {
     }
 
     /*
-        Enter ultra low power mode
+        Enter ultra low power mode until a button is pressed
      */
     fn stop(&mut self) -> u8 {
         self.stop = true;
 
-        0
+        1
     }
 
+    /*
+        Invalid opcodes lock the CPU up for good, unlike STOP no button press wakes it
+     */
     fn xx(&mut self) -> u8 {
-        println!("Unexpected operation at {}, stopping.", self.reg_pc - 1);
-        self.stop = true;
+        self.locked = true;
+        self.crashed_at = Some(self.reg_pc.wrapping_sub(1));
 
         0
     }
@@ -3053,4 +3208,56 @@ impl CPU {
             0xFF => self.set_u3_r8(7, R8::A),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gameboy::keys::{key_bit, Keys};
+    use crate::gameboy::new_test_game_boy;
+
+    // LD A,$10; LDH ($00),A selects the buttons in P1 so pressing A pulls a line low
+    const SELECT_BUTTONS: [u8; 4] = [0x3E, 0x10, 0xE0, 0x00];
+
+    fn run(code: &[u8]) -> crate::gameboy::GameBoy {
+        let mut gb = new_test_game_boy(&[&SELECT_BUTTONS, code].concat(), None);
+
+        for _ in 0..10 {
+            gb.step_instruction();
+        }
+
+        gb
+    }
+
+    #[test]
+    fn stop_wakes_on_a_button_press() {
+        // STOP; LD B,$42; JR -2
+        let mut gb = run(&[0x10, 0x00, 0x06, 0x42, 0x18, 0xFE]);
+
+        assert_eq!(gb.pc(), 0x105);
+
+        gb.set_buttons(key_bit(Keys::A));
+
+        for _ in 0..3 {
+            gb.step_instruction();
+        }
+
+        assert_eq!(gb.registers().b, 0x42);
+    }
+
+    #[test]
+    fn invalid_opcodes_lock_up_for_good() {
+        // An invalid opcode; LD B,$42
+        let mut gb = run(&[0xD3, 0x06, 0x42]);
+
+        assert_eq!(gb.pc(), 0x105);
+
+        gb.set_buttons(key_bit(Keys::A));
+
+        for _ in 0..10 {
+            gb.step_instruction();
+        }
+
+        assert_eq!(gb.pc(), 0x105);
+        assert_ne!(gb.registers().b, 0x42);
+    }
+}
@@ -0,0 +1,43 @@
+use crate::gameboy::boot::{Boot, Model};
+
+/*
+    Settings for debugging the emulator itself, chosen when the GB is created.
+
+    Everything is off by default. gb_doctor() turns on what https://github.com/robert/gameboy-doctor
+    needs to compare a run against its logs.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DebugConfig {
+    // Start at the cartridge's entry point, as the DMG's boot ROM would leave it
+    pub skip_boot: bool,
+
+    // LY always reads 0x90, so ROMs waiting for VBlank carry on straight away
+    pub stub_ly: bool,
+
+    // Print the registers before every instruction, in the Gameboy Doctor format
+    pub trace: bool,
+
+    // Run as fast as possible rather than in real time
+    pub unpaced: bool,
+}
+
+pub fn gb_doctor() -> DebugConfig {
+    DebugConfig {
+        skip_boot: true,
+        stub_ly: true,
+        trace: true,
+        unpaced: true,
+    }
+}
+
+impl DebugConfig {
+    /*
+        How the GB should boot, given how it was asked to
+     */
+    pub(crate) fn boot(&self, boot: Boot) -> Boot {
+        match boot {
+            Boot::Rom(_) if self.skip_boot => Boot::Skip(Model::Dmg),
+            boot => boot,
+        }
+    }
+}
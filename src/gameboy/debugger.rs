@@ -0,0 +1,714 @@
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+
+use crate::gameboy::disasm::Instruction;
+use crate::gameboy::GameBoy;
+use crate::gameboy::symbols::{banked, Symbols};
+use crate::gameboy::watch::{Access, parse_watchpoint, Watchpoint, WatchHit};
+
+/*
+    A command line debugger, in the spirit of gdb.
+
+    The debugger runs the GB one instruction at a time (see Debugger::step), and when it stops it
+    reads commands until told to carry on. It blocks whichever thread is running the GB, so in the
+    windowed build the window stays open showing the last frame while the emulation waits.
+
+    Watchpoints belong to the GB (see GameBoy::add_watchpoint), the debugger stops after the
+    instruction that hit one.
+
+    The call stack is worked out by watching CALL, RST, RET and interrupts go by, so it only knows
+    about calls made since the debugger was attached, and can be confused by code that plays with
+    the stack directly.
+ */
+
+const HELP: &str = "\
+Addresses and bytes are hex, counts are decimal. A breakpoint can be limited to a ROM bank with
+BANK:ADDR, e.g. 01:4A3C. With symbols, addresses can also be labels, e.g. break PlayerUpdate.
+An empty line repeats the last command.
+
+    c, continue              Run until a breakpoint
+    s, step [n]              Run n instructions (default 1), following calls
+    n, next                  Run the next instruction, running calls through to their return
+    f, finish                Run until the current call returns
+    b, break <addr>          Stop when PC reaches addr
+    d, delete [n]            Delete breakpoint n, or all of them
+    wa, watch <addrs> [rwx] [byte]
+                             Stop when addrs (an address or a range, e.g. c000-c0ff) are read,
+                             written (the default) or run, optionally only for the byte given
+    u, unwatch [n]           Delete watchpoint n, or all of them
+    i, info                  List the breakpoints and watchpoints
+    r, regs                  Show the registers and flags
+    l, list [addr] [n]       Disassemble n instructions (default 10) from addr, or around PC
+    x, mem <addr> [n]        Show n bytes (default 64) from addr
+    w, write <addr> <byte>.. Write bytes to memory, starting at addr
+    bt, stack                Show the call stack
+    q, quit                  Stop the emulator
+    h, help                  Show this message";
+
+// Calls deeper than this are assumed to never return, e.g. code that resets the stack pointer
+const MAX_CALLS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub addr: u16,
+
+    // Only in this ROM bank, any bank if None
+    pub bank: Option<u16>,
+}
+
+/*
+    A call that hasn't returned yet
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Call {
+    // Where the call was made from, or the address interrupted
+    pub from: u16,
+    pub to: u16,
+
+    // Where it will return to
+    pub ret: u16,
+
+    pub interrupt: bool,
+}
+
+/*
+    When to next stop and ask for commands
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    // Only at breakpoints
+    Breakpoint,
+    // Before the next instruction
+    Now,
+    // After n more instructions, at least 1
+    Steps(u32),
+    // Once the call stack is back down to this depth
+    Depth(usize),
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    calls: Vec<Call>,
+    stop: Stop,
+
+    // Given before there was a GB to add them to, added before the next instruction
+    watchpoints: Vec<Watchpoint>,
+
+    // Why it's stopping when a watchpoint was hit
+    watch_hit: Option<String>,
+
+    input: Box<dyn BufRead + Send>,
+    out: Box<dyn Write + Send>,
+
+    // Repeated on an empty line
+    last_command: String,
+
+    // Whether it has stopped to ask for commands since take_prompted was last called
+    prompted: bool,
+
+    // Set when carrying on from a stop, so that a breakpoint at PC doesn't stop it straight away
+    resumed: bool,
+
+    quit: bool,
+}
+
+/*
+    Reads commands from input, stopping before the first instruction
+ */
+pub fn new_debugger(input: Box<dyn BufRead + Send>, out: Box<dyn Write + Send>) -> Debugger {
+    Debugger {
+        breakpoints: vec!(),
+        calls: vec!(),
+        stop: Stop::Now,
+        watchpoints: vec!(),
+        watch_hit: None,
+        input,
+        out,
+        last_command: String::new(),
+        prompted: false,
+        resumed: false,
+        quit: false,
+    }
+}
+
+/*
+    Reads commands from the terminal
+ */
+pub fn new_stdin_debugger() -> Debugger {
+    new_debugger(Box::new(BufReader::new(io::stdin())), Box::new(io::stdout()))
+}
+
+impl Debugger {
+    /*
+        Runs one instruction, first asking for commands if it's time to stop.
+
+        Returns the t cycles taken, or None if the user has quit and nothing was run.
+     */
+    pub fn step(&mut self, game_boy: &mut GameBoy) -> Option<u32> {
+        for watchpoint in self.watchpoints.drain(..) {
+            game_boy.add_watchpoint(watchpoint);
+        }
+
+        let reason = match self.stop {
+            Stop::Now => Some(self.watch_hit.take().unwrap_or_default()),
+            _ if self.resumed => None,
+            _ => self.breakpoint_hit(game_boy),
+        };
+
+        self.resumed = false;
+
+        if let Some(reason) = reason {
+            self.prompted = true;
+            self.resumed = true;
+            self.stop = Stop::Breakpoint;
+
+            if let Err(e) = self.prompt(game_boy, &reason) {
+                eprintln!("debugger stopped: {}", e);
+                self.quit = true;
+            }
+
+            if self.quit {
+                return None;
+            }
+        }
+
+        let pc = game_boy.pc();
+        let sp = game_boy.registers().sp;
+        let instruction = game_boy.disassemble(pc);
+
+        let cycles = game_boy.step_instruction();
+
+        self.follow_calls(game_boy, pc, sp, &instruction);
+
+        if let Some(hit) = game_boy.take_watch_hit() {
+            self.watch_hit = Some(describe_hit(game_boy, &hit, &instruction));
+            self.stop = Stop::Now;
+            return Some(cycles);
+        }
+
+        self.stop = match self.stop {
+            Stop::Steps(1) => Stop::Now,
+            Stop::Steps(n) => Stop::Steps(n - 1),
+            Stop::Depth(depth) if self.calls.len() <= depth => Stop::Now,
+            stop => stop,
+        };
+
+        Some(cycles)
+    }
+
+    /*
+        Stops before the next instruction
+     */
+    pub fn pause(&mut self) {
+        self.stop = Stop::Now;
+    }
+
+    /*
+        Runs until a breakpoint
+     */
+    pub fn resume(&mut self) {
+        self.stop = Stop::Breakpoint;
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    /*
+        Adds a watchpoint to the GB before the next instruction
+     */
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn calls(&self) -> &[Call] {
+        &self.calls
+    }
+
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    /*
+        Whether it has stopped to ask for commands since the last call, which takes as long as the
+        user likes
+     */
+    pub fn take_prompted(&mut self) -> bool {
+        std::mem::take(&mut self.prompted)
+    }
+
+    fn breakpoint_hit(&self, game_boy: &GameBoy) -> Option<String> {
+        let pc = game_boy.pc();
+        let bank = game_boy.rom_bank(pc);
+
+        self.breakpoints.iter().position(|b| b.addr == pc && b.bank.is_none_or(|b| b == bank))
+            .map(|n| format!("breakpoint {} at {}\n", n, location(game_boy, pc)))
+    }
+
+    /*
+        Reads commands until one carries on running
+     */
+    fn prompt(&mut self, game_boy: &mut GameBoy, reason: &str) -> io::Result<()> {
+        let pc = game_boy.pc();
+        writeln!(self.out, "{}{}  {}", reason, location(game_boy, pc), game_boy.disassemble(pc))?;
+
+        loop {
+            write!(self.out, "(gb) ")?;
+            self.out.flush()?;
+
+            let mut line = String::new();
+
+            if self.input.read_line(&mut line)? == 0 {
+                // Nothing more to read, e.g. the end of a script of commands
+                self.quit = true;
+                return Ok(());
+            }
+
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => line.to_string(),
+            };
+
+            self.last_command = line.clone();
+
+            match self.command(game_boy, &line) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => writeln!(self.out, "{}", e)?,
+            }
+        }
+    }
+
+    /*
+        Runs a command, returning true if the GB should carry on running
+     */
+    fn command(&mut self, game_boy: &mut GameBoy, line: &str) -> Result<bool, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+
+        let out = |e: io::Error| e.to_string();
+
+        match command {
+            "c" | "continue" => {
+                self.stop = Stop::Breakpoint;
+                return Ok(true);
+            }
+            "s" | "step" => {
+                let n = match args.first() {
+                    Some(n) => n.parse().ok().filter(|&n| n > 0).ok_or_else(|| format!("invalid count {}", n))?,
+                    None => 1,
+                };
+
+                self.stop = Stop::Steps(n);
+                return Ok(true);
+            }
+            "n" | "next" => {
+                let instruction = game_boy.disassemble(game_boy.pc());
+
+                self.stop = match instruction.mnemonic {
+                    "call" | "rst" => Stop::Depth(self.calls.len()),
+                    _ => Stop::Steps(1),
+                };
+
+                return Ok(true);
+            }
+            "f" | "finish" => {
+                if self.calls.is_empty() {
+                    return Err("not in a call".to_string());
+                }
+
+                self.stop = Stop::Depth(self.calls.len() - 1);
+                return Ok(true);
+            }
+            "b" | "break" => {
+                let breakpoint = parse_breakpoint(args.first().ok_or("break needs an address")?, game_boy.symbols())?;
+
+                writeln!(self.out, "breakpoint {} at {}", self.breakpoints.len(), describe(&breakpoint, game_boy.symbols())).map_err(out)?;
+                self.breakpoints.push(breakpoint);
+            }
+            "d" | "delete" => match args.first() {
+                Some(n) => {
+                    let n: usize = n.parse().ok().filter(|&n| n < self.breakpoints.len()).ok_or_else(|| format!("no breakpoint {}", n))?;
+                    self.breakpoints.remove(n);
+                }
+                None => self.breakpoints.clear(),
+            },
+            "wa" | "watch" => {
+                let watchpoint = parse_watchpoint(&args.join(" "), game_boy.symbols())?;
+
+                writeln!(self.out, "watchpoint {} on {}", game_boy.watchpoints().len(), watchpoint).map_err(out)?;
+                game_boy.add_watchpoint(watchpoint);
+            }
+            "u" | "unwatch" => match args.first() {
+                Some(n) => {
+                    n.parse().ok().and_then(|n| game_boy.remove_watchpoint(n)).ok_or_else(|| format!("no watchpoint {}", n))?;
+                }
+                None => while game_boy.remove_watchpoint(0).is_some() {},
+            },
+            "i" | "info" => {
+                let watchpoints = game_boy.watchpoints();
+
+                if self.breakpoints.is_empty() {
+                    writeln!(self.out, "no breakpoints").map_err(out)?;
+                }
+
+                for (n, breakpoint) in self.breakpoints.iter().enumerate() {
+                    writeln!(self.out, "{}: {}", n, describe(breakpoint, game_boy.symbols())).map_err(out)?;
+                }
+
+                if watchpoints.is_empty() {
+                    writeln!(self.out, "no watchpoints").map_err(out)?;
+                }
+
+                for (n, watchpoint) in watchpoints.iter().enumerate() {
+                    writeln!(self.out, "watchpoint {}: {}", n, watchpoint).map_err(out)?;
+                }
+            }
+            "r" | "regs" => {
+                let r = game_boy.registers();
+                let flag = |bit: u8, name: char| if r.f & (1 << bit) != 0 { name } else { '-' };
+
+                writeln!(self.out, "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X}",
+                         r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l, r.sp, r.pc).map_err(out)?;
+                writeln!(self.out, "flags: {}{}{}{}", flag(7, 'Z'), flag(6, 'N'), flag(5, 'H'), flag(4, 'C')).map_err(out)?;
+            }
+            "l" | "list" => {
+                let (from, n) = match args.as_slice() {
+                    [] => (None, 10),
+                    [addr] => (Some(parse_addr(addr, game_boy.symbols())?), 10),
+                    [addr, n, ..] => (Some(parse_addr(addr, game_boy.symbols())?), n.parse().map_err(|_| format!("invalid count {}", n))?),
+                };
+
+                let pc = game_boy.pc();
+                let mut addr = from.unwrap_or_else(|| list_start(game_boy, pc));
+
+                for _ in 0..n {
+                    let instruction = game_boy.disassemble(addr);
+                    let marker = if addr == pc { "=>" } else { "  " };
+
+                    if let Some(label) = game_boy.symbols().and_then(|s| s.label(game_boy.rom_bank(addr), addr)) {
+                        writeln!(self.out, "{}:", label).map_err(out)?;
+                    }
+
+                    writeln!(self.out, "{} {:02X}:{:04X}  {}", marker, game_boy.rom_bank(addr), addr, instruction).map_err(out)?;
+                    addr = addr.wrapping_add(instruction.length);
+                }
+            }
+            "x" | "mem" => {
+                let addr = parse_addr(args.first().ok_or("mem needs an address")?, game_boy.symbols())?;
+                let n: u16 = match args.get(1) {
+                    Some(n) => n.parse().map_err(|_| format!("invalid count {}", n))?,
+                    None => 64,
+                };
+
+                for row in (0..n).step_by(16) {
+                    let start = addr.wrapping_add(row);
+                    let bytes = game_boy.read_bytes(start, (n - row).min(16) as usize);
+
+                    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                    let text: String = bytes.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+
+                    // Labels starting in the row
+                    let labels: Vec<&str> = (0..bytes.len() as u16).map(|i| start.wrapping_add(i))
+                        .filter_map(|a| game_boy.symbols()?.label(game_boy.rom_bank(a), a)).collect();
+
+                    let labels = if labels.is_empty() { String::new() } else { format!("  ; {}", labels.join(", ")) };
+
+                    writeln!(self.out, "{:04X}  {:<47}  {:<16}{}", start, hex.join(" "), text, labels).map_err(out)?;
+                }
+            }
+            "w" | "write" => {
+                let (addr, bytes) = args.split_first().ok_or("write needs an address and bytes")?;
+                let addr = parse_addr(addr, game_boy.symbols())?;
+
+                if bytes.is_empty() {
+                    return Err("write needs bytes to write".to_string());
+                }
+
+                for (i, byte) in bytes.iter().enumerate() {
+                    let value = u8::from_str_radix(byte.trim_start_matches('$').trim_start_matches("0x"), 16).map_err(|_| format!("invalid byte {}", byte))?;
+                    game_boy.write_byte(addr.wrapping_add(i as u16), value);
+                }
+            }
+            "bt" | "stack" => {
+                writeln!(self.out, "#0  {}", location(game_boy, game_boy.pc())).map_err(out)?;
+
+                for (n, call) in self.calls.iter().rev().enumerate() {
+                    let kind = if call.interrupt { "interrupt" } else { "called" };
+
+                    writeln!(self.out, "#{:<2} {}  {} from {}", n + 1, location(game_boy, call.to), kind, location(game_boy, call.from)).map_err(out)?;
+                }
+            }
+            "q" | "quit" => {
+                self.quit = true;
+                return Ok(true);
+            }
+            "h" | "help" => writeln!(self.out, "{}", HELP).map_err(out)?,
+            _ => return Err(format!("unknown command {}, see help", command)),
+        }
+
+        Ok(false)
+    }
+
+    /*
+        Keeps the call stack up to date after running instruction, which was at pc with the stack
+        pointer at sp
+     */
+    fn follow_calls(&mut self, game_boy: &GameBoy, pc: u16, sp: u16, instruction: &Instruction) {
+        let r = game_boy.registers();
+        let interrupt = game_boy.cpu.interrupt();
+
+        // An interrupt taken after the instruction pushed PC, which is where the instruction went
+        let (sp_after, pc_after) = match interrupt {
+            Some(_) => (r.sp.wrapping_add(2), game_boy.mmu.rw(r.sp)),
+            None => (r.sp, r.pc),
+        };
+
+        match instruction.mnemonic {
+            "call" | "rst" if sp_after == sp.wrapping_sub(2) => {
+                self.calls.push(Call { from: pc, to: pc_after, ret: pc.wrapping_add(instruction.length), interrupt: false });
+            }
+            "ret" | "reti" if sp_after == sp.wrapping_add(2) => {
+                // Unwind to the call being returned from, in case some were left with a jump
+                match self.calls.iter().rposition(|call| call.ret == pc_after) {
+                    Some(n) => self.calls.truncate(n),
+                    None => { self.calls.pop(); }
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(vector) = interrupt {
+            self.calls.push(Call { from: pc_after, to: vector, ret: pc_after, interrupt: true });
+        }
+
+        if self.calls.len() > MAX_CALLS {
+            self.calls.remove(0);
+        }
+    }
+}
+
+/*
+    Finds where to list from to show up to 3 instructions before PC. Instructions are different
+    lengths so this can't be known for sure, the furthest start that lands on PC is used.
+ */
+fn list_start(game_boy: &GameBoy, pc: u16) -> u16 {
+    for back in (1..=9).rev() {
+        let start = pc.wrapping_sub(back);
+        let mut addr = start;
+        let mut count = 0;
+
+        // Stops on PC, or once past it
+        while (1..=back).contains(&pc.wrapping_sub(addr)) {
+            addr = addr.wrapping_add(game_boy.disassemble(addr).length);
+            count += 1;
+        }
+
+        if addr == pc && count <= 3 {
+            return start;
+        }
+    }
+
+    pc
+}
+
+/*
+    e.g. 01:4A3C, or 01:4A3C <PlayerUpdate+$3> with symbols
+ */
+fn location(game_boy: &GameBoy, addr: u16) -> String {
+    match game_boy.describe_addr(addr) {
+        Some(label) => format!("{:02X}:{:04X} <{}>", game_boy.rom_bank(addr), addr, label),
+        None => format!("{:02X}:{:04X}", game_boy.rom_bank(addr), addr),
+    }
+}
+
+fn describe(breakpoint: &Breakpoint, symbols: Option<&Symbols>) -> String {
+    let addr = match breakpoint.bank {
+        Some(bank) => format!("{:02X}:{:04X}", bank, breakpoint.addr),
+        None => format!("{:04X}", breakpoint.addr),
+    };
+
+    match symbols.and_then(|s| s.describe(breakpoint.bank.unwrap_or(0), breakpoint.addr)) {
+        Some(label) => format!("{} <{}>", addr, label),
+        None => addr,
+    }
+}
+
+/*
+    e.g. "watchpoint 0: write 3F to C000 by 00:0153  ld [hl], a", where instruction is the one that
+    just ran
+ */
+fn describe_hit(game_boy: &GameBoy, hit: &WatchHit, instruction: &Instruction) -> String {
+    let by = format!("{}  {}", location(game_boy, hit.pc), instruction);
+
+    match hit.access {
+        Access::Read => format!("watchpoint {}: read {:02X} from {:04X} by {}\n", hit.watchpoint, hit.value, hit.addr, by),
+        Access::Write => format!("watchpoint {}: write {:02X} to {:04X} by {}\n", hit.watchpoint, hit.value, hit.addr, by),
+        // What's about to run is shown anyway
+        Access::Execute => format!("watchpoint {}: execute {:04X}\n", hit.watchpoint, hit.addr),
+    }
+}
+
+/*
+    ADDR or BANK:ADDR, in hex, or a label from symbols
+ */
+pub fn parse_breakpoint(value: &str, symbols: Option<&Symbols>) -> Result<Breakpoint, String> {
+    // Labels first, as some look like hex, e.g. Add
+    if let Some((bank, addr)) = symbols.and_then(|s| s.lookup(value)) {
+        return Ok(Breakpoint { addr, bank: banked(addr).then_some(bank) });
+    }
+
+    match value.split_once(':') {
+        Some((bank, addr)) => {
+            let bank = u16::from_str_radix(bank, 16).map_err(|_| format!("invalid bank {}", bank))?;
+            Ok(Breakpoint { addr: parse_hex(addr)?, bank: Some(bank) })
+        }
+        None => Ok(Breakpoint { addr: parse_hex(value)?, bank: None }),
+    }
+}
+
+/*
+    An address in hex or a label from symbols
+ */
+fn parse_addr(value: &str, symbols: Option<&Symbols>) -> Result<u16, String> {
+    match symbols.and_then(|s| s.lookup(value)) {
+        Some((_, addr)) => Ok(addr),
+        None => parse_hex(value),
+    }
+}
+
+fn parse_hex(value: &str) -> Result<u16, String> {
+    u16::from_str_radix(value.trim_start_matches('$').trim_start_matches("0x"), 16).map_err(|_| format!("invalid address {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::gameboy::boot::{Boot, Model};
+    use crate::gameboy::cartridge::new_test_cartridge;
+    use crate::gameboy::symbols::parse_symbols;
+    use crate::gameboy::{new_game_boy, new_test_game_boy, GameBoyConfig};
+
+    /*
+        Where it stopped for commands: after how many instructions, at what PC and how deep in calls
+     */
+    type Stopped = (usize, u16, usize);
+
+    /*
+        Runs the GB under a debugger reading script, until the script runs out
+     */
+    fn run(mut game_boy: GameBoy, script: &str) -> Vec<Stopped> {
+        let mut debugger = new_debugger(Box::new(Cursor::new(script.to_string())), Box::new(io::sink()));
+        let mut stops = vec!();
+
+        for steps in 0..1000 {
+            let (pc, depth) = (game_boy.pc(), debugger.calls().len());
+            let ran = debugger.step(&mut game_boy);
+
+            if debugger.take_prompted() {
+                stops.push((steps, pc, depth));
+            }
+
+            if ran.is_none() {
+                return stops;
+            }
+        }
+
+        panic!("still running after 1000 instructions, stopped at {:?}", stops);
+    }
+
+    // CALL $0110; CALL $0110; JR -2
+    // $0110: CALL $0118; RET
+    // $0118: NOP; RET
+    const CALLS: [u8; 26] = [
+        0xCD, 0x10, 0x01, 0xCD, 0x10, 0x01, 0x18, 0xFE, 0, 0, 0, 0, 0, 0, 0, 0,
+        0xCD, 0x18, 0x01, 0xC9, 0, 0, 0, 0, 0x00, 0xC9,
+    ];
+
+    #[test]
+    fn steps_into_and_over_calls() {
+        let stops = run(new_test_game_boy(&CALLS, None), "s\ns\nf\nf\nn\nf\ns 2\n");
+
+        assert_eq!(stops, vec!(
+            (0, 0x0100, 0),
+            (1, 0x0110, 1),
+            (2, 0x0118, 2),
+            // finish runs NOP; RET
+            (4, 0x0113, 1),
+            (5, 0x0103, 0),
+            // next runs the whole call, then finish fails as it's not in one
+            (10, 0x0106, 0),
+            (12, 0x0106, 0),
+        ));
+    }
+
+    #[test]
+    fn repeats_the_last_command() {
+        let stops = run(new_test_game_boy(&[0x00; 8], None), "s\n\ns 2\n\n");
+
+        assert_eq!(stops.iter().map(|&(_, pc, _)| pc).collect::<Vec<_>>(), vec!(0x0100, 0x0101, 0x0102, 0x0104, 0x0106));
+    }
+
+    #[test]
+    fn unwinds_calls_left_with_a_jump() {
+        // CALL $0110; JR -2
+        // $0110: CALL $0118
+        // $0118: POP HL; RET, back to the first call's caller
+        let code = [0xCD, 0x10, 0x01, 0x18, 0xFE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xCD, 0x18, 0x01, 0, 0, 0, 0, 0, 0xE1, 0xC9];
+        let stops = run(new_test_game_boy(&code, None), "s 2\ns 2\n");
+
+        assert_eq!(stops, vec!((0, 0x0100, 0), (2, 0x0118, 2), (4, 0x0103, 0)));
+    }
+
+    #[test]
+    fn follows_interrupts() {
+        // DI; LD A,$01; LDH [$0F],A; LDH [$FF],A; EI; NOP; JR -2, requesting and enabling VBlank
+        // $0040: RETI
+        let cart = new_test_cartridge(&[0xF3, 0x3E, 0x01, 0xE0, 0x0F, 0xE0, 0xFF, 0xFB, 0x00, 0x18, 0xFE]).with_code(0x40, &[0xD9]);
+        let game_boy = new_game_boy(cart, GameBoyConfig { boot: Some(Boot::Skip(Model::Dmg)), ..Default::default() });
+
+        // Taken straight after EI, then RETI goes back to the NOP
+        let stops = run(game_boy, "s 5\ns\ns\n");
+
+        assert_eq!(stops, vec!((0, 0x0100, 0), (5, 0x0040, 1), (6, 0x0108, 0), (7, 0x0109, 0)));
+    }
+
+    #[test]
+    fn carries_on_past_the_breakpoint_it_stopped_at() {
+        // NOP; JR -3
+        let stops = run(new_test_game_boy(&[0x00, 0x18, 0xFD], None), "b 0100\nc\nc\n");
+
+        assert_eq!(stops, vec!((0, 0x0100, 0), (2, 0x0100, 0), (4, 0x0100, 0)));
+    }
+
+    #[test]
+    fn lists_from_a_few_instructions_back() {
+        // LD A,$01; LD HL,$C000; NOP; NOP, after the NOPs up to the entry point
+        let game_boy = new_test_game_boy(&[0x3E, 0x01, 0x21, 0x00, 0xC0, 0x00, 0x00], None);
+
+        assert_eq!(list_start(&game_boy, 0x0106), 0x0100);
+        assert_eq!(list_start(&game_boy, 0x0105), 0x00FF);
+        assert_eq!(list_start(&game_boy, 0x0102), 0x00FE);
+        assert_eq!(list_start(&game_boy, 0x0100), 0x00FD);
+    }
+
+    #[test]
+    fn parses_breakpoints() {
+        let symbols = parse_symbols("00:0150 Main\n01:4A3C PlayerUpdate\n02:4000 Add\n").unwrap();
+        let breakpoint = |addr, bank| Ok(Breakpoint { addr, bank });
+
+        assert_eq!(parse_breakpoint("4a3c", None), breakpoint(0x4A3C, None));
+        assert_eq!(parse_breakpoint("$0150", None), breakpoint(0x0150, None));
+        assert_eq!(parse_breakpoint("01:4A3C", None), breakpoint(0x4A3C, Some(1)));
+        assert_eq!(parse_breakpoint("Main", Some(&symbols)), breakpoint(0x0150, None));
+        assert_eq!(parse_breakpoint("PlayerUpdate", Some(&symbols)), breakpoint(0x4A3C, Some(1)));
+        assert_eq!(parse_breakpoint("Add", Some(&symbols)), breakpoint(0x4000, Some(2)));
+        assert_eq!(parse_breakpoint("Add", None), breakpoint(0x0ADD, None));
+        assert_eq!(parse_breakpoint("Main", None), Err("invalid address Main".to_string()));
+        assert_eq!(parse_breakpoint("zz:4A3C", None), Err("invalid bank zz".to_string()));
+        assert_eq!(parse_breakpoint("01:", None), Err("invalid address ".to_string()));
+    }
+}
@@ -0,0 +1,254 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+/*
+    Turns SM83 machine code back into RGBDS assembly (see https://rgbds.gbdev.io/docs/gbz80.7).
+
+    Opcodes are decoded from their bit fields rather than a 512 entry table, following
+    https://gbdev.io/gb-opcodes/optables/octal: an opcode is xxyyyzzz, with y split into pp and q
+    where it picks a register pair.
+
+    Jumps show where they go rather than the offset, e.g. jr nz, $0150. Opcodes that don't exist
+    are shown as db.
+ */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub mnemonic: &'static str,
+
+    // e.g. "a, [hl+]", empty when there are none
+    pub operands: String,
+
+    // In bytes, including the CB prefix
+    pub length: u16,
+
+    // t cycles, with a conditional branch taken
+    pub cycles: u32,
+
+    // t cycles for a conditional branch that isn't taken
+    pub cycles_not_taken: Option<u32>,
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.operands.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operands)
+        }
+    }
+}
+
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+const R16_STACK: [&str; 4] = ["bc", "de", "hl", "af"];
+const R16_MEM: [&str; 4] = ["[bc]", "[de]", "[hl+]", "[hl-]"];
+const CC: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+const ROTATE: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const MISC: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+
+/*
+    Decodes the instruction at addr, reading memory with read, e.g. |a| mmu.rb(a)
+ */
+pub fn disassemble(addr: u16, read: impl Fn(u16) -> u8) -> Instruction {
+    let opc = read(addr);
+    let n8 = read(addr.wrapping_add(1));
+    let n16 = u16::from_le_bytes([n8, read(addr.wrapping_add(2))]);
+    let e8 = n8 as i8;
+
+    // Where a jr goes, from the end of the instruction
+    let jr_target = addr.wrapping_add(2).wrapping_add(e8 as u16);
+
+    let x = opc >> 6;
+    let y = ((opc >> 3) & 7) as usize;
+    let z = opc & 7;
+    let p = y >> 1;
+    let q = y & 1;
+
+    // [hl] takes an extra memory access
+    let r8_cycles = |r: usize, cycles: u32| if r == 6 { cycles + 4 } else { cycles };
+
+    let op = |mnemonic, operands: String, length, cycles| Instruction { mnemonic, operands, length, cycles, cycles_not_taken: None };
+    let branch = |mnemonic, operands: String, length, cycles, not_taken| Instruction { mnemonic, operands, length, cycles, cycles_not_taken: Some(not_taken) };
+
+    match (x, z) {
+        (0, 0) => match y {
+            0 => op("nop", String::new(), 1, 4),
+            1 => op("ld", format!("[${:04X}], sp", n16), 3, 20),
+            2 => op("stop", String::new(), 2, 4),
+            3 => op("jr", format!("${:04X}", jr_target), 2, 12),
+            _ => branch("jr", format!("{}, ${:04X}", CC[y - 4], jr_target), 2, 12, 8),
+        },
+        (0, 1) if q == 0 => op("ld", format!("{}, ${:04X}", R16[p], n16), 3, 12),
+        (0, 1) => op("add", format!("hl, {}", R16[p]), 1, 8),
+        (0, 2) if q == 0 => op("ld", format!("{}, a", R16_MEM[p]), 1, 8),
+        (0, 2) => op("ld", format!("a, {}", R16_MEM[p]), 1, 8),
+        (0, 3) => op(if q == 0 { "inc" } else { "dec" }, R16[p].to_string(), 1, 8),
+        (0, 4) => op("inc", R8[y].to_string(), 1, if y == 6 { 12 } else { 4 }),
+        (0, 5) => op("dec", R8[y].to_string(), 1, if y == 6 { 12 } else { 4 }),
+        (0, 6) => op("ld", format!("{}, ${:02X}", R8[y], n8), 2, r8_cycles(y, 8)),
+        (0, _) => op(MISC[y], String::new(), 1, 4),
+
+        (1, 6) if y == 6 => op("halt", String::new(), 1, 4),
+        (1, _) => op("ld", format!("{}, {}", R8[y], R8[z as usize]), 1, r8_cycles(y, r8_cycles(z as usize, 4))),
+
+        (2, _) => alu(y, R8[z as usize].to_string(), 1, r8_cycles(z as usize, 4)),
+
+        (3, 0) => match y {
+            0..=3 => branch("ret", CC[y].to_string(), 1, 20, 8),
+            4 => op("ldh", format!("[${:04X}], a", 0xFF00 | n8 as u16), 2, 12),
+            5 => op("add", format!("sp, {}", e8), 2, 16),
+            6 => op("ldh", format!("a, [${:04X}]", 0xFF00 | n8 as u16), 2, 12),
+            _ => op("ld", format!("hl, sp {} {}", if e8 < 0 { '-' } else { '+' }, e8.unsigned_abs()), 2, 12),
+        },
+        (3, 1) if q == 0 => op("pop", R16_STACK[p].to_string(), 1, 12),
+        (3, 1) => match p {
+            0 => op("ret", String::new(), 1, 16),
+            1 => op("reti", String::new(), 1, 16),
+            2 => op("jp", "hl".to_string(), 1, 4),
+            _ => op("ld", "sp, hl".to_string(), 1, 8),
+        },
+        (3, 2) => match y {
+            0..=3 => branch("jp", format!("{}, ${:04X}", CC[y], n16), 3, 16, 12),
+            4 => op("ldh", "[c], a".to_string(), 1, 8),
+            5 => op("ld", format!("[${:04X}], a", n16), 3, 16),
+            6 => op("ldh", "a, [c]".to_string(), 1, 8),
+            _ => op("ld", format!("a, [${:04X}]", n16), 3, 16),
+        },
+        (3, 3) => match y {
+            0 => op("jp", format!("${:04X}", n16), 3, 16),
+            1 => disassemble_cb(n8),
+            6 => op("di", String::new(), 1, 4),
+            7 => op("ei", String::new(), 1, 4),
+            _ => db(opc),
+        },
+        (3, 4) if y < 4 => branch("call", format!("{}, ${:04X}", CC[y], n16), 3, 24, 12),
+        (3, 5) if q == 0 => op("push", R16_STACK[p].to_string(), 1, 16),
+        (3, 5) if p == 0 => op("call", format!("${:04X}", n16), 3, 24),
+        (3, 6) => alu(y, format!("${:02X}", n8), 2, 8),
+        (3, 7) => op("rst", format!("${:02X}", y * 8), 1, 16),
+        _ => db(opc),
+    }
+}
+
+/*
+    Decodes the instruction at the start of bytes, which are at addr. Anything past the end of
+    bytes reads as 0.
+ */
+pub fn disassemble_bytes(bytes: &[u8], addr: u16) -> Instruction {
+    disassemble(addr, |a| bytes.get(a.wrapping_sub(addr) as usize).copied().unwrap_or(0))
+}
+
+fn disassemble_cb(opc: u8) -> Instruction {
+    let y = (opc >> 3) & 7;
+    let r = (opc & 7) as usize;
+
+    // BIT only reads [hl], the rest write it back
+    let (mnemonic, operands, cycles) = match opc >> 6 {
+        0 => (ROTATE[y as usize], R8[r].to_string(), if r == 6 { 16 } else { 8 }),
+        1 => ("bit", format!("{}, {}", y, R8[r]), if r == 6 { 12 } else { 8 }),
+        2 => ("res", format!("{}, {}", y, R8[r]), if r == 6 { 16 } else { 8 }),
+        _ => ("set", format!("{}, {}", y, R8[r]), if r == 6 { 16 } else { 8 }),
+    };
+
+    Instruction { mnemonic, operands, length: 2, cycles, cycles_not_taken: None }
+}
+
+/*
+    Everything but ADD, ADC and SBC leaves out the a, as is usual in RGBDS code
+ */
+fn alu(y: usize, operand: String, length: u16, cycles: u32) -> Instruction {
+    let operands = match y {
+        0 | 1 | 3 => format!("a, {}", operand),
+        _ => operand,
+    };
+
+    Instruction { mnemonic: ALU[y], operands, length, cycles, cycles_not_taken: None }
+}
+
+/*
+    A byte that isn't an instruction
+ */
+pub fn db(opc: u8) -> Instruction {
+    Instruction { mnemonic: "db", operands: format!("${:02X}", opc), length: 1, cycles: 4, cycles_not_taken: None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(bytes: &[u8], addr: u16) -> String {
+        disassemble_bytes(bytes, addr).to_string()
+    }
+
+    #[test]
+    fn decodes_operands() {
+        assert_eq!(text(&[0x00], 0), "nop");
+        assert_eq!(text(&[0x01, 0x34, 0x12], 0), "ld bc, $1234");
+        assert_eq!(text(&[0x08, 0x00, 0xC0], 0), "ld [$C000], sp");
+        assert_eq!(text(&[0x22], 0), "ld [hl+], a");
+        assert_eq!(text(&[0x3A], 0), "ld a, [hl-]");
+        assert_eq!(text(&[0x36, 0x7F], 0), "ld [hl], $7F");
+        assert_eq!(text(&[0x78], 0), "ld a, b");
+        assert_eq!(text(&[0x76], 0), "halt");
+        assert_eq!(text(&[0x86], 0), "add a, [hl]");
+        assert_eq!(text(&[0xE6, 0x0F], 0), "and $0F");
+        assert_eq!(text(&[0xE0, 0x44], 0), "ldh [$FF44], a");
+        assert_eq!(text(&[0xF2], 0), "ldh a, [c]");
+        assert_eq!(text(&[0xE8, 0xFE], 0), "add sp, -2");
+        assert_eq!(text(&[0xF8, 0x05], 0), "ld hl, sp + 5");
+        assert_eq!(text(&[0xF8, 0x80], 0), "ld hl, sp - 128");
+        assert_eq!(text(&[0xF5], 0), "push af");
+        assert_eq!(text(&[0xFF], 0), "rst $38");
+        assert_eq!(text(&[0xCB, 0x37], 0), "swap a");
+        assert_eq!(text(&[0xCB, 0x7E], 0), "bit 7, [hl]");
+        assert_eq!(text(&[0xCB, 0xC6], 0), "set 0, [hl]");
+    }
+
+    #[test]
+    fn shows_where_jumps_go() {
+        assert_eq!(text(&[0x18, 0xFE], 0x0150), "jr $0150");
+        assert_eq!(text(&[0x20, 0x10], 0x0150), "jr nz, $0162");
+        assert_eq!(text(&[0x38, 0x80], 0x0000), "jr c, $FF82");
+        assert_eq!(text(&[0xC3, 0x50, 0x01], 0), "jp $0150");
+        assert_eq!(text(&[0xCC, 0x00, 0x40], 0), "call z, $4000");
+    }
+
+    #[test]
+    fn counts_bytes_and_cycles() {
+        let timing = |bytes: &[u8]| {
+            let i = disassemble_bytes(bytes, 0);
+            (i.length, i.cycles, i.cycles_not_taken)
+        };
+
+        assert_eq!(timing(&[0x10, 0x00]), (2, 4, None));
+        assert_eq!(timing(&[0x34]), (1, 12, None));
+        assert_eq!(timing(&[0x46]), (1, 8, None));
+        assert_eq!(timing(&[0x20, 0x00]), (2, 12, Some(8)));
+        assert_eq!(timing(&[0xC0]), (1, 20, Some(8)));
+        assert_eq!(timing(&[0xC4, 0x00, 0x00]), (3, 24, Some(12)));
+        assert_eq!(timing(&[0xCB, 0x46]), (2, 12, None));
+        assert_eq!(timing(&[0xCB, 0x06]), (2, 16, None));
+    }
+
+    #[test]
+    fn invalid_opcodes_are_data() {
+        let invalid = [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD];
+
+        for opc in 0..=0xFFu8 {
+            let instruction = disassemble_bytes(&[opc, 0x00, 0x00], 0);
+
+            assert_eq!(instruction.mnemonic == "db", invalid.contains(&opc), "{:02X}", opc);
+            assert!((1..=3).contains(&instruction.length), "{:02X}", opc);
+        }
+
+        assert_eq!(text(&[0xD3], 0), "db $D3");
+    }
+
+    #[test]
+    fn reads_past_the_end_as_zero() {
+        assert_eq!(text(&[0xC3], 0), "jp $0000");
+        assert_eq!(text(&[0x01, 0x34], 0x4000), "ld bc, $0034");
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+
+/*
+    Where completed frames go once the GB has drawn them.
+
+    A frame is 3 bytes per pixel (RGB), row by row from the top left. It's 160x144 for a single
+    GB, or wider when several screens are shown together.
+ */
+pub trait FrameSink: Send {
+    /*
+        Called with every completed frame. An error stops any more frames being sent here.
+     */
+    fn frame(&mut self, frame: &[u8]) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/*
+    Shows frames in a speedy2d window, which receives them as user events
+ */
+#[cfg(feature = "window")]
+impl FrameSink for speedy2d::window::UserEventSender<Vec<u8>> {
+    fn frame(&mut self, frame: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.send_event(frame.to_vec()).map_err(|_| "the window has closed")?;
+
+        Ok(())
+    }
+}
+
+/*
+    Keeps the latest frame in memory. It can be cloned, with every clone sharing the same frame, so
+    one clone can be handed to the emulator and another kept to look at what it has drawn.
+ */
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    shared: Arc<Mutex<MemoryFrames>>,
+}
+
+#[derive(Default)]
+struct MemoryFrames {
+    latest: Option<Vec<u8>>,
+
+    // How many frames have been received
+    count: u64,
+}
+
+pub fn new_memory_sink() -> MemorySink {
+    MemorySink::default()
+}
+
+impl MemorySink {
+    /*
+        The last frame received, if there has been one
+     */
+    pub fn latest(&self) -> Option<Vec<u8>> {
+        self.shared.lock().unwrap().latest.clone()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.shared.lock().unwrap().count
+    }
+}
+
+impl FrameSink for MemorySink {
+    fn frame(&mut self, frame: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut shared = self.shared.lock().unwrap();
+
+        shared.latest = Some(frame.to_vec());
+        shared.count += 1;
+
+        Ok(())
+    }
+}
+
+/*
+    Writes every frame to a file one after the other as raw RGB, which can be turned into a video,
+    e.g. for a single GB at 60 frames per second:
+        ffmpeg -f rawvideo -pixel_format rgb24 -video_size 160x144 -framerate 60 -i frames.rgb out.mp4
+
+    Whatever is still buffered is written when the sink is dropped. A frame is bigger than the
+    buffer and goes straight to the file, so closing the window mid-run doesn't lose any.
+ */
+pub struct FileSink {
+    file: BufWriter<File>,
+}
+
+pub fn new_file_sink(path: &str) -> Result<FileSink, io::Error> {
+    Ok(FileSink { file: BufWriter::new(File::create(path)?) })
+}
+
+impl FrameSink for FileSink {
+    fn frame(&mut self, frame: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        self.file.write_all(frame)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.flush() {
+            eprintln!("unable to finish writing frames: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    #[test]
+    fn writes_every_frame_by_the_time_it_is_dropped() {
+        let path = env::temp_dir().join(format!("rusty-gigabyte-frames-{}.rgb", std::process::id()));
+        let mut sink = new_file_sink(path.to_str().unwrap()).unwrap();
+
+        sink.frame(&[1; 160 * 144 * 3]).unwrap();
+        sink.frame(&[2; 3]).unwrap();
+        drop(sink);
+
+        let written = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(written, [vec![1; 160 * 144 * 3], vec![2; 3]].concat());
+    }
+}
@@ -0,0 +1,638 @@
+use std::io;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::gameboy::{GameBoy, Registers};
+use crate::gameboy::watch::{Access, Watchpoint};
+
+/*
+    A stub for GDB's remote serial protocol (https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html),
+    so GDB, or anything else speaking the protocol, can debug the GB over TCP.
+
+    GDB has no SM83 support, so the registers are described in target.xml as the 16 bit pairs AF,
+    BC, DE, HL, SP and PC, in that order. Software and hardware breakpoints are the same thing
+    here, and watchpoints become the GB's (see GameBoy::add_watchpoint).
+
+    Like the Debugger this runs the GB an instruction at a time, serving packets while stopped.
+    While running, the connection is checked for a ctrl-c every so often.
+ */
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rusty-gigabyte.sm83">
+    <reg name="af" bitsize="16" type="uint16"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="uint16"/>
+    <reg name="hl" bitsize="16" type="uint16"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// Instructions run between checks for a ctrl-c, around 4 per frame
+const POLL_INTERVAL: u32 = 4096;
+
+// GDB's signal numbers, for why the GB stopped
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+pub struct GdbStub {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+
+    breakpoints: Vec<u16>,
+
+    // Whether GDB is waiting for the GB to stop
+    running: bool,
+
+    // Stop after the next instruction
+    stepping: bool,
+
+    // Instructions run since last checking for a ctrl-c
+    since_poll: u32,
+
+    // Whether it has stopped to serve GDB since take_prompted was last called
+    prompted: bool,
+
+    // GDB has gone, the GB just runs
+    detached: bool,
+
+    quit: bool,
+}
+
+/*
+    Waits for GDB to connect, e.g. with target remote localhost:2159. The GB is stopped before the
+    first instruction.
+ */
+pub fn new_gdb_stub_listen(addr: &str) -> Result<GdbStub, io::Error> {
+    let listener = TcpListener::bind(addr)?;
+
+    let (stream, _) = listener.accept()?;
+
+    new_gdb_stub(stream)
+}
+
+/*
+    Serves GDB over a connection that has already been made
+ */
+fn new_gdb_stub(stream: TcpStream) -> Result<GdbStub, io::Error> {
+    // Packets are small and GDB waits on every one
+    stream.set_nodelay(true)?;
+
+    Ok(GdbStub {
+        reader: BufReader::new(stream.try_clone()?),
+        writer: stream,
+        breakpoints: vec!(),
+        running: false,
+        stepping: false,
+        since_poll: 0,
+        prompted: false,
+        detached: false,
+        quit: false,
+    })
+}
+
+impl GdbStub {
+    /*
+        Runs one instruction, first serving GDB if stopped.
+
+        Returns the t cycles taken, or None if GDB killed the GB and nothing was run.
+     */
+    pub fn step(&mut self, game_boy: &mut GameBoy) -> Option<u32> {
+        if self.detached {
+            return Some(game_boy.step_instruction());
+        }
+
+        if self.running {
+            if self.breakpoints.contains(&game_boy.pc()) {
+                self.stop(&format!("S{:02x}", SIGTRAP));
+            } else if self.interrupted() {
+                self.stop(&format!("S{:02x}", SIGINT));
+            }
+        }
+
+        if !self.running && !self.detached {
+            self.prompted = true;
+
+            if let Err(e) = self.serve(game_boy) {
+                self.disconnect(e);
+            }
+
+            if self.quit {
+                return None;
+            }
+        }
+
+        let cycles = game_boy.step_instruction();
+
+        if let Some(hit) = game_boy.take_watch_hit() {
+            let watchpoint = game_boy.watchpoints().get(hit.watchpoint).cloned();
+
+            let kind = match (hit.access, watchpoint) {
+                (Access::Execute, _) => None,
+                (_, Some(w)) if w.read && w.write => Some("awatch"),
+                (Access::Read, _) => Some("rwatch"),
+                (Access::Write, _) => Some("watch"),
+            };
+
+            match kind {
+                Some(kind) => self.stop(&format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.addr)),
+                None => self.stop(&format!("S{:02x}", SIGTRAP)),
+            }
+        } else if self.stepping {
+            self.stop(&format!("S{:02x}", SIGTRAP));
+        }
+
+        Some(cycles)
+    }
+
+    /*
+        Stops before the next instruction, as if GDB had sent a ctrl-c
+     */
+    pub fn pause(&mut self) {
+        if self.running {
+            self.stop(&format!("S{:02x}", SIGINT));
+        }
+    }
+
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    /*
+        Whether it has stopped to serve GDB since the last call, which takes as long as the user
+        likes
+     */
+    pub fn take_prompted(&mut self) -> bool {
+        std::mem::take(&mut self.prompted)
+    }
+
+    /*
+        Tells GDB the GB has stopped and why
+     */
+    fn stop(&mut self, reply: &str) {
+        self.running = false;
+        self.stepping = false;
+
+        if let Err(e) = self.send(reply) {
+            self.disconnect(e);
+        }
+    }
+
+    fn disconnect(&mut self, e: io::Error) {
+        eprintln!("gdb disconnected: {}", e);
+        self.detached = true;
+    }
+
+    /*
+        Checks for a ctrl-c without waiting, every POLL_INTERVAL instructions
+     */
+    fn interrupted(&mut self) -> bool {
+        self.since_poll += 1;
+
+        if self.since_poll < POLL_INTERVAL {
+            return false;
+        }
+
+        self.since_poll = 0;
+
+        let byte = match self.read_now() {
+            Ok(byte) => byte,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
+            Err(e) => {
+                self.disconnect(e);
+                return false;
+            }
+        };
+
+        match byte {
+            Some(byte) => byte == 0x03,
+            None => {
+                self.disconnect(ErrorKind::UnexpectedEof.into());
+                false
+            }
+        }
+    }
+
+    /*
+        Reads a byte if one has already arrived, None if the connection has closed
+     */
+    fn read_now(&mut self) -> io::Result<Option<u8>> {
+        self.writer.set_nonblocking(true)?;
+        let byte = self.reader.fill_buf().map(|buf| buf.first().copied());
+        self.writer.set_nonblocking(false)?;
+
+        let byte = byte?;
+
+        if byte.is_some() {
+            self.reader.consume(1);
+        }
+
+        Ok(byte)
+    }
+
+    /*
+        Answers packets until GDB says to carry on
+     */
+    fn serve(&mut self, game_boy: &mut GameBoy) -> io::Result<()> {
+        loop {
+            let packet = self.read_packet()?;
+
+            match self.command(game_boy, &packet) {
+                Some(reply) => self.send(&reply)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /*
+        Waits for the next packet, acknowledging it
+     */
+    fn read_packet(&mut self) -> io::Result<String> {
+        loop {
+            let mut byte = [0];
+
+            // Skips acks, and any ctrl-c sent while already stopped
+            loop {
+                self.reader.read_exact(&mut byte)?;
+
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+
+            let mut data = vec!();
+            self.reader.read_until(b'#', &mut data)?;
+
+            if data.pop() != Some(b'#') {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum)?;
+
+            let expected = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+
+            if std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok()) == Some(expected) {
+                self.writer.write_all(b"+")?;
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+
+            // Asks for it again
+            self.writer.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+
+        write!(self.writer, "${}#{:02x}", data, checksum)?;
+        self.writer.flush()
+    }
+
+    /*
+        Handles a packet, returning the reply, or None if the GB should carry on running.
+        Unsupported packets get an empty reply, as the protocol expects.
+     */
+    fn command(&mut self, game_boy: &mut GameBoy, packet: &str) -> Option<String> {
+        let Some(command) = packet.chars().next() else {
+            return Some(String::new());
+        };
+
+        let args = &packet[command.len_utf8()..];
+
+        match command {
+            '?' => Some(format!("S{:02x}", SIGTRAP)),
+            'g' => Some(registers(game_boy).iter().map(|&r| hex_u16(r)).collect()),
+            'G' => Some(reply(write_registers(game_boy, args))),
+            'p' => {
+                let value = usize::from_str_radix(args, 16).ok().and_then(|n| registers(game_boy).get(n).copied());
+                Some(value.map(hex_u16).unwrap_or_else(|| "E01".to_string()))
+            }
+            'P' => Some(reply(write_register(game_boy, args))),
+            'm' => Some(read_memory(game_boy, args).unwrap_or_else(|| "E01".to_string())),
+            'M' => Some(reply(write_memory(game_boy, args))),
+            'c' | 's' => {
+                if let Some(addr) = parse_hex(args) {
+                    game_boy.set_registers(Registers { pc: addr, ..game_boy.registers() });
+                }
+
+                self.running = true;
+                self.stepping = command == 's';
+                None
+            }
+            'Z' | 'z' => Some(self.breakpoint(game_boy, command == 'Z', args)),
+            'H' | 'T' => Some("OK".to_string()),
+            'q' => Some(query(args)),
+            'k' => {
+                self.quit = true;
+                None
+            }
+            'D' => {
+                // There's no waiting for the OK to arrive, the GB just carries on
+                let _ = self.send("OK");
+                self.detached = true;
+                None
+            }
+            _ => Some(String::new()),
+        }
+    }
+
+    /*
+        Z and z: type,addr,kind. Types 0 and 1 are breakpoints, 2, 3 and 4 write, read and access
+        watchpoints with kind being the number of bytes.
+     */
+    fn breakpoint(&mut self, game_boy: &mut GameBoy, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+
+        let (Some(kind), Some(addr), Some(len)) = (fields.next(), fields.next().and_then(parse_hex), fields.next().and_then(parse_hex)) else {
+            return "E01".to_string();
+        };
+
+        match (kind, insert) {
+            ("0" | "1", true) => {
+                if !self.breakpoints.contains(&addr) {
+                    self.breakpoints.push(addr);
+                }
+            }
+            ("0" | "1", false) => self.breakpoints.retain(|&b| b != addr),
+            ("2" | "3" | "4", _) => {
+                let watchpoint = Watchpoint {
+                    addrs: addr..=addr.saturating_add(len.max(1) - 1),
+                    read: kind != "2",
+                    write: kind != "3",
+                    execute: false,
+                    value: None,
+                };
+
+                if insert {
+                    game_boy.add_watchpoint(watchpoint);
+                } else if let Some(n) = game_boy.watchpoints().iter().position(|w| *w == watchpoint) {
+                    game_boy.remove_watchpoint(n);
+                }
+            }
+            _ => return String::new(),
+        }
+
+        "OK".to_string()
+    }
+}
+
+fn write_registers(game_boy: &mut GameBoy, args: &str) -> Option<()> {
+    let mut values = [0; 6];
+
+    for (n, value) in values.iter_mut().enumerate() {
+        *value = parse_hex_u16(args.get(n * 4..n * 4 + 4)?)?;
+    }
+
+    set_registers(game_boy, &values);
+    Some(())
+}
+
+fn write_register(game_boy: &mut GameBoy, args: &str) -> Option<()> {
+    let (n, value) = args.split_once('=')?;
+
+    let mut values = registers(game_boy);
+    *values.get_mut(usize::from_str_radix(n, 16).ok()?)? = parse_hex_u16(value)?;
+
+    set_registers(game_boy, &values);
+    Some(())
+}
+
+fn registers(game_boy: &GameBoy) -> [u16; 6] {
+    let r = game_boy.registers();
+    let pair = |hi: u8, lo: u8| u16::from_be_bytes([hi, lo]);
+
+    [pair(r.a, r.f), pair(r.b, r.c), pair(r.d, r.e), pair(r.h, r.l), r.sp, r.pc]
+}
+
+fn set_registers(game_boy: &mut GameBoy, values: &[u16; 6]) {
+    let [[a, f], [b, c], [d, e], [h, l]] = [values[0], values[1], values[2], values[3]].map(u16::to_be_bytes);
+
+    game_boy.set_registers(Registers { a, f, b, c, d, e, h, l, sp: values[4], pc: values[5] });
+}
+
+/*
+    qXfer reads are answered a chunk at a time, starting with m if there's more to come or l if
+    it's the last
+ */
+fn query(args: &str) -> String {
+    if args.starts_with("Supported") {
+        return "PacketSize=1000;qXfer:features:read+".to_string();
+    }
+
+    if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+        let Some((offset, len)) = range.split_once(',').and_then(|(o, l)| Some((usize::from_str_radix(o, 16).ok()?, usize::from_str_radix(l, 16).ok()?))) else {
+            return "E01".to_string();
+        };
+
+        let end = offset.saturating_add(len);
+
+        let chunk = TARGET_XML.get(offset.min(TARGET_XML.len())..end.min(TARGET_XML.len())).unwrap_or("");
+        let more = end < TARGET_XML.len();
+
+        return format!("{}{}", if more { 'm' } else { 'l' }, chunk);
+    }
+
+    match args {
+        "Attached" => "1".to_string(),
+        "C" => "QC1".to_string(),
+        "fThreadInfo" => "m1".to_string(),
+        "sThreadInfo" => "l".to_string(),
+        _ => String::new(),
+    }
+}
+
+/*
+    m addr,len
+ */
+fn read_memory(game_boy: &GameBoy, args: &str) -> Option<String> {
+    let (addr, len) = args.split_once(',')?;
+    let bytes = game_boy.read_bytes(parse_hex(addr)?, usize::from_str_radix(len, 16).ok()?.min(0x10000));
+
+    Some(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/*
+    M addr,len:bytes
+ */
+fn write_memory(game_boy: &mut GameBoy, args: &str) -> Option<()> {
+    let (addr, rest) = args.split_once(',')?;
+    let (_, data) = rest.split_once(':')?;
+    let addr = parse_hex(addr)?;
+
+    let bytes = (0..data.len() / 2).map(|i| u8::from_str_radix(data.get(i * 2..i * 2 + 2)?, 16).ok()).collect::<Option<Vec<u8>>>()?;
+
+    for (i, byte) in bytes.into_iter().enumerate() {
+        game_boy.write_byte(addr.wrapping_add(i as u16), byte);
+    }
+
+    Some(())
+}
+
+fn reply(result: Option<()>) -> String {
+    match result {
+        Some(()) => "OK".to_string(),
+        None => "E01".to_string(),
+    }
+}
+
+fn parse_hex(value: &str) -> Option<u16> {
+    u16::from_str_radix(value, 16).ok()
+}
+
+/*
+    Registers are sent in the GB's byte order, little endian
+ */
+fn hex_u16(value: u16) -> String {
+    format!("{:02x}{:02x}", value as u8, value >> 8)
+}
+
+fn parse_hex_u16(value: &str) -> Option<u16> {
+    let lo = u8::from_str_radix(value.get(0..2)?, 16).ok()?;
+    let hi = u8::from_str_radix(value.get(2..4)?, 16).ok()?;
+
+    Some(u16::from_le_bytes([lo, hi]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::gameboy::new_test_game_boy;
+
+    // LD A,$42; LD B,$07; NOP; JR -2
+    const CODE: [u8; 7] = [0x3E, 0x42, 0x06, 0x07, 0x00, 0x18, 0xFE];
+
+    /*
+        Plays GDB's part over a real connection
+     */
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn send_raw(&mut self, packet: &str) {
+            self.writer.write_all(packet.as_bytes()).unwrap();
+        }
+
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.reader.read_exact(&mut byte).unwrap();
+
+            byte[0]
+        }
+
+        /*
+            Sends a packet and returns the reply, checking both are acknowledged
+         */
+        fn request(&mut self, data: &str) -> String {
+            let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+
+            self.send_raw(&format!("${}#{:02x}", data, checksum));
+            assert_eq!(self.read_byte(), b'+');
+
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            assert_eq!(self.read_byte(), b'$');
+
+            let mut data = vec!();
+            self.reader.read_until(b'#', &mut data).unwrap();
+            data.pop();
+
+            let checksum = [self.read_byte(), self.read_byte()];
+            let expected = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            assert_eq!(std::str::from_utf8(&checksum).unwrap(), format!("{:02x}", expected));
+
+            String::from_utf8(data).unwrap()
+        }
+    }
+
+    /*
+        Runs a GB under a stub on another thread until GDB kills it
+     */
+    fn connect() -> (Client, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let gb = thread::spawn(move || {
+            let mut stub = new_gdb_stub(listener.accept().unwrap().0).unwrap();
+            let mut game_boy = new_test_game_boy(&CODE, None);
+
+            while stub.step(&mut game_boy).is_some() {}
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+        (Client { reader: BufReader::new(stream.try_clone().unwrap()), writer: stream }, gb)
+    }
+
+    #[test]
+    fn runs_to_a_breakpoint() {
+        let (mut gdb, gb) = connect();
+
+        assert_eq!(gdb.request("?"), "S05");
+
+        let regs = gdb.request("g");
+        assert_eq!(regs.len(), 24);
+        assert_eq!(&regs[16..], "feff0001");
+
+        assert_eq!(gdb.request("m0100,4"), "3e420607");
+        assert_eq!(gdb.request("Z0,0105,1"), "OK");
+
+        // Stops after both loads, at the breakpoint
+        assert_eq!(gdb.request("c"), "S05");
+
+        let regs = gdb.request("g");
+        assert_eq!((&regs[2..4], &regs[4..8], &regs[20..]), ("42", "1307", "0501"));
+
+        // Carries on round the loop to the breakpoint again
+        assert_eq!(gdb.request("c"), "S05");
+
+        gdb.send_raw("$k#6b");
+        assert_eq!(gdb.read_byte(), b'+');
+        gb.join().unwrap();
+    }
+
+    #[test]
+    fn asks_again_for_bad_packets() {
+        let (mut gdb, gb) = connect();
+
+        // An ack and a ctrl-c before the packet are skipped over
+        gdb.send_raw("+\x03$?#00");
+        assert_eq!(gdb.read_byte(), b'-');
+
+        gdb.send_raw("$?#3f");
+        assert_eq!(gdb.read_byte(), b'+');
+        assert_eq!(gdb.reply(), "S05");
+
+        assert_eq!(gdb.request("m0100"), "E01");
+        assert_eq!(gdb.request("Z9,0100,1"), "");
+        assert_eq!(gdb.request("Z0,nowhere,1"), "E01");
+        assert_eq!(gdb.request("vMustReplyEmpty"), "");
+
+        gdb.send_raw("$k#6b");
+        assert_eq!(gdb.read_byte(), b'+');
+        gb.join().unwrap();
+    }
+
+    #[test]
+    fn sends_the_target_description_in_chunks() {
+        let first = query("Xfer:features:read:target.xml:0,10");
+        assert_eq!(first, format!("m{}", &TARGET_XML[..0x10]));
+
+        let rest = query(&format!("Xfer:features:read:target.xml:10,{:x}", TARGET_XML.len()));
+        assert_eq!(rest, format!("l{}", &TARGET_XML[0x10..]));
+
+        assert_eq!(query("Xfer:features:read:target.xml:ffffffffffffffff,ffff"), "l");
+        assert_eq!(query("Xfer:features:read:target.xml:10"), "E01");
+    }
+}
@@ -1,30 +1,32 @@
 use std::sync::mpsc::Sender;
 use std::thread::sleep;
 use std::time::Duration;
-use speedy2d::window::UserEventSender;
 use crate::gameboy::cpu;
 
 use crate::gameboy::mmu::MMU;
+use crate::gameboy::state::{StateError, StateReader, StateWriter};
 
-const REG_LCD_GPU_CONTROL: u16 = 0xFF40;
-const REG_SCROLL_Y: u16 = 0xFF42;
-const REG_SCROLL_X: u16 = 0xFF43;
+pub(crate) const REG_LCD_GPU_CONTROL: u16 = 0xFF40;
+pub(crate) const REG_SCROLL_Y: u16 = 0xFF42;
+pub(crate) const REG_SCROLL_X: u16 = 0xFF43;
 const REG_CURR_SCAN_LINE: u16 = 0xFF44;
-const REG_BG_PALETTE: u16 = 0xFF47;
+pub(crate) const REG_BG_PALETTE: u16 = 0xFF47;
 
-const REG_SPR_PALETTE_0: u16 = 0xFF48;
-const REG_SPR_PALETTE_1: u16 = 0xFF49;
+pub(crate) const REG_SPR_PALETTE_0: u16 = 0xFF48;
+pub(crate) const REG_SPR_PALETTE_1: u16 = 0xFF49;
+pub(crate) const REG_WINDOW_Y: u16 = 0xFF4A;
+pub(crate) const REG_WINDOW_X: u16 = 0xFF4B;
 
 const FLAG_CONT_BG_ON: u8 = 0x01;
 const FLAG_CONT_SPR_ON: u8 = 0x02;
 const FLAG_CONT_SPR_SZ: u8 = 0x04;
 // 8x8 when unset, 16x16 when set
-const FLAG_CONT_BG_MAP: u8 = 0x08;
+pub(crate) const FLAG_CONT_BG_MAP: u8 = 0x08;
 // #0 when off #1 when on (which map is in use)
-const FLAG_CONT_BG_SET: u8 = 0x10;
+pub(crate) const FLAG_CONT_BG_SET: u8 = 0x10;
 // #0 when off #1 when on (which tileset is in use)
-const FLAG_CONT_WIN_ON: u8 = 0x20;
-const FLAG_CONT_WIN_TM: u8 = 0x40;
+pub(crate) const FLAG_CONT_WIN_ON: u8 = 0x20;
+pub(crate) const FLAG_CONT_WIN_TM: u8 = 0x40;
 // #0 when off #1 when on (which window tilemap)
 const FLAG_CONT_DISP_ON: u8 = 0x80;
 
@@ -37,13 +39,23 @@ const FLAG_SPR_X_FLIP: u8 = 0x20;
 // The pallete to be used for the sprite #0 is obj palette 0, #1 is palette 1
 const FLAG_SPR_PALETTE: u8 = 0x10;
 
-const COLORS: [[u8; 3]; 4] = [
+// The RGB colour of each of the 4 shades, lightest first
+pub type Palette = [[u8; 3]; 4];
+
+const COLORS: Palette = [
     [255, 255, 255], // OFF
     [192, 192, 192], // 33%
     [96, 96, 96], // 66%
     [0, 0, 0], // ON
 ];
 
+pub const PALETTES: [(&str, Palette); 3] = [
+    ("grey", COLORS),
+    // The original DMG's green screen
+    ("green", [[0x9B, 0xBC, 0x0F], [0x8B, 0xAC, 0x0F], [0x30, 0x62, 0x30], [0x0F, 0x38, 0x0F]]),
+    ("pocket", [[0xC4, 0xCF, 0xA1], [0x8B, 0x95, 0x6D], [0x4D, 0x53, 0x3C], [0x1F, 0x1F, 0x1F]]),
+];
+
 enum Mode {
     HBlank,
     // Horizonal Blank
@@ -71,22 +83,79 @@ pub struct GPU {
     // The framebuffer
     fb: Vec<u8>, // [u8; 160 * 144 * 3], // 3 bytes per pixel (RGB), 160x144 pixels.
 
-    // The channel to dispatch the framebuffer on
-    sender: UserEventSender<Vec<u8>>,
+    // The colours shades are drawn in
+    colors: Palette,
+
+    // Set when the framebuffer holds a completed frame that hasn't been taken yet
+    frame_ready: bool,
 }
 
-pub fn new_gpu(sender: UserEventSender<Vec<u8>>) -> GPU {
+pub fn new_gpu() -> GPU {
     GPU {
         mode: Mode::HBlank,
         mode_clock: 0,
         line: 0,
         fb: vec![0; 69120], //[0; 69120],
-        sender,
+        colors: COLORS,
+        frame_ready: false,
     }
 }
 
 
 impl GPU {
+    pub(crate) fn reset(&mut self) {
+        let colors = self.colors;
+
+        *self = new_gpu();
+        self.colors = colors;
+    }
+
+    pub(crate) fn set_palette(&mut self, colors: Palette) {
+        self.colors = colors;
+    }
+
+    pub(crate) fn colors(&self) -> Palette {
+        self.colors
+    }
+
+    pub(crate) fn framebuffer(&self) -> &[u8] {
+        &self.fb
+    }
+
+    /*
+        Marks the framebuffer as a completed frame, ready for take_frame
+     */
+    pub(crate) fn present(&mut self) {
+        self.frame_ready = true;
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(match self.mode {
+            Mode::HBlank => 0,
+            Mode::VBlank => 1,
+            Mode::ScOam => 2,
+            Mode::ScVram => 3,
+        });
+        w.write_u32(self.mode_clock);
+        w.write_u8(self.line);
+        w.write_bytes(&self.fb);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.mode = match r.read_u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::ScOam,
+            3 => Mode::ScVram,
+            _ => return Err(StateError::Invalid("GPU mode")),
+        };
+        self.mode_clock = r.read_u32()?;
+        self.line = r.read_u8()?;
+        r.read_bytes(&mut self.fb)?;
+
+        Ok(())
+    }
+
     /*
         This probably seems a bit odd. Followin Imran's guide, this is setting
         up the timings to roughly emulate the behaviour of the GPU in the GB.
@@ -136,7 +205,7 @@ impl GPU {
                         let i_f = mmu.rb(cpu::REG_INTERRUPTS);
                         mmu.wb(cpu::REG_INTERRUPTS, i_f | cpu::FLAG_INT_VBLANK);
 
-                        self.sender.send_event(self.fb.clone()).unwrap(); //TODO: Handle error?
+                        self.present();
                     } else {
                         self.mode = Mode::ScOam;
                     }
@@ -160,18 +229,39 @@ impl GPU {
         mmu.wb(REG_CURR_SCAN_LINE, self.line);
     }
 
-    fn get_palette(&mut self, mmu: &mut MMU, addr: u16) -> [[u8; 3]; 4] {
-        let raw_palette = mmu.rb(addr);
+    /*
+        Returns the framebuffer if a frame has been completed since the last call
+     */
+    pub(crate) fn take_frame(&mut self) -> Option<&[u8]> {
+        if !self.frame_ready {
+            return None;
+        }
+
+        self.frame_ready = false;
+
+        Some(&self.fb)
+    }
+
+    fn get_palette(&mut self, mmu: &mut MMU, addr: u16) -> Palette {
+        Self::palette_colors(&self.colors, mmu.rb(addr))
+    }
 
+    /*
+        The colour of each colour number through a palette register, e.g. BGP
+     */
+    pub(crate) fn palette_colors(colors: &Palette, raw_palette: u8) -> Palette {
         [
-            COLORS[(raw_palette & 0b00000011) as usize],
-            COLORS[((raw_palette & 0b00001100) >> 2) as usize],
-            COLORS[((raw_palette & 0b00110000) >> 4) as usize],
-            COLORS[((raw_palette & 0b11000000) >> 6) as usize],
+            colors[(raw_palette & 0b00000011) as usize],
+            colors[((raw_palette & 0b00001100) >> 2) as usize],
+            colors[((raw_palette & 0b00110000) >> 4) as usize],
+            colors[((raw_palette & 0b11000000) >> 6) as usize],
         ]
     }
 
-    fn tilerow_n_to_color(&self, b1: u8, b2: u8, n: u8) -> u8 {
+    /*
+        The colour number (0-3) of bit n of a tile row, from its two bytes. Bit 7 is the leftmost pixel.
+     */
+    pub(crate) fn tilerow_n_to_color(b1: u8, b2: u8, n: u8) -> u8 {
         ((b1 & (1 << n)) >> n) + (((b2 & (1 << n)) >> n) << 1) //TODO: This is a bit gross...
     }
 
@@ -237,7 +327,7 @@ impl GPU {
                 let b1 = mmu.rb(0x8000 + (tile * 16) + ((y as u16) * 2));
                 let b2 = mmu.rb(0x8000 + (tile * 16) + ((y as u16) * 2) + 1);
 
-                let palette_key = self.tilerow_n_to_color(b1, b2, (7 - x));
+                let palette_key = Self::tilerow_n_to_color(b1, b2, (7 - x));
 
                 scan_line[i] = palette_key;
 
@@ -324,7 +414,7 @@ impl GPU {
                             };
 
                             // Get pixel
-                            let palette_key = self.tilerow_n_to_color(b1, b2, x);
+                            let palette_key = Self::tilerow_n_to_color(b1, b2, x);
 
                             // Write if not transparent or not covered by background
                             if (sprite[3] & FLAG_SPR_IN_BACKGROUND == 0) && palette_key != 0 ||
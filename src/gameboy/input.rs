@@ -0,0 +1,243 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::gameboy::keys::{key_bit, Keys};
+
+/*
+    The joypad as the player drives it, before it reaches the GB.
+
+    The frontend presses and releases keys here as they happen, and once per frame the emulator
+    asks for the keys to hold for the next frame. Turbo buttons and macros are worked out then,
+    so they are always frame exact however often the frontend delivers events.
+
+    Keys are held as one bit per key, see keys::key_bit.
+ */
+
+pub const FRAMES_PER_SECOND: u32 = 60;
+
+/*
+    Holds the keys for a number of frames
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MacroStep {
+    pub pressed: u8,
+    pub frames: u32,
+}
+
+pub struct Input {
+    state: Mutex<InputState>,
+}
+
+struct InputState {
+    // Keys held down by the player
+    held: u8,
+
+    // Turbo buttons held down by the player
+    turbo: u8,
+
+    // How many frames a turbo button spends pressed, and then released
+    turbo_frames: u32,
+
+    // Frames since a turbo button was first held, so every burst starts with a press
+    turbo_clock: u32,
+
+    // What is left of the running macro, one entry per frame
+    playing: VecDeque<u8>,
+
+    // The player's input on each frame while a macro is being recorded
+    recording: Option<Vec<u8>>,
+
+    recorded: Vec<u8>,
+}
+
+/*
+    turbo_rate is how many times per second turbo buttons are pressed
+ */
+pub fn new_input(turbo_rate: u32) -> Input {
+    Input {
+        state: Mutex::new(InputState {
+            held: 0,
+            turbo: 0,
+            turbo_frames: (FRAMES_PER_SECOND / (turbo_rate.max(1) * 2)).max(1),
+            turbo_clock: 0,
+            playing: VecDeque::new(),
+            recording: None,
+            recorded: vec!(),
+        }),
+    }
+}
+
+impl Input {
+    pub fn key_down(&self, key: Keys) {
+        self.state.lock().unwrap().held |= key_bit(key);
+    }
+
+    pub fn key_up(&self, key: Keys) {
+        self.state.lock().unwrap().held &= !key_bit(key);
+    }
+
+    pub fn turbo_down(&self, key: Keys) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.turbo == 0 {
+            state.turbo_clock = 0;
+        }
+
+        state.turbo |= key_bit(key);
+    }
+
+    pub fn turbo_up(&self, key: Keys) {
+        self.state.lock().unwrap().turbo &= !key_bit(key);
+    }
+
+    /*
+        Runs a macro from the next frame, replacing any macro that is already running
+     */
+    pub fn play_macro(&self, steps: &[MacroStep]) {
+        let mut state = self.state.lock().unwrap();
+
+        state.playing = steps.iter()
+            .flat_map(|step| std::iter::repeat_n(step.pressed, step.frames as usize))
+            .collect();
+    }
+
+    /*
+        Starts recording the player's input as a macro, or stops if already recording.
+
+        Returns how many frames were recorded when stopping, or None when starting.
+     */
+    pub fn toggle_recording(&self) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.recording.take() {
+            None => {
+                state.recording = Some(vec!());
+                None
+            }
+            Some(frames) => {
+                // The frames before anything was pressed are just waiting for the player to start
+                let start = frames.iter().position(|&pressed| pressed != 0).unwrap_or(frames.len());
+
+                state.recorded = frames[start..].to_vec();
+
+                Some(state.recorded.len())
+            }
+        }
+    }
+
+    pub fn play_recorded(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.playing = state.recorded.iter().copied().collect();
+    }
+
+    /*
+        The keys to hold for the next frame, called by the emulator once per frame
+     */
+    pub fn next_frame(&self) -> u8 {
+        let mut state = self.state.lock().unwrap();
+
+        let turbo = if (state.turbo_clock / state.turbo_frames).is_multiple_of(2) { state.turbo } else { 0 };
+        state.turbo_clock = state.turbo_clock.wrapping_add(1);
+
+        let player = state.held | turbo;
+
+        if let Some(frames) = &mut state.recording {
+            frames.push(player);
+        }
+
+        player | state.playing.pop_front().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(input: &Input, n: usize) -> Vec<u8> {
+        (0..n).map(|_| input.next_frame()).collect()
+    }
+
+    #[test]
+    fn turbo_presses_and_releases() {
+        // Pressed for 3 frames, then released for 3
+        let input = new_input(10);
+        let a = key_bit(Keys::A);
+
+        input.turbo_down(Keys::A);
+        assert_eq!(frames(&input, 8), vec!(a, a, a, 0, 0, 0, a, a));
+
+        // Another turbo button joins in step
+        input.turbo_down(Keys::B);
+        assert_eq!(frames(&input, 2), vec!(a | key_bit(Keys::B), 0));
+
+        // Starting again from nothing held starts with a press
+        input.turbo_up(Keys::A);
+        input.turbo_up(Keys::B);
+        input.turbo_down(Keys::A);
+        assert_eq!(frames(&input, 4), vec!(a, a, a, 0));
+    }
+
+    #[test]
+    fn turbo_rate_is_kept_in_range() {
+        // Faster than every other frame is as fast as it goes
+        for rate in [30, 1000] {
+            let input = new_input(rate);
+            input.turbo_down(Keys::A);
+
+            assert_eq!(frames(&input, 4), vec!(1, 0, 1, 0), "turbo rate {}", rate);
+        }
+
+        // 0 is taken as once a second
+        let input = new_input(0);
+        input.turbo_down(Keys::A);
+
+        assert_eq!(frames(&input, 31), [vec!(1; 30), vec!(0)].concat());
+    }
+
+    #[test]
+    fn plays_macros_over_the_player() {
+        let input = new_input(10);
+        let (a, b, start) = (key_bit(Keys::A), key_bit(Keys::B), key_bit(Keys::START));
+
+        input.key_down(Keys::START);
+        input.play_macro(&[MacroStep { pressed: a, frames: 2 }, MacroStep { pressed: b, frames: 1 }]);
+        assert_eq!(frames(&input, 1), vec!(start | a));
+
+        // A new macro replaces the one running
+        input.play_macro(&[MacroStep { pressed: b, frames: 1 }, MacroStep { pressed: 0, frames: 1 }, MacroStep { pressed: a, frames: 1 }]);
+        input.key_up(Keys::START);
+        assert_eq!(frames(&input, 4), vec!(b, 0, a, 0));
+    }
+
+    #[test]
+    fn records_from_the_first_press() {
+        let input = new_input(10);
+        let a = key_bit(Keys::A);
+
+        assert_eq!(input.toggle_recording(), None);
+        frames(&input, 3);
+
+        input.key_down(Keys::A);
+        frames(&input, 2);
+        input.key_up(Keys::A);
+        frames(&input, 1);
+
+        assert_eq!(input.toggle_recording(), Some(3));
+
+        // Stopped recording, so this isn't in the macro
+        input.key_down(Keys::B);
+        input.play_recorded();
+        assert_eq!(frames(&input, 4), vec!(a | key_bit(Keys::B), a | key_bit(Keys::B), key_bit(Keys::B), key_bit(Keys::B)));
+    }
+
+    #[test]
+    fn records_nothing_if_nothing_was_pressed() {
+        let input = new_input(10);
+
+        input.toggle_recording();
+        frames(&input, 5);
+
+        assert_eq!(input.toggle_recording(), Some(0));
+    }
+}
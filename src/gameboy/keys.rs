@@ -1,6 +1,9 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::RwLock;
 
-#[derive(Debug)]
+use crate::gameboy::state::{StateError, StateReader, StateWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Keys {
     A,
     B,
@@ -12,10 +15,27 @@ pub enum Keys {
     RIGHT,
 }
 
+// Following: https://gbdev.io/pandocs/Joypad_Input.html
+// Selects the buttons (A, B, Select, Start) when low
+const FLAG_SELECT_BUTTONS: u8 = 0x20;
+// Selects the directions when low
+const FLAG_SELECT_DIRECTIONS: u8 = 0x10;
+// Bits 6 & 7 aren't connected and always read high
+const UNUSED_BITS: u8 = 0xC0;
+
+/*
+    Keys are set once per frame by GameBoy::set_buttons, from whatever Input holds at the start of
+    the frame, and read by the MMU whenever the joypad register is.
+
+    To avoid deadlocks the locks are always taken in the same order, column then rows.
+ */
 pub struct KeyReg {
     column: RwLock<u8>,
 
     rows: RwLock<[u8; 2]>,
+
+    // Set when an input line goes from high to low, waiting to be raised as the joypad interrupt
+    interrupt: AtomicBool,
 }
 
 pub fn new_key_reg() -> KeyReg {
@@ -23,56 +43,105 @@ pub fn new_key_reg() -> KeyReg {
         column: RwLock::new(0),
 
         rows: RwLock::new([0x0F, 0x0F]),
+
+        interrupt: AtomicBool::new(false),
+    }
+}
+
+/*
+    Keys are passed around as one byte with a bit set for each key that is pressed. The low nibble
+    is the buttons and the high nibble the directions, in the same bit order as P1.
+ */
+pub fn key_bit(key: Keys) -> u8 {
+    match key {
+        Keys::A => 0x01,
+        Keys::B => 0x02,
+        Keys::SELECT => 0x04,
+        Keys::START => 0x08,
+        Keys::RIGHT => 0x10,
+        Keys::LEFT => 0x20,
+        Keys::UP => 0x40,
+        Keys::DOWN => 0x80,
     }
 }
 
+/*
+    The low nibble of P1. Each line is pulled low if a pressed key is in a selected group, so when
+    both groups are selected they are ANDed together.
+ */
+fn selected_keys(column: u8, rows: &[u8; 2]) -> u8 {
+    let mut keys = 0x0F;
+
+    if column & FLAG_SELECT_BUTTONS == 0 {
+        keys &= rows[0];
+    }
+
+    if column & FLAG_SELECT_DIRECTIONS == 0 {
+        keys &= rows[1];
+    }
+
+    keys
+}
+
 impl KeyReg {
     pub fn set_column(&self, val: u8) {
         let mut column = self.column.write().unwrap();
+        let rows = self.rows.read().unwrap();
+
+        let before = selected_keys(*column, &rows);
 
-        *column = val & 0x30;
+        *column = val & (FLAG_SELECT_BUTTONS | FLAG_SELECT_DIRECTIONS);
+
+        // Selecting a group with a key held down also pulls a line low
+        self.check_interrupt(before, selected_keys(*column, &rows));
     }
 
     pub fn get_keys(&self) -> u8 {
         let column = self.column.read().unwrap();
         let rows = self.rows.read().unwrap();
 
-        // TODO: Is there a risk of a deadlock here?
+        UNUSED_BITS | *column | selected_keys(*column, &rows)
+    }
+
+    /*
+        Returns true once for each time an input line has gone from high to low
+     */
+    pub fn take_interrupt(&self) -> bool {
+        self.interrupt.swap(false, Ordering::SeqCst)
+    }
 
-        match *column {
-            0x10 => rows[0],
-            0x20 => rows[1],
-            _ => 0,
+    fn check_interrupt(&self, before: u8, after: u8) {
+        if before & !after > 0 {
+            self.interrupt.store(true, Ordering::SeqCst);
         }
     }
 
-    pub fn key_down(&self, key: Keys) {
-        let mut rows = self.rows.write().unwrap();
+    /*
+        The keys themselves aren't saved, they are set from the player's input every frame
+     */
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(*self.column.read().unwrap());
+        w.write_bool(self.interrupt.load(Ordering::SeqCst));
+    }
 
-        match key {
-            Keys::A => rows[0] &= 0xE,
-            Keys::B => rows[0] &= 0xD,
-            Keys::START => rows[0] &= 0x7,
-            Keys::SELECT => rows[0] &= 0xB,
-            Keys::UP => rows[1] &= 0xB,
-            Keys::DOWN => rows[1] &= 0x7,
-            Keys::LEFT => rows[1] &= 0xD,
-            Keys::RIGHT => rows[1] &= 0xE,
-        }
+    pub(crate) fn load_state(&self, r: &mut StateReader) -> Result<(), StateError> {
+        *self.column.write().unwrap() = r.read_u8()? & (FLAG_SELECT_BUTTONS | FLAG_SELECT_DIRECTIONS);
+        self.interrupt.store(r.read_bool()?, Ordering::SeqCst);
+
+        Ok(())
     }
 
-    pub fn key_up(&self, key: Keys) {
+    /*
+        Replaces every key at once. pressed holds one bit per key, 1 when pressed, see key_bit.
+     */
+    pub fn set_pressed(&self, pressed: u8) {
+        let column = self.column.read().unwrap();
         let mut rows = self.rows.write().unwrap();
 
-        match key {
-            Keys::A => rows[0] |= 0x1,
-            Keys::B => rows[0] |= 0x2,
-            Keys::START => rows[0] |= 0x8,
-            Keys::SELECT => rows[0] |= 0x4,
-            Keys::UP => rows[1] |= 0x4,
-            Keys::DOWN => rows[1] |= 0x8,
-            Keys::LEFT => rows[1] |= 0x2,
-            Keys::RIGHT => rows[1] |= 0x1,
-        }
+        let before = selected_keys(*column, &rows);
+
+        *rows = [!pressed & 0x0F, (!pressed >> 4) & 0x0F];
+
+        self.check_interrupt(before, selected_keys(*column, &rows));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,301 @@
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::gameboy::serial::LinkPartner;
+
+/*
+    Link cables, each end is a LinkPartner plugged into a GB's serial port.
+ */
+
+/*
+    A link cable between two emulators over TCP.
+
+    Every message is three bytes, a kind, a sequence number and a data byte:
+        - MSG_TRANSFER: sent by the side driving the clock, carrying its outgoing byte
+        - MSG_REPLY: sent back by the other side with the same sequence number, carrying the byte
+          that was in its SB
+
+    The side driving the clock blocks until the reply arrives. This keeps the two emulators in step
+    at every transfer, which is what games synchronise on. A reply that arrives after its transfer
+    timed out has an old sequence number, so it's dropped rather than taken as the next reply.
+ */
+const MSG_TRANSFER: u8 = 0x01;
+const MSG_REPLY: u8 = 0x02;
+
+const MSG_LEN: usize = 3;
+
+// How long to wait for the partner to reply before acting as though the cable was pulled
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct TcpLink {
+    stream: TcpStream,
+
+    // Bytes received but not yet forming a complete message
+    pending: Vec<u8>,
+
+    // The sequence number of the last transfer this side sent
+    seq: u8,
+
+    // Set once the partner has gone away, after which this behaves as an unplugged cable
+    disconnected: bool,
+}
+
+/*
+    Waits for the partner emulator to connect
+ */
+pub fn new_tcp_link_listen(addr: &str) -> Result<TcpLink, io::Error> {
+    let listener = TcpListener::bind(addr)?;
+
+    let (stream, _) = listener.accept()?;
+
+    new_tcp_link(stream)
+}
+
+/*
+    Connects to a partner emulator that is listening
+ */
+pub fn new_tcp_link_connect(addr: &str) -> Result<TcpLink, io::Error> {
+    let stream = TcpStream::connect(addr)?;
+
+    new_tcp_link(stream)
+}
+
+fn new_tcp_link(stream: TcpStream) -> Result<TcpLink, io::Error> {
+    // Messages are tiny and latency sensitive
+    stream.set_nodelay(true)?;
+    stream.set_nonblocking(true)?;
+
+    Ok(TcpLink {
+        stream,
+        pending: vec!(),
+        seq: 0,
+        disconnected: false,
+    })
+}
+
+impl TcpLink {
+    /*
+        Returns the next complete message if one has arrived. Will block up to the read timeout if
+        the stream is in blocking mode.
+     */
+    fn read_message(&mut self) -> Option<[u8; MSG_LEN]> {
+        while self.pending.len() < MSG_LEN {
+            let mut buf = [0u8; 64];
+
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    self.disconnect("partner closed the connection");
+                    return None;
+                }
+                Ok(n) => self.pending.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => return None,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.disconnect(&e.to_string());
+                    return None;
+                }
+            }
+        }
+
+        let msg = [self.pending[0], self.pending[1], self.pending[2]];
+        self.pending.drain(0..MSG_LEN);
+
+        Some(msg)
+    }
+
+    fn send_message(&mut self, kind: u8, seq: u8, data: u8) {
+        if let Err(e) = self.stream.write_all(&[kind, seq, data]) {
+            self.disconnect(&e.to_string());
+        }
+    }
+
+    fn disconnect(&mut self, reason: &str) {
+        if !self.disconnected {
+            eprintln!("link cable disconnected: {}", reason);
+        }
+
+        self.disconnected = true;
+    }
+
+    fn wait_for_reply(&mut self, out: u8) -> Option<u8> {
+        self.stream.set_nonblocking(false).ok()?;
+        self.stream.set_read_timeout(Some(REPLY_TIMEOUT)).ok()?;
+
+        let mut reply = None;
+
+        while !self.disconnected {
+            match self.read_message() {
+                None => break, // Timed out
+                Some([MSG_REPLY, seq, data]) if seq == self.seq => {
+                    reply = Some(data);
+                    break;
+                }
+                Some([MSG_TRANSFER, seq, _]) => {
+                    // Both sides are driving the clock, answer the partner so it isn't left waiting
+                    self.send_message(MSG_REPLY, seq, out);
+                }
+                Some(_) => {} // A stale reply, or an unknown message
+            }
+        }
+
+        self.stream.set_nonblocking(true).ok()?;
+
+        reply
+    }
+}
+
+impl LinkPartner for TcpLink {
+    fn exchange(&mut self, out: u8) -> u8 {
+        if self.disconnected {
+            return 0xFF;
+        }
+
+        self.seq = self.seq.wrapping_add(1);
+        self.send_message(MSG_TRANSFER, self.seq, out);
+
+        self.wait_for_reply(out).unwrap_or(0xFF)
+    }
+
+    fn poll(&mut self, out: u8) -> Option<u8> {
+        while !self.disconnected {
+            // Anything else is a stale reply to a transfer that already timed out
+            if let [MSG_TRANSFER, seq, data] = self.read_message()? {
+                self.send_message(MSG_REPLY, seq, out);
+                return Some(data);
+            }
+        }
+
+        None
+    }
+}
+
+/*
+    A link cable between two GBs running in the same process.
+
+    Both ends share the state of the cable. Each end publishes its SB as soon as it changes, so that
+    when the other end drives the clock it can swap bytes straight away. The byte it sent is held
+    until the receiving end next polls.
+
+    This is only deterministic if both GBs are stepped from the same thread, see linked::LinkedGameBoys.
+ */
+struct CableState {
+    // The last SB published by each end
+    data: [u8; 2],
+
+    // A byte clocked into each end that it hasn't picked up yet
+    incoming: [Option<u8>; 2],
+}
+
+pub struct CablePort {
+    // Which end of the cable this is, 0 or 1
+    end: usize,
+
+    cable: Arc<Mutex<CableState>>,
+}
+
+pub fn new_virtual_cable() -> (CablePort, CablePort) {
+    let cable = Arc::new(Mutex::new(CableState {
+        data: [0xFF, 0xFF],
+        incoming: [None, None],
+    }));
+
+    (
+        CablePort { end: 0, cable: cable.clone() },
+        CablePort { end: 1, cable },
+    )
+}
+
+impl LinkPartner for CablePort {
+    fn exchange(&mut self, out: u8) -> u8 {
+        let mut cable = self.cable.lock().unwrap();
+        let other = 1 - self.end;
+
+        cable.incoming[other] = Some(out);
+
+        cable.data[other]
+    }
+
+    fn poll(&mut self, out: u8) -> Option<u8> {
+        let mut cable = self.cable.lock().unwrap();
+
+        cable.data[self.end] = out;
+
+        cable.incoming[self.end].take()
+    }
+
+    fn publish(&mut self, out: u8) {
+        self.cable.lock().unwrap().data[self.end] = out;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::*;
+
+    /*
+        Both ends of a cable over loopback
+     */
+    fn loopback() -> (TcpLink, TcpLink) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (b, _) = listener.accept().unwrap();
+
+        (new_tcp_link(a).unwrap(), new_tcp_link(b).unwrap())
+    }
+
+    /*
+        Polls until the partner clocks a byte, as a GB on the external clock would
+     */
+    fn poll_until_clocked(mut link: TcpLink, out: u8) -> (TcpLink, u8) {
+        loop {
+            if let Some(received) = link.poll(out) {
+                return (link, received);
+            }
+
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn exchanges_bytes_both_ways() {
+        let (mut a, b) = loopback();
+
+        let polling = thread::spawn(move || poll_until_clocked(b, 0x5A));
+        assert_eq!(a.exchange(0xA5), 0x5A);
+
+        let (mut b, received) = polling.join().unwrap();
+        assert_eq!(received, 0xA5);
+
+        let polling = thread::spawn(move || poll_until_clocked(a, 0x12));
+        assert_eq!(b.exchange(0x34), 0x12);
+        assert_eq!(polling.join().unwrap().1, 0x34);
+    }
+
+    #[test]
+    fn ignores_stale_replies() {
+        let (mut a, mut b) = loopback();
+
+        // As if replying late to a transfer before this one that timed out
+        b.send_message(MSG_REPLY, a.seq, 0xEE);
+
+        let polling = thread::spawn(move || poll_until_clocked(b, 0x77));
+        assert_eq!(a.exchange(0x01), 0x77);
+        assert_eq!(polling.join().unwrap().1, 0x01);
+    }
+
+    #[test]
+    fn unplugged_once_the_partner_goes() {
+        let (mut a, b) = loopback();
+
+        drop(b);
+
+        assert_eq!(a.exchange(0x01), 0xFF);
+        assert!(a.disconnected);
+    }
+}
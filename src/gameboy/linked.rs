@@ -0,0 +1,211 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::gameboy::{CYCLES_PER_FRAME, FRAME_TIME, GameBoy, GameBoyConfig, new_game_boy, send_frame, wait_for_frame_end};
+use crate::gameboy::frame::FrameSink;
+use crate::gameboy::cartridge::Cartridge;
+use crate::gameboy::input::Input;
+use crate::gameboy::link::new_virtual_cable;
+
+pub const SCREEN_WIDTH: usize = 160 * 2;
+pub const SCREEN_HEIGHT: usize = 144;
+
+/*
+    Two GBs in one process connected by a virtual link cable.
+
+    Both are stepped from the same thread, always stepping whichever is behind, so that neither
+    gets more than one instruction ahead of the other. Given the same inputs two runs will always
+    behave the same, which makes this useful for testing multiplayer.
+ */
+pub struct LinkedGameBoys {
+    game_boys: [GameBoy; 2],
+
+    // Handed to the GBs at the start of each frame
+    inputs: [Arc<Input>; 2],
+
+    // t cycles run by each GB since the start of the frame
+    clocks: [u32; 2],
+
+    // Both screens side by side, 3 bytes per pixel (RGB)
+    screen: Vec<u8>,
+}
+
+/*
+    Anything already plugged into the configs' link ports is replaced by the cable between them
+ */
+pub fn new_linked_game_boys(carts: [Cartridge; 2], configs: [GameBoyConfig; 2], inputs: [Arc<Input>; 2]) -> LinkedGameBoys {
+    let (port_a, port_b) = new_virtual_cable();
+    let [cart_a, cart_b] = carts;
+    let [config_a, config_b] = configs;
+
+    LinkedGameBoys {
+        game_boys: [
+            new_game_boy(cart_a, GameBoyConfig { link: Some(Box::new(port_a)), ..config_a }),
+            new_game_boy(cart_b, GameBoyConfig { link: Some(Box::new(port_b)), ..config_b }),
+        ],
+        inputs,
+        clocks: [0, 0],
+        screen: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+    }
+}
+
+impl LinkedGameBoys {
+    /*
+        The joypad for player 0 or 1, this is how tests drive the inputs
+     */
+    pub fn input(&self, player: usize) -> &Arc<Input> {
+        &self.inputs[player]
+    }
+
+    /*
+        Runs both GBs for one frame worth of cycles
+     */
+    pub fn run_frame(&mut self) {
+        for (game_boy, input) in self.game_boys.iter_mut().zip(&self.inputs) {
+            game_boy.set_buttons(input.next_frame());
+        }
+
+        while self.clocks[0] < CYCLES_PER_FRAME || self.clocks[1] < CYCLES_PER_FRAME {
+            let player = if self.clocks[0] <= self.clocks[1] { 0 } else { 1 };
+
+            self.clocks[player] += self.game_boys[player].step_instruction();
+
+            if let Some(frame) = self.game_boys[player].take_frame() {
+                copy_frame(&mut self.screen, frame, player);
+            }
+        }
+
+        // Carry any overshoot into the next frame so the two stay in step
+        self.clocks[0] -= CYCLES_PER_FRAME;
+        self.clocks[1] -= CYCLES_PER_FRAME;
+    }
+
+    /*
+        The last completed frame of both GBs side by side, player 0 on the left
+     */
+    pub fn screen(&self) -> &[u8] {
+        &self.screen
+    }
+
+    /*
+        Writes both screens as they are now to a PNG, side by side like screen
+     */
+    pub fn write_screenshot(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.screen)?;
+
+        Ok(())
+    }
+}
+
+fn copy_frame(screen: &mut [u8], frame: &[u8], player: usize) {
+    let row_len = 160 * 3;
+
+    for y in 0..SCREEN_HEIGHT {
+        let dst = (y * SCREEN_WIDTH * 3) + (player * row_len);
+
+        screen[dst..dst + row_len].copy_from_slice(&frame[y * row_len..(y + 1) * row_len]);
+    }
+}
+
+/*
+    Runs forever, or returns once `frames` have run after saving the screens to `screenshot`
+ */
+pub fn start_linked_game_boys(carts: [Cartridge; 2], configs: [GameBoyConfig; 2], mut sinks: Vec<Box<dyn FrameSink>>, inputs: [Arc<Input>; 2], frames: Option<u64>, screenshot: Option<String>) {
+    let mut linked = new_linked_game_boys(carts, configs, inputs);
+    let mut run = 0;
+
+    while frames.is_none_or(|frames| run < frames) {
+        let start = SystemTime::now();
+
+        linked.run_frame();
+        run += 1;
+
+        send_frame(&mut sinks, linked.screen());
+
+        wait_for_frame_end(start, FRAME_TIME);
+    }
+
+    if let Some(path) = screenshot {
+        match linked.write_screenshot(&path) {
+            Ok(()) => println!("Saved screenshot to {}", path),
+            Err(e) => eprintln!("unable to save screenshot to {}: {}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::input::new_input;
+    use crate::gameboy::new_test_game_boy;
+
+    // Waits for the transfer to finish, keeps the byte received at C000 and spins
+    const STORE_RECEIVED: [u8; 13] = [
+        0xF0, 0x02, // ldh a, (SC)
+        0xCB, 0x7F, // bit 7, a
+        0x20, 0xFA, // jr nz, -6
+        0xF0, 0x01, // ldh a, (SB)
+        0xEA, 0x00, 0xC0, // ld ($C000), a
+        0x18, 0xFE, // jr -2
+    ];
+
+    fn linked(codes: [Vec<u8>; 2]) -> LinkedGameBoys {
+        let (port_a, port_b) = new_virtual_cable();
+        let [code_a, code_b] = codes;
+
+        LinkedGameBoys {
+            game_boys: [new_test_game_boy(&code_a, Some(Box::new(port_a))), new_test_game_boy(&code_b, Some(Box::new(port_b)))],
+            inputs: [Arc::new(new_input(0)), Arc::new(new_input(0))],
+            clocks: [0, 0],
+            screen: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
+        }
+    }
+
+    /*
+        Player 0 waits `delay` loops, then loads SB and waits on the external clock. Player 1 starts
+        clocking almost straight away, so its transfer ends around the time player 0 is ready. From
+        delay 32 SB is written after player 0's last poll before the transfer ends, from 49 it's
+        written after the transfer.
+     */
+    fn transfer_after(delay: u8) -> [u8; 2] {
+        let mut a = vec![
+            0x06, 0xE0, // ld b, $E0
+            0x05, 0x20, 0xFD, // dec b, jr nz, -3
+            0x06, delay, // ld b, delay
+            0x05, 0x20, 0xFD, // dec b, jr nz, -3
+            0x3E, 0x42, 0xE0, 0x01, // ld a, $42, ldh (SB), a
+            0x3E, 0x80, 0xE0, 0x02, // ld a, $80, ldh (SC), a
+        ];
+        a.extend(STORE_RECEIVED);
+
+        let mut b = vec![
+            0x06, 0x10, // ld b, $10
+            0x05, 0x20, 0xFD, // dec b, jr nz, -3
+            0x3E, 0x99, 0xE0, 0x01, // ld a, $99, ldh (SB), a
+            0x3E, 0x81, 0xE0, 0x02, // ld a, $81, ldh (SC), a
+        ];
+        b.extend(STORE_RECEIVED);
+
+        let mut linked = linked([a, b]);
+        linked.run_frame();
+
+        [linked.game_boys[0].read_byte(0xC000), linked.game_boys[1].read_byte(0xC000)]
+    }
+
+    #[test]
+    fn sends_sb_written_just_before_the_transfer() {
+        for delay in 24..=48 {
+            assert_eq!(transfer_after(delay), [0x99, 0x42], "delay {}", delay);
+        }
+
+        // Too late, the transfer took SB as it was
+        assert_eq!(transfer_after(49), [0x99, 0x00]);
+    }
+}
@@ -1,9 +1,14 @@
-use std::fs;
+use std::cell::RefCell;
 use std::sync::Arc;
+use crate::gameboy::boot::{Model, post_boot_io};
 use crate::gameboy::cartridge::Cartridge;
+use crate::gameboy::cpu;
+use crate::gameboy::debug::DebugConfig;
 use crate::gameboy::keys::KeyReg;
+use crate::gameboy::state::{StateError, StateReader, StateWriter};
+use crate::gameboy::watch::{Access, Watch};
 
-pub const DEBUG_GB_DOCTOR: bool = false;
+const REG_BOOT_ROM_DISABLE: u16 = 0xFF50;
 
 pub struct MMU {
     // Following: http://imrannazar.com/GameBoy-Emulation-in-JavaScript:-Memory
@@ -12,11 +17,11 @@ pub struct MMU {
         A flag indicating whether the bios is mapped in.
 
         When the GB starts up the bios is available in memory region 0x0000-0x00FF. Once the
-        bios has been run this region is mapped to the cartridge.
+        bios has been run it writes to 0xFF50 and this region is mapped to the cartridge.
      */
     pub in_bios: bool,
 
-    bios: [u8; (0x00FF - 0x0000) + 1], //using this notation to mean addresses 0x0000 -> 0x00FF
+    bios: Vec<u8>, // 0x0000 -> 0x00FF, or 0x0000 -> 0x08FF for the CGB
 
     rom_bank0: [u8; (0x3FFF - 0x0000) + 1],
     // Bank 0 of the cartridge, this is always available
@@ -40,29 +45,19 @@ pub struct MMU {
     cart: Cartridge,
 
     key_reg: Arc<KeyReg>,
+
+    // LY always reads 0x90, for Gameboy Doctor
+    stub_ly: bool,
+
+    // Watchpoints and hooks on memory accesses, only looked at while watching is set
+    pub(crate) watch: RefCell<Watch>,
+    pub(crate) watching: bool,
 }
 
-pub fn new_mmu(cart: Cartridge, key_reg: Arc<KeyReg>) -> MMU {
+pub fn new_mmu(cart: Cartridge, key_reg: Arc<KeyReg>, debug: &DebugConfig) -> MMU {
     MMU {
-        in_bios: !DEBUG_GB_DOCTOR,
-        bios: [ // From: http://imrannazar.com/content/files/jsgb.mmu.js
-            0x31, 0xFE, 0xFF, 0xAF, 0x21, 0xFF, 0x9F, 0x32, 0xCB, 0x7C, 0x20, 0xFB, 0x21, 0x26, 0xFF, 0x0E,
-            0x11, 0x3E, 0x80, 0x32, 0xE2, 0x0C, 0x3E, 0xF3, 0xE2, 0x32, 0x3E, 0x77, 0x77, 0x3E, 0xFC, 0xE0,
-            0x47, 0x11, 0x04, 0x01, 0x21, 0x10, 0x80, 0x1A, 0xCD, 0x95, 0x00, 0xCD, 0x96, 0x00, 0x13, 0x7B,
-            0xFE, 0x34, 0x20, 0xF3, 0x11, 0xD8, 0x00, 0x06, 0x08, 0x1A, 0x13, 0x22, 0x23, 0x05, 0x20, 0xF9,
-            0x3E, 0x19, 0xEA, 0x10, 0x99, 0x21, 0x2F, 0x99, 0x0E, 0x0C, 0x3D, 0x28, 0x08, 0x32, 0x0D, 0x20,
-            0xF9, 0x2E, 0x0F, 0x18, 0xF3, 0x67, 0x3E, 0x64, 0x57, 0xE0, 0x42, 0x3E, 0x91, 0xE0, 0x40, 0x04,
-            0x1E, 0x02, 0x0E, 0x0C, 0xF0, 0x44, 0xFE, 0x90, 0x20, 0xFA, 0x0D, 0x20, 0xF7, 0x1D, 0x20, 0xF2,
-            0x0E, 0x13, 0x24, 0x7C, 0x1E, 0x83, 0xFE, 0x62, 0x28, 0x06, 0x1E, 0xC1, 0xFE, 0x64, 0x20, 0x06,
-            0x7B, 0xE2, 0x0C, 0x3E, 0x87, 0xF2, 0xF0, 0x42, 0x90, 0xE0, 0x42, 0x15, 0x20, 0xD2, 0x05, 0x20,
-            0x4F, 0x16, 0x20, 0x18, 0xCB, 0x4F, 0x06, 0x04, 0xC5, 0xCB, 0x11, 0x17, 0xC1, 0xCB, 0x11, 0x17,
-            0x05, 0x20, 0xF5, 0x22, 0x23, 0x22, 0x23, 0xC9, 0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B,
-            0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
-            0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC,
-            0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E, 0x3c, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x4C,
-            0x21, 0x04, 0x01, 0x11, 0xA8, 0x00, 0x1A, 0x13, 0xBE, 0x20, 0xFE, 0x23, 0x7D, 0xFE, 0x34, 0x20,
-            0xF5, 0x06, 0x19, 0x78, 0x86, 0x23, 0x05, 0x20, 0xFB, 0x86, 0x20, 0xFE, 0x3E, 0x01, 0xE0, 0x50
-        ],
+        in_bios: true,
+        bios: vec!(),
         rom_bank0: cart.read_bank_0(),
         rom_bankx: cart.read_bank_n(),
         v_ram: [0; 8192],
@@ -73,10 +68,77 @@ pub fn new_mmu(cart: Cartridge, key_reg: Arc<KeyReg>) -> MMU {
         z_ram: [0; 128],
         cart,
         key_reg,
+        stub_ly: debug.stub_ly,
+        watch: RefCell::default(),
+        watching: false,
     }
 }
 
 impl MMU {
+    pub fn cart(&self) -> &Cartridge {
+        &self.cart
+    }
+
+    /*
+        Clears all memory, as if the GB had been switched off and on again
+     */
+    pub(crate) fn reset(&mut self) {
+        let bios = std::mem::take(&mut self.bios);
+        let watch = self.watch.take();
+
+        let debug = DebugConfig { stub_ly: self.stub_ly, ..Default::default() };
+
+        *self = new_mmu(self.cart.clone(), self.key_reg.clone(), &debug);
+        self.bios = bios;
+        self.watch = RefCell::new(watch);
+    }
+
+    /*
+        Maps in a boot ROM to run from the start
+     */
+    pub(crate) fn set_bios(&mut self, bios: Vec<u8>) {
+        self.bios = bios;
+        self.in_bios = true;
+    }
+
+    /*
+        Leaves the IO registers as the model's boot ROM would have, with the boot ROM unmapped
+     */
+    pub(crate) fn skip_boot(&mut self, model: Model) {
+        self.in_bios = false;
+
+        for (addr, val) in post_boot_io(model) {
+            self.mm_io[addr as usize - 0xFF00] = val;
+        }
+    }
+
+    /*
+        The ROM comes from the cartridge and the bios is chosen at startup, so neither is saved
+     */
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.in_bios);
+        w.write_bytes(&self.v_ram);
+        w.write_bytes(&self.e_ram);
+        w.write_bytes(&self.w_ram);
+        w.write_bytes(&self.s_info);
+        w.write_bytes(&self.mm_io);
+        w.write_bytes(&self.z_ram);
+
+        self.key_reg.save_state(w);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.in_bios = r.read_bool()?;
+        r.read_bytes(&mut self.v_ram)?;
+        r.read_bytes(&mut self.e_ram)?;
+        r.read_bytes(&mut self.w_ram)?;
+        r.read_bytes(&mut self.s_info)?;
+        r.read_bytes(&mut self.mm_io)?;
+        r.read_bytes(&mut self.z_ram)?;
+
+        self.key_reg.load_state(r)
+    }
+
     /*
         #############
         Memory Access
@@ -86,13 +148,30 @@ impl MMU {
     /*
         Read byte
      */
-    pub fn rb(&mut self, addr: u16) -> u8 {
+    pub fn rb(&self, addr: u16) -> u8 {
+        let val = self.read(addr);
+
+        if self.watching {
+            self.watch.borrow_mut().access(Access::Read, addr, val);
+        }
+
+        val
+    }
+
+    /*
+        For the CPU's own bookkeeping that isn't part of an instruction, e.g. fetching opcodes or
+        polling for interrupts, which watchpoints shouldn't see
+     */
+    pub(crate) fn rb_unwatched(&self, addr: u16) -> u8 {
+        self.read(addr)
+    }
+
+    fn read(&self, addr: u16) -> u8 {
         match addr & 0xF000 {
             0x0000 => {
-                if self.in_bios {
-                    if addr < 0x0100 {
-                        return self.bios[addr as usize];
-                    }
+                // The CGB's boot ROM is larger, leaving a gap for the cartridge header at 0x0100
+                if self.in_bios && !(0x0100..0x0200).contains(&addr) && (addr as usize) < self.bios.len() {
+                    return self.bios[addr as usize];
                 }
 
                 self.rom_bank0[addr as usize]
@@ -129,11 +208,11 @@ impl MMU {
                     }
                     0x0F00 => {
                         if addr < 0xFF80 {
-                            if addr == 0xFF00 {
+                            if addr == cpu::REG_JOYPAD {
                                 return self.key_reg.get_keys()
                             }
 
-                            if DEBUG_GB_DOCTOR && addr == 0xFF44 {
+                            if self.stub_ly && addr == 0xFF44 {
                                 return 0x90; // GB Doctor setup indicates this should be hardcoded to make it easier to test
                             }
 
@@ -149,10 +228,21 @@ impl MMU {
         }
     }
 
+    /*
+        The ROM bank mapped at addr, 0 outside the switchable bank as in RGBDS symbol files. There
+        are no MBCs yet, so the switchable bank is always bank 1.
+     */
+    pub(crate) fn rom_bank(&self, addr: u16) -> u16 {
+        match addr {
+            0x4000..=0x7FFF => 1,
+            _ => 0,
+        }
+    }
+
     /*
         Read word
      */
-    pub fn rw(&mut self, addr: u16) -> u16 {
+    pub fn rw(&self, addr: u16) -> u16 {
         self.rb(addr) as u16 + ((self.rb(addr+1) as u16) << 8)
     }
 
@@ -160,6 +250,21 @@ impl MMU {
         Write byte
      */
     pub fn wb(&mut self, addr: u16, val: u8) {
+        if self.watching {
+            self.watch.get_mut().access(Access::Write, addr, val);
+        }
+
+        self.write(addr, val);
+    }
+
+    /*
+        See rb_unwatched
+     */
+    pub(crate) fn wb_unwatched(&mut self, addr: u16, val: u8) {
+        self.write(addr, val);
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
         match addr & 0xF000 {
             0x0000 => {
                 // All ROM
@@ -199,11 +304,16 @@ impl MMU {
                     }
                     0x0F00 => {
                         if addr < 0xFF80 {
-                            if addr == 0xFF00 {
+                            if addr == cpu::REG_JOYPAD {
                                 self.key_reg.set_column(val);
                                 return;
                             }
 
+                            // Writing anything but 0 unmaps the boot ROM, it can't be mapped back in
+                            if addr == REG_BOOT_ROM_DISABLE && val != 0 {
+                                self.in_bios = false;
+                            }
+
                             self.mm_io[addr as usize - 0xFF00] = val;
 
                             return;
@@ -225,4 +335,38 @@ impl MMU {
         self.wb(addr, val as u8);
         self.wb(addr + 1, (val >> 8) as u8)
     }
-}
\ No newline at end of file
+
+    /*
+        Keys are pressed on another thread, so any joypad interrupt they caused is raised here
+     */
+    pub fn check_joypad_interrupt(&mut self) {
+        if self.key_reg.take_interrupt() {
+            let i_f = self.rb(cpu::REG_INTERRUPTS);
+            self.wb(cpu::REG_INTERRUPTS, i_f | cpu::FLAG_INT_JOYP);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::boot::Boot;
+    use crate::gameboy::cartridge::new_test_cartridge;
+    use crate::gameboy::{new_game_boy, GameBoyConfig};
+
+    #[test]
+    fn boot_rom_is_mapped_until_a_non_zero_write() {
+        // The size of the CGB's, which leaves the cartridge header showing through
+        let config = GameBoyConfig { boot: Some(Boot::Rom(vec![0xAA; 0x900])), ..Default::default() };
+        let mut gb = new_game_boy(new_test_cartridge(&[0x11]), config);
+
+        assert_eq!([gb.read_byte(0x0000), gb.read_byte(0x0100), gb.read_byte(0x0200)], [0xAA, 0x11, 0xAA]);
+
+        gb.write_byte(REG_BOOT_ROM_DISABLE, 0x00);
+        assert_eq!(gb.read_byte(0x0000), 0xAA);
+
+        gb.write_byte(REG_BOOT_ROM_DISABLE, 0x01);
+        gb.write_byte(REG_BOOT_ROM_DISABLE, 0x00);
+        assert_eq!([gb.read_byte(0x0000), gb.read_byte(0x0200)], [0x00, 0x00]);
+    }
+}
@@ -0,0 +1,367 @@
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fmt, fs, io};
+
+use crate::gameboy::boot::{builtin_boot, Boot, Model, MODELS};
+use crate::gameboy::cartridge::Cartridge;
+use crate::gameboy::GameBoyConfig;
+
+/*
+    Input movies, a recording of the joypad on every frame that can be played back to reproduce a
+    run exactly.
+
+    Inputs are only ever handed to the GB at the start of a frame, both while recording and playing
+    back, so the same inputs always land on the same cycle. The file is laid out as:
+        "RGBMOVIE" | format version | emulator version (length, then UTF-8) | ROM CRC32 (LE)
+        | boot (0x00 then the boot ROM's CRC32 (LE), or 0x01 then the model skipped to)
+        | debug flags (bit 0 skip boot, bit 1 stub LY) | start time (LE unix seconds) | start marker
+        | one byte per frame
+
+    Each frame's byte holds the keys pressed on that frame, see keys::key_bit.
+
+    The start time is the only outside input a run could depend on. Nothing reads the time yet, but
+    once the cartridge RTC is emulated it must take its time from here when playing back.
+
+    Only movies starting from power on are supported for now, the start marker leaves room for
+    movies starting from a save state.
+ */
+
+const MAGIC: &[u8; 8] = b"RGBMOVIE";
+
+const FORMAT_VERSION: u8 = 2;
+
+const BOOT_ROM: u8 = 0x00;
+const BOOT_SKIP: u8 = 0x01;
+
+const DEBUG_SKIP_BOOT: u8 = 0x01;
+const DEBUG_STUB_LY: u8 = 0x02;
+
+const START_POWER_ON: u8 = 0x00;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(String, io::Error),
+    NotAMovie(String),
+    UnsupportedVersion(String, u8),
+    UnsupportedStart(String, u8),
+    WrongRom(String, u32, u32),
+    WrongSetup(String, Setup, Setup),
+}
+
+impl Display for MovieError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Io(path, e) => write!(f, "unable to open movie {}: {}", path, e),
+            MovieError::NotAMovie(path) => write!(f, "{} isn't a movie file", path),
+            MovieError::UnsupportedVersion(path, version) => write!(f, "movie {} uses format version {}, only version {} is supported", path, version, FORMAT_VERSION),
+            MovieError::UnsupportedStart(path, start) => write!(f, "movie {} starts from an unsupported state ({:#04x}), only power on is supported", path, start),
+            MovieError::WrongRom(path, expected, found) => write!(f, "movie {} was recorded with a ROM with CRC32 {:08x}, but this ROM is {:08x}", path, expected, found),
+            MovieError::WrongSetup(path, expected, found) => write!(f, "movie {} was recorded with {}, but this GB has {}", path, expected, found),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+/*
+    How the GB was switched on, besides the ROM. A movie played back on a GB set up differently
+    would play out differently.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Setup {
+    boot: SetupBoot,
+
+    skip_boot: bool,
+    stub_ly: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SetupBoot {
+    // The CRC32 of the boot ROM run
+    Rom(u32),
+
+    // The model whose boot was skipped
+    Skip(Model),
+}
+
+fn setup(config: &GameBoyConfig) -> Setup {
+    let boot = match config.debug.boot(config.boot.clone().unwrap_or_else(builtin_boot)) {
+        Boot::Rom(rom) => SetupBoot::Rom(crc32fast::hash(&rom)),
+        Boot::Skip(model) => SetupBoot::Skip(model),
+    };
+
+    Setup { boot, skip_boot: config.debug.skip_boot, stub_ly: config.debug.stub_ly }
+}
+
+impl Display for Setup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.boot {
+            SetupBoot::Rom(crc) => write!(f, "a boot ROM with CRC32 {:08x}", crc)?,
+            SetupBoot::Skip(model) => write!(f, "the {} boot skipped", model)?,
+        }
+
+        write!(f, ", skip boot {}, stub LY {}", on_off(self.skip_boot), on_off(self.stub_ly))
+    }
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
+
+pub struct MovieRecorder {
+    file: BufWriter<File>,
+}
+
+pub fn new_movie_recorder(path: &str, cart: &Cartridge, config: &GameBoyConfig) -> Result<MovieRecorder, MovieError> {
+    let err = |e| MovieError::Io(path.to_string(), e);
+
+    let mut file = BufWriter::new(File::create(path).map_err(err)?);
+
+    let version = env!("CARGO_PKG_VERSION").as_bytes();
+    let start_time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    let mut header = vec!();
+    header.extend_from_slice(MAGIC);
+    header.push(FORMAT_VERSION);
+    header.push(version.len() as u8);
+    header.extend_from_slice(version);
+    header.extend_from_slice(&cart.crc32().to_le_bytes());
+
+    let setup = setup(config);
+
+    match setup.boot {
+        SetupBoot::Rom(crc) => {
+            header.push(BOOT_ROM);
+            header.extend_from_slice(&crc.to_le_bytes());
+        }
+        SetupBoot::Skip(model) => {
+            header.push(BOOT_SKIP);
+            header.push(MODELS.iter().position(|(_, m)| *m == model).unwrap() as u8);
+        }
+    }
+
+    header.push(if setup.skip_boot { DEBUG_SKIP_BOOT } else { 0 } | if setup.stub_ly { DEBUG_STUB_LY } else { 0 });
+    header.extend_from_slice(&start_time.to_le_bytes());
+    header.push(START_POWER_ON);
+
+    file.write_all(&header).map_err(err)?;
+    file.flush().map_err(err)?;
+
+    Ok(MovieRecorder { file })
+}
+
+impl MovieRecorder {
+    pub fn record(&mut self, pressed: u8) -> Result<(), io::Error> {
+        self.file.write_all(&[pressed])?;
+
+        // The emulator is stopped by closing the window, so there's no chance to flush at the end
+        self.file.flush()
+    }
+}
+
+pub struct MoviePlayer {
+    inputs: Vec<u8>,
+
+    // The next frame to be played
+    frame: usize,
+}
+
+pub fn load_movie(path: &str, cart: &Cartridge, config: &GameBoyConfig) -> Result<MoviePlayer, MovieError> {
+    let file = fs::read(path).map_err(|e| MovieError::Io(path.to_string(), e))?;
+
+    let not_a_movie = || MovieError::NotAMovie(path.to_string());
+
+    if !file.starts_with(MAGIC) {
+        return Err(not_a_movie());
+    }
+
+    let mut i = MAGIC.len();
+
+    let format_version = *file.get(i).ok_or_else(not_a_movie)?;
+    if format_version != FORMAT_VERSION {
+        return Err(MovieError::UnsupportedVersion(path.to_string(), format_version));
+    }
+    i += 1;
+
+    let version_len = *file.get(i).ok_or_else(not_a_movie)? as usize;
+    i += 1;
+    let version = file.get(i..i + version_len).ok_or_else(not_a_movie)?;
+    let version = String::from_utf8_lossy(version).to_string();
+    i += version_len;
+
+    let crc = file.get(i..i + 4).ok_or_else(not_a_movie)?;
+    let crc = u32::from_le_bytes(crc.try_into().unwrap());
+    i += 4;
+
+    let boot = match *file.get(i).ok_or_else(not_a_movie)? {
+        BOOT_ROM => {
+            let crc = file.get(i + 1..i + 5).ok_or_else(not_a_movie)?;
+            i += 5;
+            SetupBoot::Rom(u32::from_le_bytes(crc.try_into().unwrap()))
+        }
+        BOOT_SKIP => {
+            let model = *file.get(i + 1).ok_or_else(not_a_movie)?;
+            i += 2;
+            SetupBoot::Skip(MODELS.get(model as usize).ok_or_else(not_a_movie)?.1)
+        }
+        _ => return Err(not_a_movie()),
+    };
+
+    let flags = *file.get(i).ok_or_else(not_a_movie)?;
+    let recorded = Setup { boot, skip_boot: flags & DEBUG_SKIP_BOOT != 0, stub_ly: flags & DEBUG_STUB_LY != 0 };
+    i += 1;
+
+    let start_time = file.get(i..i + 8).ok_or_else(not_a_movie)?;
+    let start_time = u64::from_le_bytes(start_time.try_into().unwrap());
+    i += 8;
+
+    let start = *file.get(i).ok_or_else(not_a_movie)?;
+    i += 1;
+
+    if crc != cart.crc32() {
+        return Err(MovieError::WrongRom(path.to_string(), crc, cart.crc32()));
+    }
+
+    if recorded != setup(config) {
+        return Err(MovieError::WrongSetup(path.to_string(), recorded, setup(config)));
+    }
+
+    if start != START_POWER_ON {
+        return Err(MovieError::UnsupportedStart(path.to_string(), start));
+    }
+
+    if version != env!("CARGO_PKG_VERSION") {
+        eprintln!("movie {} was recorded with version {}, it may not play back the same", path, version);
+    }
+
+    let inputs = file[i..].to_vec();
+
+    println!("Playing movie {}: {} frames, recorded at {} (unix time)", path, inputs.len(), start_time);
+
+    Ok(MoviePlayer { inputs, frame: 0 })
+}
+
+impl MoviePlayer {
+    /*
+        Returns the keys to hold for the next frame, or None once the movie has finished
+     */
+    pub fn next_input(&mut self) -> Option<u8> {
+        let input = self.inputs.get(self.frame).copied();
+
+        self.frame += 1;
+
+        input
+    }
+}
+
+pub enum Movie {
+    Record(MovieRecorder),
+    Play(MoviePlayer),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::gameboy::cartridge::new_test_cartridge;
+    use crate::gameboy::debug::DebugConfig;
+
+    fn movie_path(name: &str) -> String {
+        env::temp_dir().join(format!("rusty-gigabyte-movie-{}-{}.gbm", name, std::process::id())).to_str().unwrap().to_string()
+    }
+
+    fn record(path: &str, cart: &Cartridge, config: &GameBoyConfig, inputs: &[u8]) {
+        let mut recorder = new_movie_recorder(path, cart, config).unwrap();
+
+        for &pressed in inputs {
+            recorder.record(pressed).unwrap();
+        }
+    }
+
+    #[test]
+    fn plays_back_what_was_recorded() {
+        let path = movie_path("round-trip");
+        let cart = new_test_cartridge(&[]);
+
+        record(&path, &cart, &GameBoyConfig::default(), &[0x00, 0x01, 0x81, 0x00]);
+
+        let mut player = load_movie(&path, &cart, &GameBoyConfig::default()).unwrap();
+        let played: Vec<Option<u8>> = (0..5).map(|_| player.next_input()).collect();
+
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(played, vec!(Some(0x00), Some(0x01), Some(0x81), Some(0x00), None));
+    }
+
+    #[test]
+    fn refuses_other_roms() {
+        let path = movie_path("wrong-rom");
+        let cart = new_test_cartridge(&[]);
+
+        record(&path, &cart, &GameBoyConfig::default(), &[0x01]);
+
+        let result = load_movie(&path, &new_test_cartridge(&[0x00, 0x01]), &GameBoyConfig::default());
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(MovieError::WrongRom(_, expected, found)) if expected == cart.crc32() && found != expected));
+    }
+
+    #[test]
+    fn refuses_bad_headers() {
+        let path = movie_path("headers");
+        let cart = new_test_cartridge(&[]);
+
+        record(&path, &cart, &GameBoyConfig::default(), &[]);
+
+        let header = fs::read(&path).unwrap();
+        let load = |bytes: &[u8]| {
+            fs::write(&path, bytes).unwrap();
+            load_movie(&path, &cart, &GameBoyConfig::default())
+        };
+
+        let not_a_movie = load(b"RGBSTATE");
+        let truncated = load(&header[..header.len() - 1]);
+        let newer = load(&[&MAGIC[..], &[FORMAT_VERSION + 1]].concat());
+        let from_a_state = load(&[&header[..header.len() - 1], &[0x01]].concat());
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(not_a_movie, Err(MovieError::NotAMovie(_))));
+        assert!(matches!(truncated, Err(MovieError::NotAMovie(_))));
+        assert!(matches!(newer, Err(MovieError::UnsupportedVersion(_, version)) if version == FORMAT_VERSION + 1));
+        assert!(matches!(from_a_state, Err(MovieError::UnsupportedStart(_, 0x01))));
+    }
+
+    #[test]
+    fn refuses_other_setups() {
+        let path = movie_path("wrong-setup");
+        let cart = new_test_cartridge(&[]);
+        let skipped = |model| GameBoyConfig { boot: Some(Boot::Skip(model)), ..Default::default() };
+        let debug = |debug| GameBoyConfig { debug, ..Default::default() };
+
+        record(&path, &cart, &skipped(Model::Mgb), &[0x01]);
+
+        let same = load_movie(&path, &cart, &skipped(Model::Mgb));
+        let other_model = load_movie(&path, &cart, &skipped(Model::Dmg));
+        let boot_rom = load_movie(&path, &cart, &GameBoyConfig::default());
+        let other_boot_rom = load_movie(&path, &cart, &GameBoyConfig { boot: Some(Boot::Rom(vec![0; 0x100])), ..Default::default() });
+
+        record(&path, &cart, &debug(DebugConfig { stub_ly: true, ..Default::default() }), &[0x01]);
+
+        let stubbed = load_movie(&path, &cart, &debug(DebugConfig { stub_ly: true, trace: true, ..Default::default() }));
+        let not_stubbed = load_movie(&path, &cart, &GameBoyConfig::default());
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(same.is_ok());
+        assert!(matches!(other_model, Err(MovieError::WrongSetup(_, Setup { boot: SetupBoot::Skip(Model::Mgb), .. }, Setup { boot: SetupBoot::Skip(Model::Dmg), .. }))));
+        assert!(matches!(boot_rom, Err(MovieError::WrongSetup(_, _, Setup { boot: SetupBoot::Rom(_), .. }))));
+        assert!(matches!(other_boot_rom, Err(MovieError::WrongSetup(..))));
+        assert!(stubbed.is_ok());
+        assert!(matches!(not_stubbed, Err(MovieError::WrongSetup(_, Setup { stub_ly: true, .. }, Setup { stub_ly: false, .. }))));
+    }
+}
@@ -0,0 +1,456 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use crate::gameboy::serial::LinkPartner;
+
+/*
+    Emulates the Game Boy Printer, following: https://gbdev.io/pandocs/Gameboy_Printer.html
+
+    The GB always drives the clock when talking to the printer, sending packets of the form:
+        0x88 0x33 | command | compression | length (LE) | data | checksum (LE) | 0x00 0x00
+
+    The printer answers 0x00 for every byte except the last two, where it sends 0x81 (it's alive)
+    and then its status.
+
+    Each printed strip of paper is written to a PNG. A strip carries on across print commands
+    until one finishes with a margin after it, in the same way that the paper would only be torn
+    off once it has been fed out. The exposure (how dark to print) isn't emulated, every print
+    comes out in the same four shades.
+ */
+
+const MAGIC_1: u8 = 0x88;
+const MAGIC_2: u8 = 0x33;
+
+const ALIVE: u8 = 0x81;
+
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_DATA_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED_DATA: u8 = 0x08;
+
+// Printed images are 20 tiles wide
+const WIDTH: usize = 160;
+const TILES_PER_ROW: usize = 20;
+const BYTES_PER_TILE: usize = 16;
+const BYTES_PER_TILE_ROW: usize = TILES_PER_ROW * BYTES_PER_TILE;
+
+// The printer has enough memory for a full screen, 18 rows of tiles
+const BUFFER_SIZE: usize = BYTES_PER_TILE_ROW * 18;
+
+// Each unit of margin feeds this many lines of blank paper
+const LINES_PER_MARGIN: usize = 16;
+
+// How many status requests report the printer as busy after a print command
+const BUSY_STATUS_POLLS: u8 = 4;
+
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+pub struct Printer {
+    // Where to write the printed strips
+    output_dir: PathBuf,
+
+    // How many strips have been written, used to name the files
+    strips: u32,
+
+    state: PacketState,
+
+    // The packet being received
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+
+    status: u8,
+
+    busy_polls: u8,
+
+    // Tile data waiting to be printed
+    buffer: Vec<u8>,
+
+    // The strip being printed, one shade per pixel
+    strip: Vec<u8>,
+}
+
+pub fn new_printer(output_dir: &str) -> Result<Printer, io::Error> {
+    fs::create_dir_all(output_dir)?;
+
+    Ok(Printer {
+        output_dir: PathBuf::from(output_dir),
+        strips: 0,
+        state: PacketState::Magic1,
+        command: 0,
+        compressed: false,
+        length: 0,
+        data: vec!(),
+        checksum: 0,
+        status: 0,
+        busy_polls: 0,
+        buffer: vec!(),
+        strip: vec!(),
+    })
+}
+
+impl LinkPartner for Printer {
+    fn exchange(&mut self, out: u8) -> u8 {
+        match self.state {
+            PacketState::Magic1 => {
+                if out == MAGIC_1 {
+                    self.state = PacketState::Magic2;
+                }
+            }
+            PacketState::Magic2 => {
+                self.state = if out == MAGIC_2 { PacketState::Command } else { PacketState::Magic1 };
+            }
+            PacketState::Command => {
+                self.command = out;
+                self.checksum = out as u16;
+                self.state = PacketState::Compression;
+            }
+            PacketState::Compression => {
+                self.compressed = out & 0x01 > 0;
+                self.checksum = self.checksum.wrapping_add(out as u16);
+                self.state = PacketState::LengthLow;
+            }
+            PacketState::LengthLow => {
+                self.length = out as u16;
+                self.checksum = self.checksum.wrapping_add(out as u16);
+                self.state = PacketState::LengthHigh;
+            }
+            PacketState::LengthHigh => {
+                self.length |= (out as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(out as u16);
+                self.data.clear();
+                self.state = if self.length == 0 { PacketState::ChecksumLow } else { PacketState::Data };
+            }
+            PacketState::Data => {
+                self.data.push(out);
+                self.checksum = self.checksum.wrapping_add(out as u16);
+
+                if self.data.len() == self.length as usize {
+                    self.state = PacketState::ChecksumLow;
+                }
+            }
+            PacketState::ChecksumLow => {
+                self.checksum = self.checksum.wrapping_sub(out as u16);
+                self.state = PacketState::ChecksumHigh;
+            }
+            PacketState::ChecksumHigh => {
+                self.checksum = self.checksum.wrapping_sub((out as u16) << 8);
+                self.state = PacketState::Alive;
+            }
+            PacketState::Alive => {
+                // The whole packet has arrived, handle it before reporting the status
+                self.process_packet();
+                self.state = PacketState::Status;
+
+                return ALIVE;
+            }
+            PacketState::Status => {
+                self.state = PacketState::Magic1;
+
+                return self.status;
+            }
+        }
+
+        0x00
+    }
+
+    fn poll(&mut self, _out: u8) -> Option<u8> {
+        None // The printer never drives the clock
+    }
+}
+
+impl Printer {
+    fn process_packet(&mut self) {
+        if self.checksum != 0 {
+            // checksum holds the calculated sum less the one received
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            CMD_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            }
+            CMD_DATA => {
+                let data = if self.compressed { decompress(&self.data) } else { self.data.clone() };
+
+                let space = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..data.len().min(space)]);
+
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED_DATA;
+                }
+
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= STATUS_IMAGE_DATA_FULL;
+                }
+            }
+            CMD_PRINT => {
+                if self.data.len() < 4 {
+                    return;
+                }
+
+                // data[3] is the exposure, which isn't emulated
+                let sheets = self.data[0];
+                let margin_before = (self.data[1] >> 4) as usize;
+                let margin_after = (self.data[1] & 0x0F) as usize;
+                let palette = if self.data[2] == 0 { 0xE4 } else { self.data[2] };
+
+                self.print(sheets, margin_before, margin_after, palette);
+
+                self.status &= !(STATUS_UNPROCESSED_DATA | STATUS_IMAGE_DATA_FULL);
+                self.status |= STATUS_PRINTING;
+                self.busy_polls = BUSY_STATUS_POLLS;
+            }
+            CMD_STATUS => {
+                if self.busy_polls > 0 {
+                    self.busy_polls -= 1;
+                } else {
+                    self.status &= !STATUS_PRINTING;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /*
+        Prints the buffer `sheets` times one after the other, zero sheets only feeds the paper
+     */
+    fn print(&mut self, sheets: u8, margin_before: usize, margin_after: usize, palette: u8) {
+        self.feed(margin_before);
+
+        let image = self.render(palette);
+
+        for _ in 0..sheets {
+            self.strip.extend_from_slice(&image);
+        }
+
+        self.buffer.clear();
+
+        self.feed(margin_after);
+
+        if self.strip.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.write_strip() {
+            eprintln!("unable to write printed image: {}", e);
+        }
+
+        // Once the paper has been fed out the strip is complete, the next print starts a new one
+        if margin_after > 0 {
+            self.strip.clear();
+            self.strips += 1;
+        }
+    }
+
+    /*
+        The buffer as it would be printed, one shade per pixel
+     */
+    fn render(&self, palette: u8) -> Vec<u8> {
+        let tile_rows = self.buffer.len() / BYTES_PER_TILE_ROW;
+        let mut image = Vec::with_capacity(tile_rows * 8 * WIDTH);
+
+        for tile_row in 0..tile_rows {
+            for y in 0..8 {
+                for x in 0..WIDTH {
+                    let tile = tile_row * TILES_PER_ROW + (x / 8);
+                    let offs = (tile * BYTES_PER_TILE) + (y * 2);
+
+                    let b1 = self.buffer[offs];
+                    let b2 = self.buffer[offs + 1];
+
+                    let n = 7 - (x % 8);
+                    let color = ((b1 >> n) & 1) | (((b2 >> n) & 1) << 1);
+                    let shade = (palette >> (color * 2)) & 0b11;
+
+                    image.push(SHADES[shade as usize]);
+                }
+            }
+        }
+
+        image
+    }
+
+    fn feed(&mut self, margin: usize) {
+        self.strip.resize(self.strip.len() + (margin * LINES_PER_MARGIN * WIDTH), SHADES[0]);
+    }
+
+    /*
+        Writes the strip so far, this is rewritten as a strip grows so it is always up to date
+     */
+    fn write_strip(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self.output_dir.join(format!("print_{:04}.png", self.strips));
+
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), WIDTH as u32, (self.strip.len() / WIDTH) as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.strip)?;
+
+        Ok(())
+    }
+}
+
+/*
+    Data packets can be run length encoded. Each run starts with a control byte:
+        - 1xxxxxxx: the following byte is repeated (x + 2) times
+        - 0xxxxxxx: the following (x + 1) bytes are copied as they are
+ */
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec!();
+    let mut i = 0;
+
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+
+        if control & 0x80 > 0 {
+            let count = (control & 0x7F) as usize + 2;
+
+            if let Some(&val) = data.get(i) {
+                out.resize(out.len() + count, val);
+            }
+
+            i += 1;
+        } else {
+            let count = control as usize + 1;
+            let end = (i + count).min(data.len());
+
+            out.extend_from_slice(&data[i..end]);
+
+            i = end;
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /*
+        Removes the printer's output directory when the test ends, however it ends
+     */
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn test_printer(name: &str) -> (Printer, TempDir) {
+        let dir = env::temp_dir().join(format!("rusty-gigabyte-printer-{}-{}", name, std::process::id()));
+
+        (new_printer(dir.to_str().unwrap()).unwrap(), TempDir(dir))
+    }
+
+    /*
+        Sends a whole packet, returning the printer's last two replies, which should be ALIVE and
+        the status
+     */
+    fn send_packet(printer: &mut Printer, command: u8, compression: u8, data: &[u8], checksum_error: u16) -> (u8, u8) {
+        let length = data.len() as u16;
+        let checksum = [command, compression, length as u8, (length >> 8) as u8].iter().chain(data)
+            .fold(0u16, |sum, b| sum.wrapping_add(*b as u16))
+            .wrapping_add(checksum_error);
+
+        let mut packet = vec![MAGIC_1, MAGIC_2, command, compression, length as u8, (length >> 8) as u8];
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(&[checksum as u8, (checksum >> 8) as u8]);
+
+        for b in packet {
+            assert_eq!(printer.exchange(b), 0x00);
+        }
+
+        (printer.exchange(0x00), printer.exchange(0x00))
+    }
+
+    #[test]
+    fn decompresses_runs() {
+        // Two bytes copied, then 0xAB five times, then one byte copied
+        let data = [0x01, 0x11, 0x22, 0x83, 0xAB, 0x00, 0x33];
+
+        assert_eq!(decompress(&data), vec![0x11, 0x22, 0xAB, 0xAB, 0xAB, 0xAB, 0xAB, 0x33]);
+    }
+
+    #[test]
+    fn decompresses_compressed_data_packets() {
+        let (mut printer, _dir) = test_printer("rle");
+
+        // A whole row of tiles, all 0xFF, as runs of 129, 129 and 62
+        let data = [0xFF, 0xFF, 0xFF, 0xFF, 0xBC, 0xFF];
+        assert_eq!(send_packet(&mut printer, CMD_DATA, 0x01, &data, 0), (ALIVE, STATUS_UNPROCESSED_DATA));
+
+        assert_eq!(printer.buffer, vec![0xFF; BYTES_PER_TILE_ROW]);
+    }
+
+    #[test]
+    fn reports_bad_checksums() {
+        let (mut printer, _dir) = test_printer("checksum");
+
+        assert_eq!(send_packet(&mut printer, CMD_DATA, 0x00, &[0x12; 16], 1), (ALIVE, STATUS_CHECKSUM_ERROR));
+        assert!(printer.buffer.is_empty());
+
+        // Cleared by the next good packet
+        assert_eq!(send_packet(&mut printer, CMD_STATUS, 0x00, &[], 0), (ALIVE, 0));
+    }
+
+    #[test]
+    fn status_through_a_print() {
+        let (mut printer, _dir) = test_printer("status");
+
+        assert_eq!(send_packet(&mut printer, CMD_INIT, 0x00, &[], 0), (ALIVE, 0));
+        assert_eq!(send_packet(&mut printer, CMD_DATA, 0x00, &[0x00; BUFFER_SIZE], 0), (ALIVE, STATUS_UNPROCESSED_DATA | STATUS_IMAGE_DATA_FULL));
+
+        // An empty data packet ends the data
+        assert_eq!(send_packet(&mut printer, CMD_DATA, 0x00, &[], 0), (ALIVE, STATUS_UNPROCESSED_DATA | STATUS_IMAGE_DATA_FULL));
+
+        // Two copies with a margin after
+        assert_eq!(send_packet(&mut printer, CMD_PRINT, 0x00, &[0x02, 0x01, 0xE4, 0x40], 0), (ALIVE, STATUS_PRINTING));
+
+        for _ in 0..BUSY_STATUS_POLLS {
+            assert_eq!(send_packet(&mut printer, CMD_STATUS, 0x00, &[], 0), (ALIVE, STATUS_PRINTING));
+        }
+
+        assert_eq!(send_packet(&mut printer, CMD_STATUS, 0x00, &[], 0), (ALIVE, 0));
+
+        let written = fs::read(printer.output_dir.join("print_0000.png")).unwrap();
+        let reader = png::Decoder::new(written.as_slice()).read_info().unwrap();
+
+        assert_eq!((reader.info().width, reader.info().height), (WIDTH as u32, (2 * 144 + LINES_PER_MARGIN) as u32));
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use crate::gameboy::GameBoy;
+use crate::gameboy::input::FRAMES_PER_SECOND;
+
+/*
+    Rewinding, stepping back through snapshots of the GB taken every few frames.
+
+    Only the newest snapshot is kept whole. Each older one is stored as the difference (XOR) from
+    the one after it, which is mostly zeros from one snapshot to the next and so compresses very
+    well. Stepping back undoes the newest difference, and once the buffer is full the oldest
+    difference is simply dropped.
+ */
+
+#[derive(Debug, Clone, Copy)]
+pub struct RewindConfig {
+    // Frames between snapshots
+    pub interval: u32,
+
+    // How many seconds can be rewound, 0 turns rewinding off
+    pub length: u32,
+
+    // How fast rewinding goes back, e.g. 2 goes back two seconds every second
+    pub speed: u32,
+}
+
+pub struct Rewind {
+    config: RewindConfig,
+
+    // How many differences fit in the buffer
+    capacity: usize,
+
+    // The newest snapshot
+    latest: Option<Vec<u8>>,
+
+    // Compressed differences between each snapshot and the next, oldest first
+    deltas: VecDeque<Vec<u8>>,
+
+    // Frames run since the last snapshot
+    frames: u32,
+
+    // How far rewinding has got towards the next snapshot back, in frames
+    clock: u32,
+}
+
+pub fn new_rewind(config: RewindConfig) -> Rewind {
+    Rewind {
+        config,
+        capacity: (config.length * FRAMES_PER_SECOND / config.interval.max(1)) as usize,
+        latest: None,
+        deltas: VecDeque::new(),
+        frames: 0,
+        clock: 0,
+    }
+}
+
+impl Rewind {
+    /*
+        Called after every frame, taking a snapshot once enough frames have run
+     */
+    pub fn frame_done(&mut self, game_boy: &GameBoy) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.frames += 1;
+
+        if self.frames < self.config.interval {
+            return;
+        }
+
+        self.frames = 0;
+
+        let state = game_boy.save_state();
+
+        if let Some(latest) = &self.latest {
+            self.deltas.push_back(compress(&xor(latest, &state)));
+
+            if self.deltas.len() > self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+
+        self.latest = Some(state);
+    }
+
+    /*
+        Called every frame while rewinding instead of running the frame. Stays put once there is
+        nothing further back.
+     */
+    pub fn step_back(&mut self, game_boy: &mut GameBoy) {
+        self.clock += self.config.speed;
+
+        let mut moved = false;
+
+        while self.clock >= self.config.interval {
+            self.clock -= self.config.interval;
+
+            if self.frames > 0 {
+                // The GB has run on since the newest snapshot, so go back to that first
+                self.frames = 0;
+            } else {
+                let (latest, delta) = match (&self.latest, self.deltas.pop_back()) {
+                    (Some(latest), Some(delta)) => (latest, delta),
+                    _ => break,
+                };
+
+                self.latest = Some(xor(latest, &decompress(&delta)));
+            }
+
+            moved = true;
+        }
+
+        if let (true, Some(latest)) = (moved, &self.latest) {
+            // Snapshots only ever come from this GB, so they always load
+            game_boy.load_state(latest).unwrap();
+        }
+    }
+}
+
+/*
+    States are always the same length for a ROM
+ */
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(x, y)| x ^ y).collect()
+}
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(vec!(), Compression::fast());
+
+    // Writing to a Vec can't fail
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec!();
+
+    DeflateDecoder::new(data).read_to_end(&mut out).expect("rewind snapshot is corrupt");
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::new_test_game_boy;
+
+    // LD HL,$C000; INC (HL); INC A; JR -4, so every instruction changes the state
+    const COUNTER: [u8; 7] = [0x21, 0x00, 0xC0, 0x34, 0x3C, 0x18, 0xFC];
+
+    const INTERVAL: u32 = 20;
+
+    // Enough for 3 differences, going back one snapshot per step
+    const CONFIG: RewindConfig = RewindConfig { interval: INTERVAL, length: INTERVAL * 3 / FRAMES_PER_SECOND, speed: INTERVAL };
+
+    /*
+        Runs an instruction, then enough frames for a snapshot, returning the state snapshotted
+     */
+    fn snapshot(rewind: &mut Rewind, gb: &mut GameBoy) -> Vec<u8> {
+        gb.step_instruction();
+
+        for _ in 0..INTERVAL {
+            rewind.frame_done(gb);
+        }
+
+        gb.save_state()
+    }
+
+    #[test]
+    fn differences_undo_exactly() {
+        let (a, b) = (vec![0x00, 0x12, 0xFF, 0x80], vec![0x00, 0x13, 0x0F, 0x80]);
+
+        assert_eq!(xor(&a, &decompress(&compress(&xor(&a, &b)))), b);
+    }
+
+    #[test]
+    fn steps_back_through_every_snapshot() {
+        let mut gb = new_test_game_boy(&COUNTER, None);
+        let mut rewind = new_rewind(CONFIG);
+
+        let states: Vec<Vec<u8>> = (0..4).map(|_| snapshot(&mut rewind, &mut gb)).collect();
+
+        for state in states.iter().rev().skip(1) {
+            rewind.step_back(&mut gb);
+            assert_eq!(&gb.save_state(), state);
+        }
+
+        // Nothing further back, so it stays at the oldest
+        rewind.step_back(&mut gb);
+        assert_eq!(gb.save_state(), states[0]);
+    }
+
+    #[test]
+    fn forgets_the_oldest_snapshots_once_full() {
+        let mut gb = new_test_game_boy(&COUNTER, None);
+        let mut rewind = new_rewind(CONFIG);
+
+        let states: Vec<Vec<u8>> = (0..6).map(|_| snapshot(&mut rewind, &mut gb)).collect();
+
+        for _ in 0..5 {
+            rewind.step_back(&mut gb);
+        }
+
+        assert_eq!(gb.save_state(), states[2]);
+    }
+
+    #[test]
+    fn goes_back_to_the_newest_snapshot_first() {
+        let mut gb = new_test_game_boy(&COUNTER, None);
+        let mut rewind = new_rewind(CONFIG);
+
+        snapshot(&mut rewind, &mut gb);
+        let newest = snapshot(&mut rewind, &mut gb);
+
+        gb.step_instruction();
+        rewind.frame_done(&gb);
+
+        rewind.step_back(&mut gb);
+        assert_eq!(gb.save_state(), newest);
+    }
+
+    #[test]
+    fn does_nothing_when_turned_off() {
+        let mut gb = new_test_game_boy(&COUNTER, None);
+        let mut rewind = new_rewind(RewindConfig { length: 0, ..CONFIG });
+
+        snapshot(&mut rewind, &mut gb);
+        let now = snapshot(&mut rewind, &mut gb);
+
+        rewind.step_back(&mut gb);
+        assert_eq!(gb.save_state(), now);
+    }
+}
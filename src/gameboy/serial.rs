@@ -0,0 +1,170 @@
+use crate::gameboy::cpu;
+use crate::gameboy::mmu::MMU;
+use crate::gameboy::state::{StateError, StateReader, StateWriter};
+
+pub const REG_SERIAL_DATA: u16 = 0xFF01;
+pub const REG_SERIAL_CONTROL: u16 = 0xFF02;
+
+// Set by the program to start a transfer, cleared by the hardware once the byte has been shifted
+const FLAG_SC_TRANSFER: u8 = 0x80;
+// #0 when the partner drives the clock (external), #1 when this GB drives the clock (internal)
+const FLAG_SC_INTERNAL_CLOCK: u8 = 0x01;
+
+// The internal clock runs at 8192Hz, so each bit takes 512 t cycles and a byte takes 4096
+const CYCLES_PER_BIT: u32 = 512;
+const CYCLES_PER_BYTE: u32 = CYCLES_PER_BIT * 8;
+
+/*
+    Something plugged into the other end of the link cable.
+
+    The GB that drives the clock (internal clock) shifts a whole byte out and a whole byte in at
+    the same time. Rather than emulate each bit, a partner is only asked to swap whole bytes.
+ */
+pub trait LinkPartner: Send {
+    /*
+        Called when this GB is driving the clock and has finished shifting out `out`.
+
+        Returns the byte that was shifted in from the partner.
+     */
+    fn exchange(&mut self, out: u8) -> u8;
+
+    /*
+        Called periodically while this GB is not driving the clock. `out` is the current value of SB.
+
+        If the partner has clocked a byte since the last poll this returns the byte shifted in, and
+        the partner receives `out`.
+     */
+    fn poll(&mut self, out: u8) -> Option<u8>;
+
+    /*
+        Called as soon as SB changes, with its new value. Polls are too far apart for a partner
+        that can clock a byte at any moment to rely on them for this.
+     */
+    fn publish(&mut self, _out: u8) {}
+}
+
+pub struct Serial {
+    // Whether an internal clock transfer is in progress
+    active: bool,
+
+    // How many t cycles the current transfer has been running
+    transfer_clock: u32,
+
+    // How many t cycles since the partner was last polled
+    poll_clock: u32,
+
+    // Whatever is plugged into the link port, None when there is no cable
+    link: Option<Box<dyn LinkPartner>>,
+
+    // SB as the link was last told it, see LinkPartner::publish
+    published: Option<u8>,
+}
+
+pub fn new_serial(link: Option<Box<dyn LinkPartner>>) -> Serial {
+    Serial {
+        active: false,
+        transfer_clock: 0,
+        poll_clock: 0,
+        link,
+        published: None,
+    }
+}
+
+impl Serial {
+    /*
+        Back to how it was at power on, leaving the cable plugged in
+     */
+    pub(crate) fn reset(&mut self) {
+        *self = new_serial(self.link.take());
+    }
+
+    pub(crate) fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.active);
+        w.write_u32(self.transfer_clock);
+        w.write_u32(self.poll_clock);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.active = r.read_bool()?;
+        self.transfer_clock = r.read_u32()?;
+        self.poll_clock = r.read_u32()?;
+
+        // SB has probably changed
+        self.published = None;
+
+        Ok(())
+    }
+
+    /*
+        Follows the same pattern as the GPU, registers live in the MMU and are read each step.
+     */
+    pub(crate) fn step(&mut self, mmu: &mut MMU, delta_t: u32) {
+        let control = mmu.rb(REG_SERIAL_CONTROL);
+
+        if let Some(link) = &mut self.link {
+            let data = mmu.rb(REG_SERIAL_DATA);
+
+            if self.published != Some(data) {
+                link.publish(data);
+                self.published = Some(data);
+            }
+        }
+
+        if control & FLAG_SC_TRANSFER == 0 {
+            // Transfer was never started, or was cancelled by the program
+            self.active = false;
+        } else if control & FLAG_SC_INTERNAL_CLOCK > 0 {
+            if !self.active {
+                self.active = true;
+                self.transfer_clock = 0;
+            }
+
+            self.transfer_clock += delta_t;
+
+            if self.transfer_clock >= CYCLES_PER_BYTE {
+                let out = mmu.rb(REG_SERIAL_DATA);
+
+                // With no cable connected the input line is pulled high
+                let received = match &mut self.link {
+                    None => 0xFF,
+                    Some(link) => link.exchange(out),
+                };
+
+                self.complete(mmu, received, control);
+            }
+
+            return;
+        }
+
+        // This GB is not driving the clock, check whether the partner has clocked a byte
+        self.poll_clock += delta_t;
+
+        if self.poll_clock < CYCLES_PER_BIT {
+            return;
+        }
+
+        self.poll_clock = 0;
+
+        if let Some(link) = &mut self.link {
+            if let Some(received) = link.poll(mmu.rb(REG_SERIAL_DATA)) {
+                if control & FLAG_SC_TRANSFER > 0 {
+                    self.complete(mmu, received, control);
+                } else {
+                    // The shift register is clocked regardless, but no transfer was requested so there is no interrupt
+                    mmu.wb(REG_SERIAL_DATA, received);
+                }
+            }
+        }
+    }
+
+    fn complete(&mut self, mmu: &mut MMU, received: u8, control: u8) {
+        self.active = false;
+
+        mmu.wb(REG_SERIAL_DATA, received);
+        mmu.wb(REG_SERIAL_CONTROL, control & !FLAG_SC_TRANSFER);
+
+        // Send the serial interrupt
+        let i_f = mmu.rb(cpu::REG_INTERRUPTS);
+        mmu.wb(cpu::REG_INTERRUPTS, i_f | cpu::FLAG_INT_SERIAL);
+    }
+}
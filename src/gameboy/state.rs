@@ -0,0 +1,241 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+use crate::gameboy::cartridge::Cartridge;
+
+/*
+    Save states, a snapshot of the whole GB that can be restored later.
+
+    A state is laid out as:
+        "RGBSTATE" | format version | ROM CRC32 (LE) | CPU | MMU | joypad | GPU | serial
+
+    Each part writes its own fields in a fixed order, numbers are little endian. Anything that
+    changes what is written must bump FORMAT_VERSION, older states are then refused rather than
+    being misread. The GB has no timer, APU or cartridge mapper yet, they will need adding here
+    when they are emulated.
+
+    Nothing outside the GB is saved, e.g. whatever is plugged into the link port.
+ */
+
+const MAGIC: &[u8; 8] = b"RGBSTATE";
+
+const FORMAT_VERSION: u8 = 2;
+
+pub const DEFAULT_SAVE_DIR: &str = "saves";
+
+pub const SLOTS: u8 = 10;
+
+#[derive(Debug)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u8),
+    WrongRom(u32, u32),
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(f, "save state uses format version {}, only version {} is supported", version, FORMAT_VERSION),
+            StateError::WrongRom(expected, found) => write!(f, "save state is for a ROM with CRC32 {:08x}, but this ROM is {:08x}", expected, found),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Invalid(what) => write!(f, "save state has an invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+pub fn new_state_writer(cart: &Cartridge) -> StateWriter {
+    let mut writer = StateWriter { data: vec!() };
+
+    writer.write_bytes(MAGIC);
+    writer.write_u8(FORMAT_VERSION);
+    writer.write_u32(cart.crc32());
+
+    writer
+}
+
+impl StateWriter {
+    pub fn write_u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.data.push(val as u8);
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, val: &[u8]) {
+        self.data.extend_from_slice(val);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+
+    pos: usize,
+}
+
+/*
+    Checks the header, leaving the reader at the start of the CPU
+ */
+pub fn new_state_reader<'a>(data: &'a [u8], cart: &Cartridge) -> Result<StateReader<'a>, StateError> {
+    if !data.starts_with(MAGIC) {
+        return Err(StateError::NotAState);
+    }
+
+    let mut reader = StateReader { data, pos: MAGIC.len() };
+
+    let version = reader.read_u8()?;
+    if version != FORMAT_VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+
+    let crc = reader.read_u32()?;
+    if crc != cart.crc32() {
+        return Err(StateError::WrongRom(crc, cart.crc32()));
+    }
+
+    Ok(reader)
+}
+
+impl StateReader<'_> {
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        let val = *self.data.get(self.pos).ok_or(StateError::Truncated)?;
+        self.pos += 1;
+
+        Ok(val)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let mut bytes = [0; 2];
+        self.read_bytes(&mut bytes)?;
+
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        self.read_bytes(&mut bytes)?;
+
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /*
+        Fills out with the next out.len() bytes
+     */
+    pub fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.data.get(self.pos..self.pos + out.len()).ok_or(StateError::Truncated)?;
+        out.copy_from_slice(bytes);
+        self.pos += out.len();
+
+        Ok(())
+    }
+
+    /*
+        Anything left over means the state doesn't match what was expected
+     */
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.pos != self.data.len() {
+            return Err(StateError::Invalid("length"));
+        }
+
+        Ok(())
+    }
+}
+
+/*
+    Where the state in a slot is kept for a ROM
+ */
+pub fn slot_path(save_dir: &str, cart: &Cartridge, slot: u8) -> PathBuf {
+    PathBuf::from(save_dir).join(format!("{:08x}.state{}", cart.crc32(), slot))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::cartridge::new_test_cartridge;
+    use crate::gameboy::{new_test_game_boy, GameBoy};
+
+    // LD HL,$C000; INC (HL); INC A; JR -4, counting in memory and A forever
+    const COUNTER: [u8; 7] = [0x21, 0x00, 0xC0, 0x34, 0x3C, 0x18, 0xFC];
+
+    fn run_frames(gb: &mut GameBoy, frames: usize) {
+        for _ in 0..frames {
+            gb.run_frame();
+        }
+    }
+
+    #[test]
+    fn loading_a_state_carries_on_from_where_it_was_saved() {
+        let mut gb = new_test_game_boy(&COUNTER, None);
+        run_frames(&mut gb, 3);
+
+        let state = gb.save_state();
+
+        run_frames(&mut gb, 2);
+        let expected = gb.save_state();
+
+        gb.load_state(&state).unwrap();
+        assert_eq!(gb.save_state(), state);
+
+        run_frames(&mut gb, 2);
+        assert_eq!(gb.save_state(), expected);
+    }
+
+    #[test]
+    fn bad_states_leave_the_gb_alone() {
+        let mut gb = new_test_game_boy(&COUNTER, None);
+        run_frames(&mut gb, 1);
+
+        let state = gb.save_state();
+        let other_rom = new_test_game_boy(&[0x00], None).save_state();
+
+        let mut newer = state.clone();
+        newer[MAGIC.len()] = FORMAT_VERSION + 1;
+
+        let mut longer = state.clone();
+        longer.push(0x00);
+
+        run_frames(&mut gb, 1);
+        let now = gb.save_state();
+
+        assert!(matches!(gb.load_state(b"RGBMOVIE"), Err(StateError::NotAState)));
+        assert!(matches!(gb.load_state(&newer), Err(StateError::UnsupportedVersion(version)) if version == FORMAT_VERSION + 1));
+        assert!(matches!(gb.load_state(&other_rom), Err(StateError::WrongRom(..))));
+        assert!(matches!(gb.load_state(&state[..state.len() - 1]), Err(StateError::Truncated)));
+        assert!(matches!(gb.load_state(&longer), Err(StateError::Invalid("length"))));
+
+        assert_eq!(gb.save_state(), now);
+    }
+
+    #[test]
+    fn slots_are_named_after_the_rom() {
+        let cart = new_test_cartridge(&[]);
+
+        assert_eq!(slot_path("saves", &cart, 3), PathBuf::from("saves").join(format!("{:08x}.state3", cart.crc32())));
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/*
+    Labels from an RGBDS .sym file (https://rgbds.gbdev.io/sym), which rgblink writes with -n. Each
+    line is BANK:ADDR name in hex, e.g.
+        01:4A3C PlayerUpdate
+        01:4A51 PlayerUpdate.jump
+    with ; starting a comment.
+
+    Only ROM is banked here, as there are no MBCs yet. Labels anywhere else match whatever their
+    bank, so WRAMX and SRAM labels still show up.
+ */
+
+#[derive(Debug)]
+pub enum SymbolsError {
+    Io(String, io::Error),
+    Invalid(String, usize),
+}
+
+impl Display for SymbolsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolsError::Io(path, e) => write!(f, "unable to read symbols {}: {}", path, e),
+            SymbolsError::Invalid(path, line) => write!(f, "{} line {} isn't BANK:ADDR name", path, line),
+        }
+    }
+}
+
+impl std::error::Error for SymbolsError {}
+
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    // By bank then address, the first label at each address
+    labels: BTreeMap<(u16, u16), String>,
+
+    // Every label, with its bank and address
+    names: HashMap<String, (u16, u16)>,
+}
+
+pub fn load_symbols(path: &str) -> Result<Symbols, SymbolsError> {
+    let text = fs::read_to_string(path).map_err(|e| SymbolsError::Io(path.to_string(), e))?;
+
+    parse_symbols(&text).map_err(|line| SymbolsError::Invalid(path.to_string(), line))
+}
+
+/*
+    The .sym rgblink would have written next to the ROM, e.g. game.sym for game.gb, if there is one
+ */
+pub fn find_symbols(rom: &str) -> Option<String> {
+    let path = Path::new(rom).with_extension("sym");
+
+    path.is_file().then(|| path.to_string_lossy().into_owned())
+}
+
+/*
+    Returns the number of the first line that couldn't be read on failure
+ */
+pub fn parse_symbols(text: &str) -> Result<Symbols, usize> {
+    let mut symbols = Symbols::default();
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or("").trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let parsed = line.split_once(char::is_whitespace).and_then(|(location, name)| {
+            let (bank, addr) = location.split_once(':')?;
+
+            Some((u16::from_str_radix(bank, 16).ok()?, u16::from_str_radix(addr, 16).ok()?, name.trim()))
+        });
+
+        let (bank, addr, name) = parsed.ok_or(n + 1)?;
+
+        symbols.labels.entry(key(bank, addr)).or_insert_with(|| name.to_string());
+        symbols.names.insert(name.to_string(), (bank, addr));
+    }
+
+    Ok(symbols)
+}
+
+impl Symbols {
+    /*
+        The bank and address of a label
+     */
+    pub fn lookup(&self, name: &str) -> Option<(u16, u16)> {
+        self.names.get(name).copied()
+    }
+
+    /*
+        The label at addr, with bank being the ROM bank mapped there
+     */
+    pub fn label(&self, bank: u16, addr: u16) -> Option<&str> {
+        self.labels.get(&key(bank, addr)).map(|name| name.as_str())
+    }
+
+    /*
+        The label at or before addr, e.g. PlayerUpdate+$12, as long as it's in the same area of
+        memory and bank
+     */
+    pub fn describe(&self, bank: u16, addr: u16) -> Option<String> {
+        let (bank, _) = key(bank, addr);
+        let (&(_, at), name) = self.labels.range((bank, 0)..=(bank, addr)).next_back()?;
+
+        match addr - at {
+            _ if area(at) != area(addr) => None,
+            0 => Some(name.clone()),
+            offset => Some(format!("{}+${:X}", name, offset)),
+        }
+    }
+
+    /*
+        Swaps each $XXXX in an instruction's operands for the label there, if there is one, e.g.
+        call $4A3C becomes call PlayerUpdate
+     */
+    pub fn label_operands(&self, bank: u16, operands: &str) -> String {
+        let mut labelled = String::with_capacity(operands.len());
+        let mut rest = operands;
+
+        while let Some(i) = rest.find('$') {
+            labelled.push_str(&rest[..i]);
+            rest = &rest[i..];
+
+            let hex = rest.get(1..5).filter(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()));
+            let label = hex.and_then(|hex| self.label(bank, u16::from_str_radix(hex, 16).ok()?));
+
+            match label {
+                Some(label) => {
+                    labelled.push_str(label);
+                    rest = &rest[5..];
+                }
+                None => {
+                    labelled.push('$');
+                    rest = &rest[1..];
+                }
+            }
+        }
+
+        labelled.push_str(rest);
+        labelled
+    }
+
+    /*
+        Where the next label after addr is, in the same bank
+     */
+    pub fn next_label(&self, bank: u16, addr: u16) -> Option<u16> {
+        let from = key(bank, addr.checked_add(1)?);
+
+        self.labels.range(from..=(from.0, 0xFFFF)).next().map(|(&(_, at), _)| at)
+    }
+}
+
+/*
+    Whether the bank matters at addr, i.e. the switchable ROM bank
+ */
+pub fn banked(addr: u16) -> bool {
+    (0x4000..=0x7FFF).contains(&addr)
+}
+
+fn key(bank: u16, addr: u16) -> (u16, u16) {
+    (if banked(addr) { bank } else { 0 }, addr)
+}
+
+/*
+    Labels don't reach from one of these into another, e.g. from the end of ROM into VRAM
+ */
+fn area(addr: u16) -> u8 {
+    match addr {
+        0x0000..=0x3FFF => 0,
+        0x4000..=0x7FFF => 1,
+        0x8000..=0x9FFF => 2,
+        0xA000..=0xBFFF => 3,
+        0xC000..=0xDFFF => 4,
+        0xE000..=0xFF7F => 5,
+        _ => 6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "\
+; File generated by rgblink
+00:0150 Main
+00:0150 Main.duplicate
+00:0160 Main.loop ; a local label
+01:4A3C PlayerUpdate
+02:4000 Other
+02:4100 Other.end
+00:C0A0 wPlayerHealth
+00:FF80 hVar
+";
+
+    fn symbols() -> Symbols {
+        parse_symbols(SYM).unwrap()
+    }
+
+    #[test]
+    fn parses_sym_files() {
+        let symbols = symbols();
+
+        assert_eq!(symbols.lookup("PlayerUpdate"), Some((1, 0x4A3C)));
+        assert_eq!(symbols.lookup("Main.loop"), Some((0, 0x0160)));
+        assert_eq!(symbols.lookup("Main.duplicate"), Some((0, 0x0150)));
+        assert_eq!(symbols.lookup("nothing"), None);
+    }
+
+    #[test]
+    fn reports_the_first_bad_line() {
+        assert_eq!(parse_symbols("00:0150 Main\n\n0150 Main\n").unwrap_err(), 3);
+        assert_eq!(parse_symbols("00:0150\n").unwrap_err(), 1);
+        assert_eq!(parse_symbols("00:10000 Main\n").unwrap_err(), 1);
+    }
+
+    #[test]
+    fn only_rom_labels_depend_on_the_bank() {
+        let symbols = symbols();
+
+        assert_eq!(symbols.label(5, 0x0150), Some("Main"));
+        assert_eq!(symbols.label(1, 0x4A3C), Some("PlayerUpdate"));
+        assert_eq!(symbols.label(2, 0x4A3C), None);
+        assert_eq!(symbols.label(7, 0xC0A0), Some("wPlayerHealth"));
+    }
+
+    #[test]
+    fn describes_addresses_from_the_label_before() {
+        let symbols = symbols();
+
+        assert_eq!(symbols.describe(0, 0x0160).as_deref(), Some("Main.loop"));
+        assert_eq!(symbols.describe(0, 0x0172).as_deref(), Some("Main.loop+$12"));
+        assert_eq!(symbols.describe(1, 0x4A40).as_deref(), Some("PlayerUpdate+$4"));
+        assert_eq!(symbols.describe(2, 0x4A40).as_deref(), Some("Other.end+$940"));
+        assert_eq!(symbols.describe(0, 0x0100), None);
+
+        // Not from the end of WRAM into echo RAM
+        assert_eq!(symbols.describe(0, 0xE000), None);
+    }
+
+    #[test]
+    fn describe_stays_in_the_bank() {
+        let symbols = parse_symbols("00:0150 Main\n00:FF80 hVar\n02:4100 Other\n").unwrap();
+
+        assert_eq!(symbols.describe(1, 0x4010), None);
+        assert_eq!(symbols.describe(3, 0x4200), None);
+        assert_eq!(symbols.describe(2, 0x4200).as_deref(), Some("Other+$100"));
+    }
+
+    #[test]
+    fn labels_operands() {
+        let symbols = symbols();
+
+        assert_eq!(symbols.label_operands(1, "$4A3C"), "PlayerUpdate");
+        assert_eq!(symbols.label_operands(2, "$4A3C"), "$4A3C");
+        assert_eq!(symbols.label_operands(0, "[$C0A0], a"), "[wPlayerHealth], a");
+        assert_eq!(symbols.label_operands(0, "a, $50"), "a, $50");
+        assert_eq!(symbols.label_operands(0, "nz, $0150$0160"), "nz, MainMain.loop");
+    }
+
+    #[test]
+    fn finds_the_next_label_in_the_bank() {
+        let symbols = symbols();
+
+        assert_eq!(symbols.next_label(0, 0x0150), Some(0x0160));
+        assert_eq!(symbols.next_label(2, 0x4000), Some(0x4100));
+        assert_eq!(symbols.next_label(1, 0x4A3C), None);
+        assert_eq!(symbols.next_label(2, 0x3FFF), Some(0x4000));
+        assert_eq!(symbols.next_label(0, 0xFFFF), None);
+    }
+}
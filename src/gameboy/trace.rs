@@ -0,0 +1,401 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::ops::{Range, RangeInclusive};
+use std::sync::Arc;
+
+use crate::gameboy::cpu::CPU;
+use crate::gameboy::disasm::disassemble;
+use crate::gameboy::mmu::MMU;
+use crate::gameboy::symbols::Symbols;
+
+/*
+    Logs every instruction the CPU runs.
+
+    The Doctor format is line for line what https://github.com/robert/gameboy-doctor compares
+    against. The full format adds the frame, ROM bank, the instruction, how many cycles it took and
+    which interrupts were taken, e.g.
+        000012 01:4A3C  F0 44     ldh a, [$FF44]        A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE  12t
+        000012 -- interrupt 0040
+
+    With symbols, labels are written before the instruction at them and used in place of addresses,
+    e.g.
+        000012 -- PlayerUpdate:
+        000012 01:4A3C  CD 51 4A  call PlayerMove       ...
+
+    In ring mode nothing is written as it happens, only the last few instructions are kept and
+    written out when something goes wrong (see Tracer::dump).
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    #[default]
+    Doctor,
+    Full,
+}
+
+pub const TRACE_FORMATS: [(&str, TraceFormat); 2] = [
+    ("doctor", TraceFormat::Doctor),
+    ("full", TraceFormat::Full),
+];
+
+/*
+    What to trace, everything by default
+ */
+#[derive(Debug, Clone, Default)]
+pub struct TraceConfig {
+    pub format: TraceFormat,
+
+    // Only instructions at these addresses
+    pub pcs: Option<RangeInclusive<u16>>,
+
+    // Only instructions in this ROM bank, 0 for anything outside the switchable bank
+    pub bank: Option<u16>,
+
+    // Only these frames, counted from when tracing started
+    pub frames: Option<Range<u64>>,
+
+    // Keep the last n instructions, only writing them out when dumped
+    pub ring: Option<usize>,
+}
+
+impl TraceConfig {
+    /*
+        Whether any of TRACE_OPTIONS were given, which mean nothing without --trace
+     */
+    pub fn needs_trace(&self) -> bool {
+        self.format != TraceFormat::Doctor || self.pcs.is_some() || self.bank.is_some() || self.frames.is_some() || self.ring.is_some()
+    }
+}
+
+pub struct Tracer {
+    config: TraceConfig,
+    out: BufWriter<Box<dyn Write + Send>>,
+
+    // The last instructions when in ring mode
+    ring: VecDeque<String>,
+
+    // The line for the instruction being run, finished once its cycles are known
+    pending: Option<String>,
+
+    frame: u64,
+
+    symbols: Option<Arc<Symbols>>,
+}
+
+pub fn new_tracer(out: Box<dyn Write + Send>, config: TraceConfig) -> Tracer {
+    Tracer {
+        ring: VecDeque::with_capacity(config.ring.unwrap_or(0)),
+        config,
+        out: BufWriter::new(out),
+        pending: None,
+        frame: 0,
+        symbols: None,
+    }
+}
+
+impl Tracer {
+    /*
+        Called before the CPU runs the instruction at PC
+     */
+    pub(crate) fn instruction(&mut self, cpu: &CPU, mmu: &MMU) -> io::Result<()> {
+        let pc = cpu.pc();
+        let bank = mmu.rom_bank(pc);
+
+        if !self.wanted(pc, bank) {
+            return Ok(());
+        }
+
+        match self.config.format {
+            TraceFormat::Doctor => self.write(cpu.doctor_line(mmu)),
+            TraceFormat::Full => {
+                let r = cpu.registers();
+                let mut instruction = disassemble(pc, |a| mmu.rb(a));
+
+                if let Some(symbols) = &self.symbols {
+                    instruction.operands = symbols.label_operands(bank, &instruction.operands);
+
+                    if let Some(label) = symbols.label(bank, pc) {
+                        let line = format!("{:06} -- {}:", self.frame, label);
+                        self.write(line)?;
+                    }
+                }
+
+                let bytes: Vec<String> = (0..instruction.length).map(|i| format!("{:02X}", mmu.rb(pc.wrapping_add(i)))).collect();
+
+                self.pending = Some(format!("{:06} {:02X}:{:04X}  {:<8}  {:<20}  A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X}",
+                                            self.frame, bank, pc, bytes.join(" "), instruction.to_string(),
+                                            r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l, r.sp));
+
+                Ok(())
+            }
+        }
+    }
+
+    /*
+        Called once the instruction has run, with the t cycles it took and the vector jumped to if
+        an interrupt was taken afterwards
+     */
+    pub(crate) fn executed(&mut self, cycles: u32, interrupt: Option<u16>) -> io::Result<()> {
+        if let Some(line) = self.pending.take() {
+            self.write(format!("{}  {}t", line, cycles))?;
+        }
+
+        match interrupt {
+            Some(vector) => self.event(&format!("interrupt {:04X}", vector)),
+            None => Ok(()),
+        }
+    }
+
+    /*
+        Notes something that happened, in the full format only as it would break Doctor logs
+     */
+    pub(crate) fn event(&mut self, what: &str) -> io::Result<()> {
+        if self.config.format != TraceFormat::Full || self.config.frames.as_ref().is_some_and(|f| !f.contains(&self.frame)) {
+            return Ok(());
+        }
+
+        self.write(format!("{:06} -- {}", self.frame, what))
+    }
+
+    pub(crate) fn set_symbols(&mut self, symbols: Option<Arc<Symbols>>) {
+        self.symbols = symbols;
+    }
+
+    pub(crate) fn frame_done(&mut self) {
+        self.frame += 1;
+    }
+
+    /*
+        Writes out the instructions kept in ring mode, saying why. Does nothing otherwise, as
+        everything has already been written.
+     */
+    pub fn dump(&mut self, why: &str) -> io::Result<()> {
+        if self.config.ring.is_none() {
+            return self.out.flush();
+        }
+
+        writeln!(self.out, "-- last {} instructions before {}", self.ring.len(), why)?;
+
+        for line in self.ring.drain(..) {
+            writeln!(self.out, "{}", line)?;
+        }
+
+        self.out.flush()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn wanted(&self, pc: u16, bank: u16) -> bool {
+        self.config.pcs.as_ref().is_none_or(|pcs| pcs.contains(&pc))
+            && self.config.bank.is_none_or(|b| b == bank)
+            && self.config.frames.as_ref().is_none_or(|frames| frames.contains(&self.frame))
+    }
+
+    fn write(&mut self, line: String) -> io::Result<()> {
+        match self.config.ring {
+            Some(n) => {
+                if self.ring.len() >= n {
+                    self.ring.pop_front();
+                }
+
+                if n > 0 {
+                    self.ring.push_back(line);
+                }
+
+                Ok(())
+            }
+            None => writeln!(self.out, "{}", line),
+        }
+    }
+}
+
+// The command line options that narrow down a trace, see parse_trace_option
+pub const TRACE_OPTIONS: [&str; 5] = ["--trace-format", "--trace-pc", "--trace-bank", "--trace-frames", "--trace-ring"];
+
+/*
+    Applies one of TRACE_OPTIONS and its value to config, shared by both binaries. If the value is
+    invalid, returns what was expected.
+ */
+pub fn parse_trace_option(config: &mut TraceConfig, option: &str, value: &str) -> Result<(), &'static str> {
+    match option {
+        "--trace-format" => {
+            config.format = TRACE_FORMATS.iter().find(|(n, _)| n.eq_ignore_ascii_case(value)).map(|(_, f)| *f)
+                .ok_or("doctor or full")?;
+        }
+        "--trace-pc" => config.pcs = Some(parse_addr_range(value).ok_or("hex addresses like 0150-01FF")?),
+        "--trace-bank" => config.bank = Some(value.parse().map_err(|_| "a bank number")?),
+        "--trace-frames" => config.frames = Some(parse_frame_range(value).ok_or("frames like 60-120 or 60-")?),
+        "--trace-ring" => config.ring = Some(value.parse().map_err(|_| "a number of instructions")?),
+        _ => return Err("one of the --trace-* options"),
+    }
+
+    Ok(())
+}
+
+/*
+    An inclusive range of hex addresses, e.g. 0150-01FF, or a single address
+ */
+pub fn parse_addr_range(value: &str) -> Option<RangeInclusive<u16>> {
+    let hex = |s: &str| u16::from_str_radix(s.trim().trim_start_matches("0x"), 16).ok();
+
+    let range = match value.split_once('-') {
+        Some((from, to)) => hex(from)?..=hex(to)?,
+        None => hex(value)?..=hex(value)?,
+    };
+
+    (!range.is_empty()).then_some(range)
+}
+
+/*
+    An inclusive range of frames, e.g. 60-120, or from a frame onwards, e.g. 60-
+ */
+pub fn parse_frame_range(value: &str) -> Option<Range<u64>> {
+    let (from, to) = value.split_once('-')?;
+    let from = from.trim().parse().ok()?;
+
+    let to = match to.trim() {
+        "" => u64::MAX,
+        to => to.parse::<u64>().ok()?.checked_add(1)?,
+    };
+
+    (from < to).then_some(from..to)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /*
+        Somewhere to trace to that can be read back
+     */
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn tracer(config: TraceConfig) -> (Tracer, Shared) {
+        let out = Shared::default();
+
+        (new_tracer(Box::new(out.clone()), config), out)
+    }
+
+    #[test]
+    fn parses_addr_ranges() {
+        assert_eq!(parse_addr_range("0150-01FF"), Some(0x0150..=0x01FF));
+        assert_eq!(parse_addr_range("0x150 - 0x1ff"), Some(0x0150..=0x01FF));
+        assert_eq!(parse_addr_range("ff80"), Some(0xFF80..=0xFF80));
+        assert_eq!(parse_addr_range("01FF-0150"), None);
+        assert_eq!(parse_addr_range("0150-"), None);
+        assert_eq!(parse_addr_range("10000"), None);
+    }
+
+    #[test]
+    fn parses_frame_ranges() {
+        assert_eq!(parse_frame_range("60-120"), Some(60..121));
+        assert_eq!(parse_frame_range("60-"), Some(60..u64::MAX));
+        assert_eq!(parse_frame_range("60-60"), Some(60..61));
+        assert_eq!(parse_frame_range("120-60"), None);
+        assert_eq!(parse_frame_range("60"), None);
+        assert_eq!(parse_frame_range("-60"), None);
+    }
+
+    #[test]
+    fn rejects_unknown_trace_options() {
+        let mut config = TraceConfig::default();
+
+        assert_eq!(parse_trace_option(&mut config, "--trace-format", "FULL"), Ok(()));
+        assert_eq!(config.format, TraceFormat::Full);
+        assert_eq!(parse_trace_option(&mut config, "--trace-format", "json"), Err("doctor or full"));
+        assert_eq!(parse_trace_option(&mut config, "--trace-ring", "-1"), Err("a number of instructions"));
+        assert!(parse_trace_option(&mut config, "--trace", "t.log").is_err());
+    }
+
+    #[test]
+    fn writes_straight_away_without_a_ring() {
+        let (mut tracer, out) = tracer(TraceConfig::default());
+
+        tracer.write("one".to_string()).unwrap();
+        tracer.write("two".to_string()).unwrap();
+        tracer.flush().unwrap();
+
+        assert_eq!(out.text(), "one\ntwo\n");
+    }
+
+    #[test]
+    fn ring_keeps_the_newest_in_order() {
+        let (mut tracer, out) = tracer(TraceConfig { ring: Some(3), ..Default::default() });
+
+        for n in 1..=5 {
+            tracer.write(n.to_string()).unwrap();
+        }
+
+        tracer.flush().unwrap();
+        assert_eq!(out.text(), "");
+
+        tracer.dump("a crash").unwrap();
+        tracer.write("6".to_string()).unwrap();
+        tracer.dump("the end").unwrap();
+
+        assert_eq!(out.text(), "-- last 3 instructions before a crash\n3\n4\n5\n-- last 1 instructions before the end\n6\n");
+    }
+
+    #[test]
+    fn empty_ring_keeps_nothing() {
+        let (mut tracer, out) = tracer(TraceConfig { ring: Some(0), ..Default::default() });
+
+        tracer.write("1".to_string()).unwrap();
+        tracer.dump("a crash").unwrap();
+
+        assert_eq!(out.text(), "-- last 0 instructions before a crash\n");
+    }
+
+    #[test]
+    fn events_only_in_the_full_format_and_wanted_frames() {
+        let (mut doctor, doctor_out) = tracer(TraceConfig::default());
+        let (mut full, full_out) = tracer(TraceConfig { format: TraceFormat::Full, frames: Some(1..2), ..Default::default() });
+
+        for _ in 0..3 {
+            doctor.event("vblank").unwrap();
+            full.event("vblank").unwrap();
+            doctor.frame_done();
+            full.frame_done();
+        }
+
+        doctor.flush().unwrap();
+        full.flush().unwrap();
+
+        assert_eq!(doctor_out.text(), "");
+        assert_eq!(full_out.text(), "000001 -- vblank\n");
+    }
+
+    #[test]
+    fn any_trace_option_needs_a_trace() {
+        assert!(!TraceConfig::default().needs_trace());
+
+        for (option, value) in TRACE_OPTIONS.iter().zip(["full", "0150-01ff", "1", "60-", "100"]) {
+            let mut config = TraceConfig::default();
+            parse_trace_option(&mut config, option, value).unwrap();
+
+            assert!(config.needs_trace(), "{} {}", option, value);
+        }
+    }
+}
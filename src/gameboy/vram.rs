@@ -0,0 +1,172 @@
+use std::sync::{Arc, Mutex};
+
+use crate::gameboy::gpu::{FLAG_CONT_BG_MAP, FLAG_CONT_BG_SET, FLAG_CONT_WIN_ON, FLAG_CONT_WIN_TM, GPU, Palette};
+
+/*
+    A copy of VRAM and the registers that say how it's drawn, for looking at the tiles and maps
+    the GB is using.
+
+    Only the DMG's single bank is emulated, so on the CGB there's no bank 1 to show yet.
+ */
+
+// Tiles in VRAM's tile data, 0x8000-0x97FF
+pub const TILES: usize = 384;
+
+pub const VRAM_START: u16 = 0x8000;
+
+// The two 32x32 tile maps
+pub const MAPS: [u16; 2] = [0x9800, 0x9C00];
+
+#[derive(Debug, Clone)]
+pub struct VramSnapshot {
+    // 0x8000-0x9FFF
+    pub vram: Vec<u8>,
+
+    pub lcdc: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub wy: u8,
+    pub wx: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+
+    // The colours the GB draws its shades in
+    pub colors: Palette,
+}
+
+impl VramSnapshot {
+    /*
+        The colour numbers (0-3) of row y of a tile, left to right, before any palette
+     */
+    pub fn tile_row(&self, tile: usize, y: usize) -> [u8; 8] {
+        let addr = tile * 16 + y * 2;
+        let (b1, b2) = (self.vram[addr], self.vram[addr + 1]);
+
+        std::array::from_fn(|x| GPU::tilerow_n_to_color(b1, b2, 7 - x as u8))
+    }
+
+    /*
+        The colour of each colour number through a palette register, e.g. bgp
+     */
+    pub fn palette(&self, raw_palette: u8) -> Palette {
+        GPU::palette_colors(&self.colors, raw_palette)
+    }
+
+    pub fn byte(&self, addr: u16) -> u8 {
+        self.vram[(addr - VRAM_START) as usize]
+    }
+
+    /*
+        Where the BG's map is, from LCDC bit 3
+     */
+    pub fn bg_map(&self) -> u16 {
+        MAPS[(self.lcdc & FLAG_CONT_BG_MAP != 0) as usize]
+    }
+
+    /*
+        Where the window's map is, from LCDC bit 6
+     */
+    pub fn window_map(&self) -> u16 {
+        MAPS[(self.lcdc & FLAG_CONT_WIN_TM != 0) as usize]
+    }
+
+    pub fn window_on(&self) -> bool {
+        self.lcdc & FLAG_CONT_WIN_ON != 0
+    }
+
+    /*
+        Whether the maps' indexes count up from 0x8000 (LCDC bit 4 set), rather than being signed
+        from 0x9000
+     */
+    pub fn unsigned_tiles(&self) -> bool {
+        self.lcdc & FLAG_CONT_BG_SET != 0
+    }
+}
+
+/*
+    The tile (0-383) a map's tile index picks. Signed indexes 0x00-0x7F are tiles 0x100-0x17F.
+ */
+pub fn map_tile(index: u8, unsigned: bool) -> usize {
+    match index {
+        0x00..=0x7F if !unsigned => index as usize + 0x100,
+        _ => index as usize,
+    }
+}
+
+/*
+    Hands VRAM snapshots from the GB's thread to whatever is showing them. Like frame::MemorySink
+    every clone shares the same snapshot, so one clone goes to start_game_boy and the other is
+    kept to look at.
+
+    Copying VRAM every frame isn't free, so the GB only does it after Command::ViewVram(true).
+ */
+#[derive(Clone, Default)]
+pub struct VramShare {
+    latest: Arc<Mutex<Option<VramSnapshot>>>,
+}
+
+pub fn new_vram_share() -> VramShare {
+    VramShare::default()
+}
+
+impl VramShare {
+    pub fn update(&self, snapshot: VramSnapshot) {
+        *self.latest.lock().unwrap() = Some(snapshot);
+    }
+
+    /*
+        The last snapshot sent, if there has been one
+     */
+    pub fn latest(&self) -> Option<VramSnapshot> {
+        self.latest.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::PALETTES;
+
+    fn snapshot(lcdc: u8) -> VramSnapshot {
+        VramSnapshot { vram: vec![0; 0x2000], lcdc, scy: 0, scx: 0, wy: 0, wx: 0, bgp: 0xE4, obp0: 0, obp1: 0, colors: PALETTES[0].1 }
+    }
+
+    #[test]
+    fn reads_tile_rows_left_to_right() {
+        let mut vram = snapshot(0x91);
+
+        // Row 2 of tile 1, low bits then high bits
+        vram.vram[16 + 4] = 0b1010_0101;
+        vram.vram[16 + 5] = 0b1100_0011;
+
+        assert_eq!(vram.tile_row(1, 2), [3, 2, 1, 0, 0, 1, 2, 3]);
+        assert_eq!(vram.tile_row(1, 1), [0; 8]);
+
+        // The last row of the last tile
+        vram.vram[TILES * 16 - 2] = 0x01;
+        assert_eq!(vram.tile_row(TILES - 1, 7), [0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn picks_tiles_for_map_indexes() {
+        assert_eq!(map_tile(0x00, true), 0x000);
+        assert_eq!(map_tile(0x7F, true), 0x07F);
+        assert_eq!(map_tile(0x80, true), 0x080);
+        assert_eq!(map_tile(0x00, false), 0x100);
+        assert_eq!(map_tile(0x7F, false), 0x17F);
+        assert_eq!(map_tile(0x80, false), 0x080);
+        assert_eq!(map_tile(0xFF, false), 0x0FF);
+    }
+
+    #[test]
+    fn reads_maps_from_lcdc() {
+        let vram = snapshot(0x91);
+
+        assert_eq!((vram.bg_map(), vram.window_map(), vram.window_on(), vram.unsigned_tiles()), (0x9800, 0x9800, false, true));
+
+        let vram = snapshot(0xE9);
+
+        assert_eq!((vram.bg_map(), vram.window_map(), vram.window_on(), vram.unsigned_tiles()), (0x9C00, 0x9C00, true, false));
+    }
+}
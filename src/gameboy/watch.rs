@@ -0,0 +1,268 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::ops::RangeInclusive;
+
+use crate::gameboy::symbols::Symbols;
+
+/*
+    Watching the CPU's memory accesses, see GameBoy::add_watchpoint and GameBoy::add_memory_hook.
+
+    Only what the CPU does is seen, not the GPU drawing from VRAM or anything reading memory
+    from outside, e.g. the debugger showing it.
+ */
+
+/*
+    Something that wants to see every memory access the CPU makes. pc is the instruction making
+    the access.
+ */
+pub trait MemoryHook: Send {
+    fn read(&mut self, _pc: u16, _addr: u16, _value: u8) {}
+
+    fn write(&mut self, _pc: u16, _addr: u16, _value: u8) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    // The CPU is about to run the instruction at the address
+    Execute,
+}
+
+impl Display for Access {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addrs: RangeInclusive<u16>,
+
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+
+    // Only when this value is read or written, or the opcode executed
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: Access, addr: u16, value: u8) -> bool {
+        let watched = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        };
+
+        watched && self.addrs.contains(&addr) && self.value.is_none_or(|v| v == value)
+    }
+}
+
+impl Display for Watchpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.addrs.start() == self.addrs.end() {
+            write!(f, "{:04X}", self.addrs.start())?;
+        } else {
+            write!(f, "{:04X}-{:04X}", self.addrs.start(), self.addrs.end())?;
+        }
+
+        let accesses: String = [(self.read, 'r'), (self.write, 'w'), (self.execute, 'x')].iter()
+            .filter(|(on, _)| *on).map(|(_, c)| *c).collect();
+
+        write!(f, " {}", accesses)?;
+
+        match self.value {
+            Some(value) => write!(f, " {:02X}", value),
+            None => Ok(()),
+        }
+    }
+}
+
+/*
+    A watchpoint being hit
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    // Its index in GameBoy::watchpoints
+    pub watchpoint: usize,
+    pub access: Access,
+    pub addr: u16,
+    pub value: u8,
+
+    // The instruction responsible, or about to run for an execute
+    pub pc: u16,
+}
+
+#[derive(Default)]
+pub(crate) struct Watch {
+    pub(crate) watchpoints: Vec<Watchpoint>,
+    pub(crate) hooks: Vec<Box<dyn MemoryHook>>,
+
+    // The instruction the CPU is running
+    pub(crate) pc: u16,
+
+    // The first watchpoint hit since the last was taken
+    pub(crate) hit: Option<WatchHit>,
+}
+
+impl Watch {
+    pub(crate) fn access(&mut self, access: Access, addr: u16, value: u8) {
+        for hook in &mut self.hooks {
+            match access {
+                Access::Read => hook.read(self.pc, addr, value),
+                Access::Write => hook.write(self.pc, addr, value),
+                Access::Execute => {}
+            }
+        }
+
+        if self.hit.is_some() {
+            return;
+        }
+
+        if let Some(n) = self.watchpoints.iter().position(|w| w.matches(access, addr, value)) {
+            self.hit = Some(WatchHit { watchpoint: n, access, addr, value, pc: self.pc });
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.watchpoints.is_empty() && self.hooks.is_empty()
+    }
+}
+
+/*
+    An address or range in hex, then which accesses to watch (any of r, w and x, w by default), then
+    optionally the value to watch for in hex. Separated by spaces or commas, e.g. "C000-C0FF rw 3F".
+    Addresses can also be labels from symbols, e.g. "wPlayerHealth w 00".
+ */
+pub fn parse_watchpoint(value: &str, symbols: Option<&Symbols>) -> Result<Watchpoint, String> {
+    let mut words = value.split([' ', ',']).filter(|w| !w.is_empty());
+
+    let addrs = words.next().ok_or("a watchpoint needs an address")?;
+    let addr = |a: &str| symbols.and_then(|s| s.lookup(a)).map(|(_, addr)| addr)
+        .or_else(|| u16::from_str_radix(a.trim_start_matches('$').trim_start_matches("0x"), 16).ok());
+
+    let (from, to) = addrs.split_once('-').unwrap_or((addrs, addrs));
+
+    let addrs = match (addr(from), addr(to)) {
+        (Some(from), Some(to)) if from <= to => from..=to,
+        _ => return Err(format!("invalid address {}", addrs)),
+    };
+
+    let mut watchpoint = Watchpoint { addrs, read: false, write: true, execute: false, value: None };
+
+    if let Some(accesses) = words.next() {
+        if accesses.is_empty() || !accesses.chars().all(|c| "rwx".contains(c)) {
+            return Err(format!("invalid accesses {}, expected any of r, w and x", accesses));
+        }
+
+        watchpoint.read = accesses.contains('r');
+        watchpoint.write = accesses.contains('w');
+        watchpoint.execute = accesses.contains('x');
+    }
+
+    if let Some(value) = words.next() {
+        watchpoint.value = Some(u8::from_str_radix(value.trim_start_matches('$').trim_start_matches("0x"), 16).map_err(|_| format!("invalid value {}", value))?);
+    }
+
+    match words.next() {
+        Some(extra) => Err(format!("unexpected {}", extra)),
+        None => Ok(watchpoint),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::new_test_game_boy;
+    use crate::gameboy::symbols::parse_symbols;
+
+    fn watch(addrs: RangeInclusive<u16>, accesses: &str, value: Option<u8>) -> Watchpoint {
+        Watchpoint { addrs, read: accesses.contains('r'), write: accesses.contains('w'), execute: accesses.contains('x'), value }
+    }
+
+    #[test]
+    fn parses_watchpoints() {
+        assert_eq!(parse_watchpoint("c0a0", None), Ok(watch(0xC0A0..=0xC0A0, "w", None)));
+        assert_eq!(parse_watchpoint("$8000-$97FF rx", None), Ok(watch(0x8000..=0x97FF, "rx", None)));
+        assert_eq!(parse_watchpoint("0xC000,rw,3f", None), Ok(watch(0xC000..=0xC000, "rw", Some(0x3F))));
+        assert_eq!(parse_watchpoint("  ff80  wr  ", None), Ok(watch(0xFF80..=0xFF80, "rw", None)));
+    }
+
+    #[test]
+    fn parses_labels() {
+        let symbols = parse_symbols("00:C0A0 wPlayerHealth\n00:C0B0 wEnd\n").unwrap();
+
+        assert_eq!(parse_watchpoint("wPlayerHealth w 00", Some(&symbols)), Ok(watch(0xC0A0..=0xC0A0, "w", Some(0x00))));
+        assert_eq!(parse_watchpoint("wPlayerHealth-wEnd r", Some(&symbols)), Ok(watch(0xC0A0..=0xC0B0, "r", None)));
+        assert!(parse_watchpoint("wPlayerHealth", None).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_watchpoints() {
+        assert_eq!(parse_watchpoint("", None), Err("a watchpoint needs an address".to_string()));
+        assert_eq!(parse_watchpoint("c0ff-c000", None), Err("invalid address c0ff-c000".to_string()));
+        assert_eq!(parse_watchpoint("c000 rq", None), Err("invalid accesses rq, expected any of r, w and x".to_string()));
+        assert_eq!(parse_watchpoint("c000 w 100", None), Err("invalid value 100".to_string()));
+        assert_eq!(parse_watchpoint("c000 w 00 now", None), Err("unexpected now".to_string()));
+    }
+
+    #[test]
+    fn shows_as_it_was_given() {
+        for text in ["C0A0 w", "8000-97FF rx 3F"] {
+            assert_eq!(parse_watchpoint(text, None).unwrap().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn stops_on_the_value_watched_for() {
+        // LD HL,$C0A0; LD [HL],$05; LD [HL],$00; LD A,[HL]; JR -2
+        let mut gb = new_test_game_boy(&[0x21, 0xA0, 0xC0, 0x36, 0x05, 0x36, 0x00, 0x7E, 0x18, 0xFE], None);
+
+        gb.add_watchpoint(parse_watchpoint("c0a0 w 00", None).unwrap());
+        gb.add_watchpoint(parse_watchpoint("c0a0 r", None).unwrap());
+
+        let hits: Vec<WatchHit> = (0..5).filter_map(|_| {
+            gb.step_instruction();
+            gb.take_watch_hit()
+        }).collect();
+
+        assert_eq!(hits, vec!(
+            WatchHit { watchpoint: 0, access: Access::Write, addr: 0xC0A0, value: 0x00, pc: 0x0105 },
+            WatchHit { watchpoint: 1, access: Access::Read, addr: 0xC0A0, value: 0x00, pc: 0x0107 },
+        ));
+    }
+
+    #[test]
+    fn ignores_interrupt_polling() {
+        // EI; NOP; NOP; JR -2
+        let mut gb = new_test_game_boy(&[0xFB, 0x00, 0x00, 0x18, 0xFE], None);
+        gb.write_byte(0xFFFF, 0x1F);
+
+        gb.add_watchpoint(parse_watchpoint("ffff r", None).unwrap());
+        gb.add_watchpoint(parse_watchpoint("ff0f rw", None).unwrap());
+
+        for _ in 0..10 {
+            gb.step_instruction();
+            assert_eq!(gb.take_watch_hit(), None);
+        }
+    }
+
+    #[test]
+    fn sees_fetches_as_executes() {
+        // NOP; JR -3
+        let mut gb = new_test_game_boy(&[0x00, 0x18, 0xFD], None);
+
+        gb.add_watchpoint(parse_watchpoint("0100 r", None).unwrap());
+        gb.add_watchpoint(parse_watchpoint("0100 x", None).unwrap());
+
+        gb.step_instruction();
+        assert_eq!(gb.take_watch_hit(), None);
+        gb.step_instruction();
+        assert_eq!(gb.take_watch_hit(), Some(WatchHit { watchpoint: 1, access: Access::Execute, addr: 0x0100, value: 0x00, pc: 0x0100 }));
+    }
+}
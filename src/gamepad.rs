@@ -0,0 +1,453 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::config::{Binding, GamepadConfig, Stick};
+use crate::gameboy::keys::Keys;
+
+/*
+    Gamepad input.
+
+    The mapping from controller buttons and sticks to GB buttons is kept apart from the library
+    that talks to the controllers. Anything implementing GamepadDevice can be used, the real one is
+    backed by gilrs (behind the "gamepad" feature) but a fake device can be used to drive it in
+    tests.
+
+    Controllers are assigned to players in the order they connect, and can be plugged in and out
+    while running.
+ */
+
+// Named after the position of the face buttons rather than their labels, as these differ between controllers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftTrigger,
+    LeftTrigger2,
+    RightTrigger,
+    RightTrigger2,
+    Select,
+    Start,
+    Mode,
+    LeftThumb,
+    RightThumb,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+pub const PAD_BUTTON_NAMES: [(&str, PadButton); 17] = [
+    ("South", PadButton::South),
+    ("East", PadButton::East),
+    ("North", PadButton::North),
+    ("West", PadButton::West),
+    ("LeftTrigger", PadButton::LeftTrigger),
+    ("LeftTrigger2", PadButton::LeftTrigger2),
+    ("RightTrigger", PadButton::RightTrigger),
+    ("RightTrigger2", PadButton::RightTrigger2),
+    ("Select", PadButton::Select),
+    ("Start", PadButton::Start),
+    ("Mode", PadButton::Mode),
+    ("LeftThumb", PadButton::LeftThumb),
+    ("RightThumb", PadButton::RightThumb),
+    ("DPadUp", PadButton::DPadUp),
+    ("DPadDown", PadButton::DPadDown),
+    ("DPadLeft", PadButton::DPadLeft),
+    ("DPadRight", PadButton::DPadRight),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PadAxis {
+    LeftStickX,
+    LeftStickY,
+    RightStickX,
+    RightStickY,
+}
+
+/*
+    Controllers are identified by a number that stays the same while they are connected
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum PadEvent {
+    Connected(usize, String),
+    Disconnected(usize),
+    ButtonPressed(usize, PadButton),
+    ButtonReleased(usize, PadButton),
+    // Ranges from -1.0 to 1.0, positive is right or up
+    AxisChanged(usize, PadAxis, f32),
+}
+
+pub trait GamepadDevice {
+    /*
+        Returns the next event that hasn't been handled, or None once they have all been handled
+     */
+    fn next_event(&mut self) -> Option<PadEvent>;
+}
+
+/*
+    A button or hotkey changing state on the GB controlled by player
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PadAction {
+    pub player: usize,
+    pub binding: Binding,
+    pub pressed: bool,
+}
+
+pub struct GamepadInput {
+    device: Box<dyn GamepadDevice>,
+
+    config: GamepadConfig,
+
+    // Controllers in the order they were connected, the first controls player 0 and so on
+    pads: Vec<usize>,
+
+    // The GB directions currently held by each controller's stick
+    stick_held: HashMap<usize, HashSet<Keys>>,
+
+    // The bindings currently held by each controller's buttons
+    buttons_held: HashMap<usize, Vec<(PadButton, Binding)>>,
+
+    // Last seen stick position for each controller
+    stick_pos: HashMap<usize, (f32, f32)>,
+}
+
+pub fn new_gamepad_input(device: Box<dyn GamepadDevice>, config: GamepadConfig) -> GamepadInput {
+    GamepadInput {
+        device,
+        config,
+        pads: vec!(),
+        stick_held: HashMap::new(),
+        buttons_held: HashMap::new(),
+        stick_pos: HashMap::new(),
+    }
+}
+
+/*
+    Opens the real controllers, if gamepad support was built in
+ */
+#[cfg(feature = "gamepad")]
+pub fn open_gamepads(config: GamepadConfig) -> Option<GamepadInput> {
+    match new_gilrs_device() {
+        Ok(device) => Some(new_gamepad_input(Box::new(device), config)),
+        Err(e) => {
+            eprintln!("gamepads unavailable: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "gamepad"))]
+pub fn open_gamepads(_config: GamepadConfig) -> Option<GamepadInput> {
+    None
+}
+
+impl GamepadInput {
+    /*
+        Handles every event since the last poll. players is how many GBs there are to control.
+     */
+    pub fn poll(&mut self, players: usize) -> Vec<PadAction> {
+        let mut actions = vec!();
+
+        while let Some(event) = self.device.next_event() {
+            self.handle_event(event, players, &mut actions);
+        }
+
+        actions
+    }
+
+    fn player_for(&mut self, pad: usize, players: usize) -> usize {
+        // Controllers that were already connected before we started don't always announce themselves
+        if !self.pads.contains(&pad) {
+            self.pads.push(pad);
+        }
+
+        self.pads.iter().position(|&p| p == pad).unwrap() % players.max(1)
+    }
+
+    fn handle_event(&mut self, event: PadEvent, players: usize, actions: &mut Vec<PadAction>) {
+        match event {
+            PadEvent::Connected(pad, name) => {
+                let player = self.player_for(pad, players);
+                println!("Gamepad connected: {} (player {})", name, player + 1);
+            }
+            PadEvent::Disconnected(pad) => {
+                let player = self.player_for(pad, players);
+                let before: Vec<(usize, usize)> = self.pads.clone().into_iter().map(|p| (p, self.player_for(p, players))).collect();
+
+                self.release(pad, player, actions);
+                self.stick_pos.remove(&pad);
+                self.pads.retain(|&p| p != pad);
+
+                // The controllers after it move up a player, and what they held was held on the old one
+                for (other, was) in before {
+                    if other != pad && self.player_for(other, players) != was {
+                        self.release(other, was, actions);
+                    }
+                }
+
+                println!("Gamepad disconnected (player {})", player + 1);
+            }
+            PadEvent::ButtonPressed(pad, button) => {
+                let player = self.player_for(pad, players);
+
+                if let Some(&binding) = self.config.buttons.get(&button) {
+                    let held = self.buttons_held.entry(pad).or_default();
+
+                    if !held.iter().any(|(b, _)| *b == button) {
+                        held.push((button, binding));
+                        actions.push(PadAction { player, binding, pressed: true });
+                    }
+                }
+            }
+            PadEvent::ButtonReleased(pad, button) => {
+                let player = self.player_for(pad, players);
+
+                if let Some(held) = self.buttons_held.get_mut(&pad) {
+                    if let Some(i) = held.iter().position(|(b, _)| *b == button) {
+                        let (_, binding) = held.remove(i);
+                        actions.push(PadAction { player, binding, pressed: false });
+                    }
+                }
+            }
+            PadEvent::AxisChanged(pad, axis, value) => {
+                let player = self.player_for(pad, players);
+
+                let (x, y) = self.stick_pos.get(&pad).copied().unwrap_or((0.0, 0.0));
+
+                let pos = match (self.config.stick, axis) {
+                    (Some(Stick::Left), PadAxis::LeftStickX) | (Some(Stick::Right), PadAxis::RightStickX) => (value, y),
+                    (Some(Stick::Left), PadAxis::LeftStickY) | (Some(Stick::Right), PadAxis::RightStickY) => (x, value),
+                    _ => return,
+                };
+
+                self.stick_pos.insert(pad, pos);
+
+                self.update_stick(pad, player, pos, actions);
+            }
+        }
+    }
+
+    /*
+        Lets go of everything the controller is holding on player's GB, so nothing sticks down
+     */
+    fn release(&mut self, pad: usize, player: usize, actions: &mut Vec<PadAction>) {
+        for (_, binding) in self.buttons_held.remove(&pad).unwrap_or_default() {
+            actions.push(PadAction { player, binding, pressed: false });
+        }
+
+        for key in self.stick_held.remove(&pad).unwrap_or_default() {
+            actions.push(PadAction { player, binding: Binding::Button(key), pressed: false });
+        }
+    }
+
+    /*
+        Converts the stick position into D-pad directions, ignoring anything inside the deadzone
+     */
+    fn update_stick(&mut self, pad: usize, player: usize, (x, y): (f32, f32), actions: &mut Vec<PadAction>) {
+        let deadzone = self.config.deadzone;
+
+        let mut now = HashSet::new();
+
+        if x > deadzone {
+            now.insert(Keys::RIGHT);
+        } else if x < -deadzone {
+            now.insert(Keys::LEFT);
+        }
+
+        if y > deadzone {
+            now.insert(Keys::UP);
+        } else if y < -deadzone {
+            now.insert(Keys::DOWN);
+        }
+
+        let held = self.stick_held.entry(pad).or_default();
+
+        for &key in held.difference(&now) {
+            actions.push(PadAction { player, binding: Binding::Button(key), pressed: false });
+        }
+
+        for &key in now.difference(held) {
+            actions.push(PadAction { player, binding: Binding::Button(key), pressed: true });
+        }
+
+        *held = now;
+    }
+}
+
+#[cfg(feature = "gamepad")]
+use gilrs_device::new_gilrs_device;
+
+#[cfg(feature = "gamepad")]
+mod gilrs_device {
+    use gilrs::{Axis, Button, EventType, Gilrs};
+
+    use crate::gamepad::{GamepadDevice, PadAxis, PadButton, PadEvent};
+
+    pub struct GilrsDevice {
+        gilrs: Gilrs,
+    }
+
+    pub fn new_gilrs_device() -> Result<GilrsDevice, Box<dyn std::error::Error>> {
+        Ok(GilrsDevice { gilrs: Gilrs::new()? })
+    }
+
+    impl GamepadDevice for GilrsDevice {
+        fn next_event(&mut self) -> Option<PadEvent> {
+            // Skip over events that aren't relevant, only returning None once there are none left
+            while let Some(event) = self.gilrs.next_event() {
+                let pad = usize::from(event.id);
+
+                let mapped = match event.event {
+                    EventType::Connected => Some(PadEvent::Connected(pad, self.gilrs.gamepad(event.id).name().to_string())),
+                    EventType::Disconnected => Some(PadEvent::Disconnected(pad)),
+                    EventType::ButtonPressed(button, _) => map_button(button).map(|b| PadEvent::ButtonPressed(pad, b)),
+                    EventType::ButtonReleased(button, _) => map_button(button).map(|b| PadEvent::ButtonReleased(pad, b)),
+                    EventType::AxisChanged(axis, value, _) => map_axis(axis).map(|a| PadEvent::AxisChanged(pad, a, value)),
+                    _ => None,
+                };
+
+                if mapped.is_some() {
+                    return mapped;
+                }
+            }
+
+            None
+        }
+    }
+
+    fn map_button(button: Button) -> Option<PadButton> {
+        match button {
+            Button::South => Some(PadButton::South),
+            Button::East => Some(PadButton::East),
+            Button::North => Some(PadButton::North),
+            Button::West => Some(PadButton::West),
+            Button::LeftTrigger => Some(PadButton::LeftTrigger),
+            Button::LeftTrigger2 => Some(PadButton::LeftTrigger2),
+            Button::RightTrigger => Some(PadButton::RightTrigger),
+            Button::RightTrigger2 => Some(PadButton::RightTrigger2),
+            Button::Select => Some(PadButton::Select),
+            Button::Start => Some(PadButton::Start),
+            Button::Mode => Some(PadButton::Mode),
+            Button::LeftThumb => Some(PadButton::LeftThumb),
+            Button::RightThumb => Some(PadButton::RightThumb),
+            Button::DPadUp => Some(PadButton::DPadUp),
+            Button::DPadDown => Some(PadButton::DPadDown),
+            Button::DPadLeft => Some(PadButton::DPadLeft),
+            Button::DPadRight => Some(PadButton::DPadRight),
+            _ => None,
+        }
+    }
+
+    fn map_axis(axis: Axis) -> Option<PadAxis> {
+        match axis {
+            Axis::LeftStickX => Some(PadAxis::LeftStickX),
+            Axis::LeftStickY => Some(PadAxis::LeftStickY),
+            Axis::RightStickX => Some(PadAxis::RightStickX),
+            Axis::RightStickY => Some(PadAxis::RightStickY),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::config::{default_config, Hotkey};
+
+    /*
+        Hands out its events a batch at a time, one batch per poll
+     */
+    struct ScriptedDevice {
+        batches: VecDeque<VecDeque<PadEvent>>,
+    }
+
+    impl GamepadDevice for ScriptedDevice {
+        fn next_event(&mut self) -> Option<PadEvent> {
+            let event = self.batches.front_mut()?.pop_front();
+
+            if event.is_none() {
+                self.batches.pop_front();
+            }
+
+            event
+        }
+    }
+
+    fn scripted(batches: Vec<Vec<PadEvent>>) -> GamepadInput {
+        let device = ScriptedDevice { batches: batches.into_iter().map(VecDeque::from).collect() };
+
+        new_gamepad_input(Box::new(device), default_config().gamepad)
+    }
+
+    fn button(player: usize, key: Keys, pressed: bool) -> PadAction {
+        PadAction { player, binding: Binding::Button(key), pressed }
+    }
+
+    #[test]
+    fn maps_buttons() {
+        let mut input = scripted(vec!(
+            vec!(PadEvent::Connected(7, "pad".to_string()), PadEvent::ButtonPressed(7, PadButton::East), PadEvent::ButtonPressed(7, PadButton::Mode)),
+            vec!(PadEvent::ButtonReleased(7, PadButton::East), PadEvent::ButtonPressed(7, PadButton::North)),
+        ));
+
+        assert_eq!(input.poll(1), vec!(button(0, Keys::A, true)));
+        assert_eq!(input.poll(1), vec!(
+            button(0, Keys::A, false),
+            PadAction { player: 0, binding: Binding::Turbo(Keys::A), pressed: true },
+        ));
+        assert_eq!(input.poll(1), vec!());
+    }
+
+    #[test]
+    fn maps_hotkeys() {
+        let mut input = scripted(vec!(
+            vec!(PadEvent::ButtonPressed(0, PadButton::RightTrigger2)),
+            vec!(PadEvent::ButtonReleased(0, PadButton::RightTrigger2)),
+        ));
+
+        assert_eq!(input.poll(1), vec!(PadAction { player: 0, binding: Binding::Hotkey(Hotkey::FastForward), pressed: true }));
+        assert_eq!(input.poll(1), vec!(PadAction { player: 0, binding: Binding::Hotkey(Hotkey::FastForward), pressed: false }));
+    }
+
+    #[test]
+    fn ignores_the_stick_inside_the_deadzone() {
+        let mut input = scripted(vec!(
+            vec!(PadEvent::AxisChanged(0, PadAxis::LeftStickX, 0.3)),
+            vec!(PadEvent::AxisChanged(0, PadAxis::LeftStickX, 0.9), PadEvent::AxisChanged(0, PadAxis::RightStickY, 0.9)),
+            vec!(PadEvent::AxisChanged(0, PadAxis::LeftStickX, -0.2)),
+        ));
+
+        assert_eq!(input.poll(1), vec!());
+        assert_eq!(input.poll(1), vec!(button(0, Keys::RIGHT, true)));
+        assert_eq!(input.poll(1), vec!(button(0, Keys::RIGHT, false)));
+    }
+
+    #[test]
+    fn assigns_players_as_pads_come_and_go() {
+        let mut input = scripted(vec!(
+            vec!(
+                PadEvent::Connected(3, "first".to_string()),
+                PadEvent::Connected(5, "second".to_string()),
+                PadEvent::ButtonPressed(3, PadButton::East),
+                PadEvent::ButtonPressed(5, PadButton::South),
+                PadEvent::AxisChanged(5, PadAxis::LeftStickY, -1.0),
+            ),
+            vec!(PadEvent::Disconnected(3)),
+            vec!(PadEvent::ButtonPressed(5, PadButton::Start)),
+            vec!(PadEvent::ButtonPressed(9, PadButton::Start)),
+        ));
+
+        assert_eq!(input.poll(2), vec!(button(0, Keys::A, true), button(1, Keys::B, true), button(1, Keys::DOWN, true)));
+
+        // The second pad takes over player 0, letting go of everything it held on player 1
+        assert_eq!(input.poll(2), vec!(button(0, Keys::A, false), button(1, Keys::B, false), button(1, Keys::DOWN, false)));
+        assert_eq!(input.poll(2), vec!(button(0, Keys::START, true)));
+
+        // A pad that never announced itself is given the next player
+        assert_eq!(input.poll(2), vec!(button(1, Keys::START, true)));
+    }
+}
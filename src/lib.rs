@@ -0,0 +1,21 @@
+/*
+    The emulator core, shared by the windowed emulator and the headless runner.
+
+    A GB is driven a frame (or an instruction) at a time:
+
+        let mut game_boy = new_game_boy(cart, GameBoyConfig::default());
+
+        loop {
+            game_boy.set_buttons(pressed);
+            game_boy.run_frame();
+            show(game_boy.framebuffer());
+        }
+
+    Buttons are one bit each, see gameboy::keys::key_bit. The framebuffer is 160x144, 3 bytes per
+    pixel (RGB). Nothing in the core needs a window, start_game_boy is just one way of running it.
+ */
+pub mod gameboy;
+
+pub use gameboy::{GameBoy, GameBoyConfig, new_game_boy, Registers, CYCLES_PER_FRAME};
+pub use gameboy::cartridge::{Cartridge, new_cartridge_from_bytes, new_cartridge_from_file};
+pub use gameboy::state::StateError;
@@ -1,23 +1,50 @@
 extern crate core;
 
+use std::{env, io, process};
+use std::io::Write;
+use std::error::Error;
+use std::fs::File;
 use std::sync::Arc;
+use std::sync::mpsc;
 use std::thread;
 
 use speedy2d::dimen::Vector2;
 use speedy2d::Window;
 use speedy2d::window::{WindowCreationOptions, WindowSize};
 
-use gameboy::cartridge::new_cartridge_from_file;
-use gameboy::start_game_boy;
-use crate::gameboy::cartridge::new_cartridge_from_url;
+use gameboy::{GameBoyConfig, RunOptions, start_game_boy};
+use crate::cli::{Args, CliError, DisasmArgs, LinkArg, MovieArg, parse_args, parse_disasm_args};
+use crate::gameboy::boot::{Boot, load_boot_rom, Model};
+use crate::gameboy::cartridge::{Cartridge, new_cartridge_from_file};
+use crate::gameboy::frame::{FrameSink, new_file_sink};
+use crate::gameboy::printer::new_printer;
+use crate::gameboy::linked::{SCREEN_HEIGHT, SCREEN_WIDTH, start_linked_game_boys};
+use crate::gameboy::link::{new_tcp_link_connect, new_tcp_link_listen};
+use crate::gameboy::serial::LinkPartner;
+use crate::gameboy::movie::{load_movie, Movie, new_movie_recorder};
+use crate::gameboy::symbols::{find_symbols, load_symbols, Symbols};
+use crate::gameboy::trace::new_tracer;
+use crate::gameboy::disasm::{db, disassemble_bytes};
+use crate::gameboy::debugger::{Debugger, new_stdin_debugger, parse_breakpoint};
+use crate::gameboy::watch::parse_watchpoint;
+use crate::gameboy::gdb::new_gdb_stub_listen;
+use crate::gameboy::vram::new_vram_share;
 
-use crate::gameboy::keys::new_key_reg;
+use crate::config::{Config, load_config};
+use crate::gameboy::input::new_input;
 use crate::window::{new_gb_window_handler};
 
+use rusty_gigabyte::gameboy;
+
 mod window;
-mod gameboy;
+mod viewer;
+mod config;
+mod cli;
+// Without the gamepad feature there is no device to drive the input mapping
+#[cfg_attr(not(feature = "gamepad"), allow(dead_code))]
+mod gamepad;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() {
     /*
         cpu_instrs test status
         - 01-special.gb - PASSED
@@ -33,26 +60,323 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         - 11-op a,(hl).gb - PASSED
      */
 
-    // let cart = new_cartridge_from_file("roms/cpu_instrs/individual/02-interrupts.gb")?;
-    // let cart = new_cartridge_from_file("roms/ttt.gb")?;
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.first().is_some_and(|arg| arg == "disasm") {
+        return match parse_disasm_args(args[1..].to_vec()) {
+            Ok(args) => if let Err(e) = disasm(&args) {
+                eprintln!("error: {}", e);
+                process::exit(1);
+            },
+            Err(CliError::Help) => println!("{}", cli::DISASM_USAGE),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(2);
+            }
+        };
+    }
+
+    let args = match parse_args(args) {
+        Ok(args) => args,
+        Err(CliError::Help) => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(args) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let config = load_config(args.config.as_deref())?;
+
+    if matches!(args.model, Model::Sgb | Model::Cgb) {
+        eprintln!("warning: only the DMG is emulated so far, the {} only changes how it boots", args.model);
+    }
+
+    let cart = load_rom(&args.rom)?;
+
+    let mut sinks: Vec<Box<dyn FrameSink>> = vec!();
+
+    if let Some(path) = &args.dump_frames {
+        sinks.push(Box::new(new_file_sink(path).map_err(|e| format!("unable to create {}: {}", path, e))?));
+    }
+
+    let boot = boot_from_args(&args)?;
+
+    if args.linked {
+        return start_linked(cart, boot, config, args, sinks);
+    }
+
+    let trace = match &args.trace {
+        Some(path) => {
+            let file = File::create(path).map_err(|e| format!("unable to create trace {}: {}", path, e))?;
+            Some(new_tracer(Box::new(file), args.trace_config.clone()))
+        }
+        None => None,
+    };
+
+    let link = open_link(&args.link)?;
+
+    let input = Arc::new(new_input(config.turbo_rate));
+
+    let input_clone = input.clone();
+
+    let (command_sender, commands) = mpsc::channel();
+
+    let symbols = load_symbols_for(&args.sym, &args.rom)?;
 
-    let cart = new_cartridge_from_url("http://imrannazar.com/stuff/software/jsgb/tests/tetris.gb")?;
+    let debugger = new_debugger_from_args(&args, symbols.as_ref())?;
 
-    let key_reg = Arc::new(new_key_reg());
+    let gb_config = GameBoyConfig {
+        boot,
+        palette: Some(args.palette),
+        link,
+        debug: args.debug,
+        symbols,
+    };
 
-    let key_reg_clone = key_reg.clone();
+    let movie = match &args.movie {
+        Some(MovieArg::Record(path)) => {
+            println!("Recording movie to {}", path);
+            Some(Movie::Record(new_movie_recorder(path, &cart, &gb_config)?))
+        }
+        Some(MovieArg::Play(path)) => Some(Movie::Play(load_movie(path, &cart, &gb_config)?)),
+        None => None,
+    };
 
-    let window = Window::<Vec<u8>>::new_with_user_events("Rusty GB", WindowCreationOptions::new_windowed(WindowSize::ScaledPixels(Vector2::from((160.0, 144.0))), None))?;
+    let gdb = match &args.gdb {
+        Some(addr) => {
+            println!("Waiting for gdb on {}", addr);
+            Some(new_gdb_stub_listen(addr)?)
+        }
+        None => None,
+    };
+
+    // Filled in by the GB while a viewer is open, there's nothing to view when headless
+    let vram = (!args.headless).then(new_vram_share);
+
+    let options = RunOptions {
+        movie,
+        rewind: config.rewind,
+        speed: config.speed,
+        save_dir: args.save_dir,
+        frames: args.frames,
+        screenshot: args.screenshot,
+        unpaced: args.headless,
+        trace,
+        debugger,
+        gdb,
+        vram: vram.clone(),
+    };
+
+    if args.headless {
+        start_game_boy(cart, gb_config, sinks, input, options, commands);
+        return Ok(());
+    }
+
+    let size = (160 * args.scale, 144 * args.scale);
+
+    let window = Window::<Vec<u8>>::new_with_user_events("Rusty GB", WindowCreationOptions::new_windowed(WindowSize::ScaledPixels(Vector2::from((size.0 as f32, size.1 as f32))), None))?;
 
     // Window needs to run on the main thread.
-    let image_sender = window.create_user_event_sender();
+    sinks.push(Box::new(window.create_user_event_sender()));
 
     // spawn a thread for the gameboy
     thread::spawn(move || {
-        start_game_boy(cart, image_sender, key_reg_clone);
+        start_game_boy(cart, gb_config, sinks, input_clone, options, commands);
+
+        // Only returns once the requested frames have run, and the window can't be closed from here
+        process::exit(0);
     });
 
-    window.run_loop(new_gb_window_handler(key_reg));
+    window.run_loop(new_gb_window_handler(vec![input], (160, 144), config, Some(command_sender), vram));
+}
+
+/*
+    How the GB starts up, from --model, --boot-rom and --skip-boot
+ */
+fn boot_from_args(args: &Args) -> Result<Option<Boot>, Box<dyn Error>> {
+    Ok(match &args.boot_rom {
+        Some(path) => Some(Boot::Rom(load_boot_rom(path, args.model)?)),
+        None if args.skip_boot => Some(Boot::Skip(args.model)),
+        None if args.model != Model::Dmg => return Err(format!("there's no built in {} boot ROM, use --boot-rom or --skip-boot", args.model).into()),
+        None => None,
+    })
+}
+
+/*
+    A debugger reading from the terminal if --debug, --break or --watch was given
+ */
+fn new_debugger_from_args(args: &Args, symbols: Option<&Symbols>) -> Result<Option<Debugger>, String> {
+    if !args.debugger && args.breakpoints.is_empty() && args.watchpoints.is_empty() {
+        return Ok(None);
+    }
+
+    let mut debugger = new_stdin_debugger();
+
+    for breakpoint in &args.breakpoints {
+        debugger.add_breakpoint(parse_breakpoint(breakpoint, symbols).map_err(|e| format!("invalid --break {}: {}", breakpoint, e))?);
+    }
+
+    for watchpoint in &args.watchpoints {
+        debugger.add_watchpoint(parse_watchpoint(watchpoint, symbols).map_err(|e| format!("invalid --watch {}: {}", watchpoint, e))?);
+    }
+
+    if !args.debugger {
+        debugger.resume();
+    }
+
+    Ok(Some(debugger))
+}
+
+/*
+    Prints the ROM's banks as RGBDS sections, with where each instruction is and its bytes after it
+ */
+fn disasm(args: &DisasmArgs) -> Result<(), Box<dyn Error>> {
+    let cart = load_rom(&args.rom)?;
+    let symbols = load_symbols_for(&args.sym, &args.rom)?;
+
+    let banks = match args.bank {
+        Some(bank) if bank >= cart.rom_banks() => return Err(format!("there's no bank {}, {} has {} banks", bank, args.rom, cart.rom_banks()).into()),
+        Some(bank) => bank..bank + 1,
+        None => 0..cart.rom_banks(),
+    };
+
+    let mut out = io::BufWriter::new(io::stdout().lock());
+
+    for bank in banks {
+        let data = cart.rom_bank(bank).unwrap();
+        let base = if bank == 0 { 0x0000 } else { 0x4000 };
+
+        if bank == 0 {
+            writeln!(out, "SECTION \"ROM Bank $000\", ROM0[$0000]")?;
+        } else {
+            writeln!(out, "\nSECTION \"ROM Bank ${:03X}\", ROMX[$4000], BANK[${:X}]", bank, bank)?;
+        }
+
+        let mut offset = 0;
+        let bank_n = bank as u16;
+
+        while offset < data.len() {
+            let addr = base + offset as u16;
+            let mut instruction = disassemble_bytes(&data[offset..], addr);
+
+            let end = symbols.as_ref().and_then(|s| s.next_label(bank_n, addr)).map_or(data.len(), |next| (next - base) as usize);
+
+            // Cut off by the end of the bank, or by the next label so that code after data lines up
+            if offset + instruction.length as usize > end.min(data.len()) {
+                instruction = db(data[offset]);
+            }
+
+            if let Some(symbols) = &symbols {
+                if let Some(label) = symbols.label(bank_n, addr) {
+                    writeln!(out, "{}:", label)?;
+                }
+
+                instruction.operands = symbols.label_operands(bank_n, &instruction.operands);
+            }
+
+            let length = instruction.length as usize;
+            let bytes: Vec<String> = data[offset..offset + length].iter().map(|b| format!("{:02X}", b)).collect();
+
+            writeln!(out, "    {:<24}; {:02X}:{:04X}  {}", instruction.to_string(), bank, addr, bytes.join(" "))?;
+
+            offset += length;
+        }
+    }
+
+    out.flush()?;
 
     Ok(())
 }
+
+/*
+    The symbols given with --sym, or the .sym next to the ROM if there is one
+ */
+fn load_symbols_for(sym: &Option<String>, rom: &str) -> Result<Option<Symbols>, Box<dyn Error>> {
+    match sym.clone().or_else(|| find_symbols(rom)) {
+        Some(path) => Ok(Some(load_symbols(&path)?)),
+        None => Ok(None),
+    }
+}
+
+/*
+    Downloads the ROM if given a URL, otherwise reads it from a file
+ */
+fn load_rom(rom: &str) -> Result<Cartridge, Box<dyn Error>> {
+    let cart = if rom.starts_with("http://") || rom.starts_with("https://") {
+        download_rom(rom)
+    } else {
+        new_cartridge_from_file(rom).map_err(|e| e.into())
+    };
+
+    cart.map_err(|e| format!("unable to load ROM {}: {}", rom, e).into())
+}
+
+#[cfg(feature = "download")]
+fn download_rom(url: &str) -> Result<Cartridge, Box<dyn Error>> {
+    gameboy::cartridge::new_cartridge_from_url(url)
+}
+
+#[cfg(not(feature = "download"))]
+fn download_rom(_url: &str) -> Result<Cartridge, Box<dyn Error>> {
+    Err("built without the download feature, so ROMs can't be loaded from a URL".into())
+}
+
+/*
+    Runs two GBs linked together in this process, shown side by side. The next_player hotkey
+    switches which one the keyboard controls. Both start up the same way and use the same palette.
+ */
+fn start_linked(cart: Cartridge, boot: Option<Boot>, config: Config, args: Args, mut sinks: Vec<Box<dyn FrameSink>>) -> Result<(), Box<dyn Error>> {
+    let gb_config = || GameBoyConfig { boot: boot.clone(), palette: Some(args.palette), debug: args.debug, ..Default::default() };
+    let configs = [gb_config(), gb_config()];
+
+    let inputs = [Arc::new(new_input(config.turbo_rate)), Arc::new(new_input(config.turbo_rate))];
+
+    let inputs_clone = inputs.clone();
+
+    let size = ((SCREEN_WIDTH as u32 * args.scale) as f32, (SCREEN_HEIGHT as u32 * args.scale) as f32);
+
+    let window = Window::<Vec<u8>>::new_with_user_events("Rusty GB - Linked", WindowCreationOptions::new_windowed(WindowSize::ScaledPixels(Vector2::from(size)), None))?;
+
+    sinks.push(Box::new(window.create_user_event_sender()));
+
+    thread::spawn(move || {
+        start_linked_game_boys([cart.clone(), cart], configs, sinks, inputs_clone, args.frames, args.screenshot);
+
+        // Only returns once the requested frames have run
+        process::exit(0);
+    });
+
+    window.run_loop(new_gb_window_handler(inputs.to_vec(), (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32), config, None, None));
+}
+
+/*
+    Connects the link cable to another emulator if requested, one side listens and the other
+    connects. Or plugs in a Game Boy Printer which writes its prints to a directory.
+ */
+fn open_link(link: &Option<LinkArg>) -> Result<Option<Box<dyn LinkPartner>>, Box<dyn Error>> {
+    Ok(match link {
+        Some(LinkArg::Listen(addr)) => {
+            println!("Waiting for link partner on {}", addr);
+            Some(Box::new(new_tcp_link_listen(addr)?))
+        }
+        Some(LinkArg::Connect(addr)) => {
+            println!("Connecting to link partner at {}", addr);
+            Some(Box::new(new_tcp_link_connect(addr)?))
+        }
+        Some(LinkArg::Printer(dir)) => {
+            println!("Printing to {}", dir);
+            Some(Box::new(new_printer(dir)?))
+        }
+        None => None,
+    })
+}
@@ -0,0 +1,497 @@
+use speedy2d::dimen::UVec2;
+
+use rusty_gigabyte::gameboy::Palette;
+use rusty_gigabyte::gameboy::vram::{map_tile, TILES, VRAM_START, VramSnapshot};
+
+/*
+    Panels drawn next to the screen showing what's in VRAM. speedy2d only gives us the one window,
+    so they share it with the screen (see window::GBWindowHandler) and whatever the mouse is over
+    is described in the window's title.
+ */
+pub trait Viewer {
+    /*
+        In GB pixels, which are scaled up along with the screen
+     */
+    fn size(&self) -> UVec2;
+
+    /*
+        RGB, row by row from the top left
+     */
+    fn render(&self, vram: &VramSnapshot) -> Vec<u8>;
+
+    /*
+        Called as the mouse moves over the viewer, with None once it leaves. Returns a description
+        of what it's over.
+     */
+    fn hover(&mut self, vram: &VramSnapshot, pos: Option<UVec2>) -> Option<String>;
+
+    /*
+        Called when the viewer is clicked, returns a description of what was clicked
+     */
+    fn click(&mut self, _vram: &VramSnapshot, _pos: UVec2) -> Option<String> {
+        None
+    }
+
+    /*
+        Moves on to the next palette, returning its name
+     */
+    fn next_palette(&mut self) -> Option<&'static str> {
+        None
+    }
+
+    /*
+        Moves on to the next map, returning a description of it
+     */
+    fn next_map(&mut self) -> Option<String> {
+        None
+    }
+}
+
+// The lines between tiles
+const GRID: [u8; 3] = [64, 64, 64];
+
+// Around the tile under the mouse, or the one clicked
+const HIGHLIGHT: [u8; 3] = [255, 0, 0];
+
+// Around the part of the BG on screen
+const VIEWPORT: [u8; 3] = [0, 200, 0];
+
+// Around the part of the screen the window covers
+const WINDOW: [u8; 3] = [0, 128, 255];
+
+/*
+    Somewhere to draw a viewer, 3 bytes per pixel like the frames
+ */
+struct Canvas {
+    size: UVec2,
+    pixels: Vec<u8>,
+}
+
+fn new_canvas(size: UVec2, color: [u8; 3]) -> Canvas {
+    Canvas { size, pixels: color.repeat((size.x * size.y) as usize) }
+}
+
+impl Canvas {
+    /*
+        Wraps around at the edges, like the BG does
+     */
+    fn set(&mut self, x: u32, y: u32, color: [u8; 3]) {
+        let i = (((y % self.size.y) * self.size.x + x % self.size.x) * 3) as usize;
+
+        self.pixels[i..i + 3].copy_from_slice(&color);
+    }
+
+    /*
+        Draws a tile's 8x8 pixels with their top left at x, y
+     */
+    fn tile(&mut self, vram: &VramSnapshot, tile: usize, x: u32, y: u32, colors: &Palette) {
+        for row in 0..8 {
+            for (col, n) in vram.tile_row(tile, row).iter().enumerate() {
+                self.set(x + col as u32, y + row as u32, colors[*n as usize]);
+            }
+        }
+    }
+
+    fn outline(&mut self, x: u32, y: u32, w: u32, h: u32, color: [u8; 3]) {
+        for i in 0..w {
+            self.set(x + i, y, color);
+            self.set(x + i, y + h - 1, color);
+        }
+
+        for i in 0..h {
+            self.set(x, y + i, color);
+            self.set(x + w - 1, y + i, color);
+        }
+    }
+}
+
+/*
+    Which palette register tiles are drawn through, or none to see the colour numbers as they are
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TilePalette {
+    Bgp,
+    Obp0,
+    Obp1,
+    None,
+}
+
+impl TilePalette {
+    fn next(self) -> TilePalette {
+        match self {
+            TilePalette::Bgp => TilePalette::Obp0,
+            TilePalette::Obp0 => TilePalette::Obp1,
+            TilePalette::Obp1 => TilePalette::None,
+            TilePalette::None => TilePalette::Bgp,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TilePalette::Bgp => "BGP",
+            TilePalette::Obp0 => "OBP0",
+            TilePalette::Obp1 => "OBP1",
+            TilePalette::None => "no palette",
+        }
+    }
+
+    fn colors(self, vram: &VramSnapshot) -> Palette {
+        match self {
+            TilePalette::Bgp => vram.palette(vram.bgp),
+            TilePalette::Obp0 => vram.palette(vram.obp0),
+            TilePalette::Obp1 => vram.palette(vram.obp1),
+            // Each colour number as its own shade
+            TilePalette::None => vram.palette(0b11100100),
+        }
+    }
+}
+
+// Tiles across the tile viewer, which makes the three blocks of 128 tiles 8 rows each
+const TILE_COLUMNS: usize = 16;
+
+/*
+    All 384 tiles in VRAM's tile data, in order from 0x8000, with a line between each
+ */
+pub struct TileViewer {
+    palette: TilePalette,
+
+    // The tile under the mouse
+    hovered: Option<usize>,
+}
+
+pub fn new_tile_viewer() -> TileViewer {
+    TileViewer { palette: TilePalette::Bgp, hovered: None }
+}
+
+/*
+    Where a tile's top left pixel is in the tile viewer
+ */
+fn tile_origin(tile: usize) -> (u32, u32) {
+    ((tile % TILE_COLUMNS * 9 + 1) as u32, (tile / TILE_COLUMNS * 9 + 1) as u32)
+}
+
+/*
+    Where the tile is and how it's picked from a map, e.g. tiles 0x100-0x17F are only used by the
+    BG and window, as 0x00-0x7F when LCDC bit 4 is clear
+ */
+fn describe_tile(tile: usize) -> String {
+    let addr = VRAM_START as usize + tile * 16;
+
+    let index = match tile {
+        0x00..=0x7F => format!("${:02X} for sprites, or the BG and window with LCDC.4 set", tile),
+        0x80..=0xFF => format!("${:02X} for sprites, the BG and window", tile),
+        _ => format!("${:02X} for the BG and window with LCDC.4 clear", tile - 0x100),
+    };
+
+    format!("tile ${:03X} at ${:04X}, index {}", tile, addr, index)
+}
+
+impl Viewer for TileViewer {
+    fn size(&self) -> UVec2 {
+        UVec2::new((TILE_COLUMNS * 9 + 1) as u32, (TILES / TILE_COLUMNS * 9 + 1) as u32)
+    }
+
+    fn render(&self, vram: &VramSnapshot) -> Vec<u8> {
+        let mut canvas = new_canvas(self.size(), GRID);
+        let colors = self.palette.colors(vram);
+
+        for tile in 0..TILES {
+            let (x, y) = tile_origin(tile);
+
+            canvas.tile(vram, tile, x, y, &colors);
+        }
+
+        if let Some(tile) = self.hovered {
+            let (x, y) = tile_origin(tile);
+
+            canvas.outline(x - 1, y - 1, 10, 10, HIGHLIGHT);
+        }
+
+        canvas.pixels
+    }
+
+    fn hover(&mut self, _vram: &VramSnapshot, pos: Option<UVec2>) -> Option<String> {
+        self.hovered = pos.map(|pos| (pos.y.saturating_sub(1) / 9) as usize * TILE_COLUMNS + (pos.x.saturating_sub(1) / 9) as usize)
+            .filter(|tile| *tile < TILES);
+
+        self.hovered.map(describe_tile)
+    }
+
+    fn next_palette(&mut self) -> Option<&'static str> {
+        self.palette = self.palette.next();
+
+        Some(self.palette.name())
+    }
+}
+
+/*
+    Which map the map viewer shows and whether its indexes are unsigned, with None following LCDC
+ */
+const MAP_CHOICES: [(Option<u16>, Option<bool>); 5] = [
+    (None, None),
+    (Some(0x9800), Some(true)),
+    (Some(0x9800), Some(false)),
+    (Some(0x9C00), Some(true)),
+    (Some(0x9C00), Some(false)),
+];
+
+/*
+    A whole 32x32 tile map, 256x256 pixels. Where the BG is on screen (SCX and SCY) is outlined, and
+    where the window covers the screen.
+ */
+pub struct MapViewer {
+    // Into MAP_CHOICES
+    choice: usize,
+
+    palette: TilePalette,
+
+    // The column and row of the tile last clicked
+    selected: Option<(u16, u16)>,
+}
+
+pub fn new_map_viewer() -> MapViewer {
+    MapViewer { choice: 0, palette: TilePalette::Bgp, selected: None }
+}
+
+/*
+    Where the window is on screen as x, y, width and height, if it's on and not off the edge. WX is
+    7 more than its left edge.
+ */
+fn window_rect(vram: &VramSnapshot) -> Option<(u32, u32, u32, u32)> {
+    let (x, y) = (vram.wx.saturating_sub(7) as u32, vram.wy as u32);
+
+    (vram.window_on() && x < 160 && y < 144).then(|| (x, y, 160 - x, 144 - y))
+}
+
+fn map_tile_at(pos: UVec2) -> (u16, u16) {
+    ((pos.x / 8) as u16, (pos.y / 8) as u16)
+}
+
+impl MapViewer {
+    /*
+        The map shown and whether its indexes are unsigned
+     */
+    fn map(&self, vram: &VramSnapshot) -> (u16, bool) {
+        let (map, unsigned) = MAP_CHOICES[self.choice];
+
+        (map.unwrap_or(vram.bg_map()), unsigned.unwrap_or(vram.unsigned_tiles()))
+    }
+
+    /*
+        Where a tile in the map is, what it picks and where from
+     */
+    fn describe(&self, vram: &VramSnapshot, (col, row): (u16, u16)) -> String {
+        let (map, unsigned) = self.map(vram);
+        let addr = map + row * 32 + col;
+        let index = vram.byte(addr);
+        let tile = map_tile(index, unsigned);
+
+        // The CGB keeps them in VRAM bank 1 at the same address
+        format!("map ${:04X} ({}, {}), index ${:02X}, tile ${:03X} at ${:04X}, no attributes without CGB VRAM bank 1",
+                addr, col, row, index, tile, VRAM_START as usize + tile * 16)
+    }
+}
+
+impl Viewer for MapViewer {
+    fn size(&self) -> UVec2 {
+        UVec2::new(256, 256)
+    }
+
+    fn render(&self, vram: &VramSnapshot) -> Vec<u8> {
+        let mut canvas = new_canvas(self.size(), GRID);
+        let colors = self.palette.colors(vram);
+        let (map, unsigned) = self.map(vram);
+
+        for row in 0..32 {
+            for col in 0..32 {
+                let tile = map_tile(vram.byte(map + row * 32 + col), unsigned);
+
+                canvas.tile(vram, tile, col as u32 * 8, row as u32 * 8, &colors);
+            }
+        }
+
+        let (scx, scy) = (vram.scx as u32, vram.scy as u32);
+
+        if map == vram.bg_map() {
+            canvas.outline(scx, scy, 160, 144, VIEWPORT);
+
+            if let Some((x, y, w, h)) = window_rect(vram) {
+                canvas.outline(scx + x, scy + y, w, h, WINDOW);
+            }
+        }
+
+        // The window always starts from the top left of its map
+        if let Some((_, _, w, h)) = window_rect(vram).filter(|_| map == vram.window_map()) {
+            canvas.outline(0, 0, w, h, WINDOW);
+        }
+
+        if let Some((col, row)) = self.selected {
+            canvas.outline(col as u32 * 8, row as u32 * 8, 8, 8, HIGHLIGHT);
+        }
+
+        canvas.pixels
+    }
+
+    fn hover(&mut self, vram: &VramSnapshot, pos: Option<UVec2>) -> Option<String> {
+        pos.map(|pos| self.describe(vram, map_tile_at(pos)))
+    }
+
+    fn click(&mut self, vram: &VramSnapshot, pos: UVec2) -> Option<String> {
+        self.selected = Some(map_tile_at(pos));
+
+        self.selected.map(|tile| self.describe(vram, tile))
+    }
+
+    fn next_palette(&mut self) -> Option<&'static str> {
+        self.palette = self.palette.next();
+
+        Some(self.palette.name())
+    }
+
+    fn next_map(&mut self) -> Option<String> {
+        self.choice = (self.choice + 1) % MAP_CHOICES.len();
+
+        Some(match MAP_CHOICES[self.choice] {
+            (Some(map), Some(unsigned)) => format!("${:04X} with tiles from ${:04X}", map, if unsigned { 0x8000 } else { 0x8800 }),
+            _ => "the BG's, as LCDC says".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> VramSnapshot {
+        VramSnapshot { vram: vec![0; 0x2000], lcdc: 0x91, scy: 0, scx: 0, wy: 0, wx: 7, bgp: 0xE4, obp0: 0, obp1: 0, colors: [[0; 3]; 4] }
+    }
+
+    #[test]
+    fn describes_where_tiles_are_used() {
+        assert_eq!(describe_tile(0x000), "tile $000 at $8000, index $00 for sprites, or the BG and window with LCDC.4 set");
+        assert_eq!(describe_tile(0x080), "tile $080 at $8800, index $80 for sprites, the BG and window");
+        assert_eq!(describe_tile(0x17F), "tile $17F at $97F0, index $7F for the BG and window with LCDC.4 clear");
+    }
+
+    #[test]
+    fn hovers_over_tiles() {
+        let mut viewer = new_tile_viewer();
+        let vram = snapshot();
+
+        assert_eq!(viewer.size(), UVec2::new(145, 217));
+
+        let mut hover = |x, y| viewer.hover(&vram, Some(UVec2::new(x, y)));
+
+        assert_eq!(hover(1, 1), Some(describe_tile(0)));
+        assert_eq!(hover(8, 8), Some(describe_tile(0)));
+        assert_eq!(hover(10, 1), Some(describe_tile(1)));
+        assert_eq!(hover(1, 10), Some(describe_tile(16)));
+
+        // The last column and row
+        assert_eq!(hover(137, 1), Some(describe_tile(15)));
+        assert_eq!(hover(137, 209), Some(describe_tile(TILES - 1)));
+    }
+
+    #[test]
+    fn hovers_over_grid_lines() {
+        let mut viewer = new_tile_viewer();
+        let vram = snapshot();
+        let mut hover = |x, y| viewer.hover(&vram, Some(UVec2::new(x, y)));
+
+        // The outer lines belong to the tiles next to them, the lines between to the tile before
+        assert_eq!(hover(0, 0), Some(describe_tile(0)));
+        assert_eq!(hover(9, 9), Some(describe_tile(0)));
+        assert_eq!(hover(144, 0), Some(describe_tile(15)));
+        assert_eq!(hover(144, 216), Some(describe_tile(TILES - 1)));
+    }
+
+    #[test]
+    fn forgets_the_tile_once_the_mouse_leaves() {
+        let mut viewer = new_tile_viewer();
+        let vram = snapshot();
+
+        viewer.hover(&vram, Some(UVec2::new(10, 1)));
+        assert_eq!(viewer.hovered, Some(1));
+
+        assert_eq!(viewer.hover(&vram, None), None);
+        assert_eq!(viewer.hovered, None);
+
+        // Past the last row, which the window doesn't send but nothing should be picked
+        assert_eq!(viewer.hover(&vram, Some(UVec2::new(1, 217))), None);
+    }
+
+    #[test]
+    fn finds_the_window_on_screen() {
+        let window = |lcdc, wx, wy| window_rect(&VramSnapshot { lcdc, wx, wy, ..snapshot() });
+
+        assert_eq!(window(0x91, 7, 0), None);
+        assert_eq!(window(0xB1, 7, 0), Some((0, 0, 160, 144)));
+        assert_eq!(window(0xB1, 87, 72), Some((80, 72, 80, 72)));
+
+        // WX below 7 still starts from the left edge
+        assert_eq!(window(0xB1, 0, 0), Some((0, 0, 160, 144)));
+        assert_eq!(window(0xB1, 3, 10), Some((0, 10, 160, 134)));
+
+        // Off the right and bottom edges
+        assert_eq!(window(0xB1, 166, 143), Some((159, 143, 1, 1)));
+        assert_eq!(window(0xB1, 167, 0), None);
+        assert_eq!(window(0xB1, 7, 144), None);
+        assert_eq!(window(0xB1, 255, 255), None);
+    }
+
+    #[test]
+    fn describes_map_tiles() {
+        let mut vram = snapshot();
+        vram.vram[0x1843] = 0x05;
+        vram.vram[0x1C43] = 0x85;
+
+        let viewer = new_map_viewer();
+
+        assert_eq!(viewer.describe(&vram, (3, 2)), "map $9843 (3, 2), index $05, tile $005 at $8050, no attributes without CGB VRAM bank 1");
+
+        // Signed indexes from LCDC
+        vram.lcdc = 0x81;
+        assert_eq!(viewer.describe(&vram, (3, 2)), "map $9843 (3, 2), index $05, tile $105 at $9050, no attributes without CGB VRAM bank 1");
+
+        // The other map from LCDC, signed indexes 0x80 and up are the same tiles
+        vram.lcdc = 0x89;
+        assert_eq!(viewer.describe(&vram, (3, 2)), "map $9C43 (3, 2), index $85, tile $085 at $8850, no attributes without CGB VRAM bank 1");
+    }
+
+    #[test]
+    fn picks_the_tile_under_the_mouse() {
+        let mut viewer = new_map_viewer();
+        let vram = snapshot();
+
+        assert_eq!(viewer.hover(&vram, None), None);
+        assert!(viewer.hover(&vram, Some(UVec2::new(7, 15))).unwrap().starts_with("map $9820 (0, 1)"));
+        assert!(viewer.click(&vram, UVec2::new(255, 255)).unwrap().starts_with("map $9BFF (31, 31)"));
+        assert_eq!(viewer.selected, Some((31, 31)));
+    }
+
+    #[test]
+    fn cycles_through_the_maps() {
+        let mut viewer = new_map_viewer();
+        let mut vram = snapshot();
+        vram.lcdc = 0x89;
+
+        let mut shown = vec!();
+
+        for _ in 0..5 {
+            let name = viewer.next_map().unwrap();
+            shown.push((name, viewer.map(&vram)));
+        }
+
+        assert_eq!(shown, vec!(
+            ("$9800 with tiles from $8000".to_string(), (0x9800, true)),
+            ("$9800 with tiles from $8800".to_string(), (0x9800, false)),
+            ("$9C00 with tiles from $8000".to_string(), (0x9C00, true)),
+            ("$9C00 with tiles from $8800".to_string(), (0x9C00, false)),
+            ("the BG's, as LCDC says".to_string(), (0x9C00, false)),
+        ));
+
+        // Following LCDC as it changes
+        vram.lcdc = 0x91;
+        assert_eq!(viewer.map(&vram), (0x9800, true));
+    }
+}
@@ -1,43 +1,300 @@
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
 use speedy2d::color::Color;
-use speedy2d::dimen::UVec2;
+use speedy2d::dimen::{UVec2, Vec2};
 use speedy2d::Graphics2D;
 use speedy2d::image::{ImageDataType, ImageSmoothingMode};
 use speedy2d::shape::Rectangle;
-use speedy2d::window::{KeyScancode, VirtualKeyCode, WindowHandler, WindowHelper, WindowStartupInfo};
-use crate::gameboy::keys::{KeyReg, Keys};
+use speedy2d::window::{KeyScancode, MouseButton, VirtualKeyCode, WindowHandler, WindowHelper, WindowStartupInfo};
+use crate::config::{Binding, Config, Hotkey, InputMacro};
+use crate::gameboy::control::Command;
+use crate::gameboy::input::Input;
+use crate::gameboy::state::SLOTS;
+use crate::gameboy::vram::VramShare;
+use crate::gamepad::{GamepadInput, open_gamepads};
+use crate::viewer::{new_map_viewer, new_tile_viewer, Viewer};
+
+const TITLE: &str = "Rusty GB";
+
+// GB pixels between the screen and each viewer
+const VIEWER_GAP: u32 = 4;
+
+// How often the gamepads are checked, about once a frame
+const GAMEPAD_POLL_INTERVAL: Duration = Duration::from_millis(16);
 
 pub struct GBWindowHandler {
     size: UVec2,
 
-    key_reg: Arc<KeyReg>,
+    // The size of the frames sent by the emulator, more than one screen when GBs are linked
+    screen_size: UVec2,
+
+    // One joypad per GB being shown
+    inputs: Vec<Arc<Input>>,
+
+    // Which joypad the keyboard is currently controlling
+    player: usize,
+
+    keyboard: HashMap<VirtualKeyCode, Binding>,
+
+    macros: Vec<InputMacro>,
+
+    // None when there is no gamepad support
+    gamepad: Option<GamepadInput>,
+
+    // None when the emulator doesn't take commands, e.g. when GBs are linked
+    commands: Option<Sender<Command>>,
+
+    // The save state slot used by the save and load hotkeys
+    slot: u8,
 
     frame: Vec<u8>,
+
+    // Where the GB copies VRAM to for the viewers, None when they aren't supported
+    vram: Option<VramShare>,
+
+    // Those open, to the right of the screen in the order opened, with the hotkey that opened each
+    viewers: Vec<(Hotkey, Box<dyn Viewer>)>,
+
+    // What the mouse is over in a viewer, which is shown in the title
+    hovered: Option<String>,
+
+    // Where the mouse is, in window pixels
+    mouse: Vec2,
 }
 
-pub fn new_gb_window_handler(key_reg: Arc<KeyReg>) -> GBWindowHandler {
+pub fn new_gb_window_handler(inputs: Vec<Arc<Input>>, screen_size: (u32, u32), config: Config, commands: Option<Sender<Command>>, vram: Option<VramShare>) -> GBWindowHandler {
     GBWindowHandler {
-        size: UVec2::from((160, 144)),
+        size: UVec2::from(screen_size),
+
+        screen_size: UVec2::from(screen_size),
+
+        inputs,
+
+        player: 0,
+
+        keyboard: config.keyboard,
+
+        macros: config.macros,
+
+        gamepad: open_gamepads(config.gamepad),
 
-        key_reg,
+        commands,
+
+        slot: 0,
 
         frame: vec!(),
+
+        vram,
+
+        viewers: vec!(),
+
+        hovered: None,
+
+        mouse: Vec2::ZERO,
     }
 }
 
 impl GBWindowHandler {
-    fn map_vkc_to_key(&self, scancode: Option<VirtualKeyCode>) -> Option<Keys> {
-        match scancode {
-            Some(VirtualKeyCode::Return) => Some(Keys::START), // Enter
-            Some(VirtualKeyCode::Space) => Some(Keys::SELECT), // Space
-            Some(VirtualKeyCode::Left) => Some(Keys::LEFT), // Left Arrow
-            Some(VirtualKeyCode::Up) => Some(Keys::UP), // Up Arrow
-            Some(VirtualKeyCode::Right) => Some(Keys::RIGHT), // Right Arrow
-            Some(VirtualKeyCode::Down) => Some(Keys::DOWN), // Down Arrow
-            Some(VirtualKeyCode::S) => Some(Keys::B), // X
-            Some(VirtualKeyCode::A) => Some(Keys::A), // Z
-            _ => None,
+    fn map_vkc_to_binding(&self, virtual_key_code: Option<VirtualKeyCode>) -> Option<Binding> {
+        self.keyboard.get(&virtual_key_code?).copied()
+    }
+
+    fn apply_binding(&mut self, helper: &mut WindowHelper<Vec<u8>>, player: usize, binding: Binding, pressed: bool) {
+        match binding {
+            Binding::Button(k) if pressed => self.inputs[player].key_down(k),
+            Binding::Button(k) => self.inputs[player].key_up(k),
+            Binding::Turbo(k) if pressed => self.inputs[player].turbo_down(k),
+            Binding::Turbo(k) => self.inputs[player].turbo_up(k),
+            Binding::Hotkey(h) => self.on_hotkey(helper, h, pressed),
+            Binding::Macro(i) if pressed => {
+                println!("Running macro {}", self.macros[i].name);
+                self.inputs[player].play_macro(&self.macros[i].steps)
+            }
+            Binding::Macro(_) => {}
+        }
+    }
+
+    fn poll_gamepads(&mut self, helper: &mut WindowHelper<Vec<u8>>) {
+        let actions = match &mut self.gamepad {
+            None => return,
+            Some(gamepad) => gamepad.poll(self.inputs.len()),
+        };
+
+        for action in actions {
+            self.apply_binding(helper, action.player, action.binding, action.pressed);
+        }
+    }
+
+    fn on_hotkey(&mut self, helper: &mut WindowHelper<Vec<u8>>, hotkey: Hotkey, pressed: bool) {
+        // Most hotkeys only act when pressed, the rest are held
+        match hotkey {
+            Hotkey::Rewind => return self.send_command(Command::Rewind(pressed)),
+            Hotkey::FastForward => return self.send_command(Command::FastForward(pressed)),
+            _ if !pressed => return,
+            _ => {}
+        }
+
+        match hotkey {
+            Hotkey::NextPlayer => self.player = (self.player + 1) % self.inputs.len(),
+            Hotkey::RecordMacro => match self.inputs[self.player].toggle_recording() {
+                None => println!("Recording macro"),
+                Some(frames) => println!("Recorded macro of {} frames", frames),
+            },
+            Hotkey::PlayMacro => self.inputs[self.player].play_recorded(),
+            Hotkey::SaveState => self.send_command(Command::SaveState(self.slot)),
+            Hotkey::LoadState => self.send_command(Command::LoadState(self.slot)),
+            Hotkey::NextSlot => {
+                self.slot = (self.slot + 1) % SLOTS;
+                println!("Save state slot {}", self.slot);
+            }
+            Hotkey::PreviousSlot => {
+                self.slot = (self.slot + SLOTS - 1) % SLOTS;
+                println!("Save state slot {}", self.slot);
+            }
+            Hotkey::Pause => self.send_command(Command::TogglePause),
+            Hotkey::FrameAdvance => self.send_command(Command::FrameAdvance),
+            Hotkey::StepInstruction => self.send_command(Command::StepInstruction),
+            Hotkey::SlowMotion => self.send_command(Command::ToggleSlowMotion),
+            Hotkey::Reset => self.send_command(Command::SoftReset),
+            Hotkey::HardReset => self.send_command(Command::HardReset),
+            Hotkey::Debug => self.send_command(Command::Break),
+            Hotkey::TileViewer => self.toggle_viewer(helper, hotkey, || Box::new(new_tile_viewer())),
+            Hotkey::MapViewer => self.toggle_viewer(helper, hotkey, || Box::new(new_map_viewer())),
+            Hotkey::ViewerPalette => {
+                for (_, viewer) in &mut self.viewers {
+                    if let Some(name) = viewer.next_palette() {
+                        println!("Viewer palette {}", name);
+                    }
+                }
+
+                helper.request_redraw();
+            }
+            Hotkey::ViewerMap => {
+                for (_, viewer) in &mut self.viewers {
+                    if let Some(map) = viewer.next_map() {
+                        println!("Viewing map {}", map);
+                    }
+                }
+
+                self.hover(helper);
+                helper.request_redraw();
+            }
+            Hotkey::Rewind | Hotkey::FastForward => {}
+        }
+    }
+
+    /*
+        Opens the viewer if it isn't open or closes it if it is, growing or shrinking the window to
+        keep everything the same size on screen
+     */
+    fn toggle_viewer(&mut self, helper: &mut WindowHelper<Vec<u8>>, hotkey: Hotkey, new_viewer: impl FnOnce() -> Box<dyn Viewer>) {
+        if self.vram.is_none() || self.commands.is_none() {
+            return eprintln!("The VRAM viewers aren't supported here");
+        }
+
+        let scale = self.scale();
+
+        match self.viewers.iter().position(|(h, _)| *h == hotkey) {
+            Some(n) => { self.viewers.remove(n); }
+            None => self.viewers.push((hotkey, new_viewer())),
+        }
+
+        self.send_command(Command::ViewVram(!self.viewers.is_empty()));
+
+        let layout = self.layout_size();
+
+        self.size = UVec2::new((layout.x as f32 * scale.x).round() as u32, (layout.y as f32 * scale.y).round() as u32);
+        helper.set_size_pixels(self.size);
+
+        self.set_hovered(helper, None);
+        helper.request_redraw();
+    }
+
+    /*
+        The size of the screen and the open viewers side by side, in GB pixels
+     */
+    fn layout_size(&self) -> UVec2 {
+        self.viewers.iter().fold(self.screen_size, |size, (_, viewer)| {
+            UVec2::new(size.x + VIEWER_GAP + viewer.size().x, size.y.max(viewer.size().y))
+        })
+    }
+
+    /*
+        Where the nth viewer's left edge is, in GB pixels
+     */
+    fn viewer_x(&self, n: usize) -> u32 {
+        self.screen_size.x + self.viewers[..n].iter().map(|(_, viewer)| VIEWER_GAP + viewer.size().x).sum::<u32>() + VIEWER_GAP
+    }
+
+    /*
+        Window pixels per GB pixel. The layout is stretched to fill the window, like the screen always has been.
+     */
+    fn scale(&self) -> Vec2 {
+        let layout = self.layout_size();
+
+        Vec2::new(self.size.x as f32 / layout.x as f32, self.size.y as f32 / layout.y as f32)
+    }
+
+    /*
+        The viewer under position in window pixels, and where in it
+     */
+    fn viewer_at(&self, position: Vec2) -> Option<(usize, UVec2)> {
+        let scale = self.scale();
+        let (x, y) = (position.x / scale.x, position.y / scale.y);
+
+        (0..self.viewers.len()).find_map(|n| {
+            let (left, size) = (self.viewer_x(n) as f32, self.viewers[n].1.size());
+
+            (x >= left && x < left + size.x as f32 && y >= 0.0 && y < size.y as f32)
+                .then(|| (n, UVec2::new((x - left) as u32, y as u32)))
+        })
+    }
+
+    /*
+        Tells each viewer whether the mouse is over it
+     */
+    fn hover(&mut self, helper: &mut WindowHelper<Vec<u8>>) {
+        let vram = match self.vram.as_ref().and_then(|vram| vram.latest()) {
+            Some(vram) => vram,
+            None => return,
+        };
+
+        let over = self.viewer_at(self.mouse);
+        let mut hovered = None;
+
+        for (n, (_, viewer)) in self.viewers.iter_mut().enumerate() {
+            let pos = over.filter(|(at, _)| *at == n).map(|(_, pos)| pos);
+
+            if let Some(description) = viewer.hover(&vram, pos) {
+                hovered = Some(description);
+            }
+        }
+
+        self.set_hovered(helper, hovered);
+    }
+
+    fn set_hovered(&mut self, helper: &mut WindowHelper<Vec<u8>>, hovered: Option<String>) {
+        if hovered == self.hovered {
+            return;
+        }
+
+        match &hovered {
+            Some(description) => helper.set_title(format!("{} - {}", TITLE, description)),
+            None => helper.set_title(TITLE),
+        }
+
+        self.hovered = hovered;
+        helper.request_redraw();
+    }
+
+    fn send_command(&self, command: Command) {
+        match &self.commands {
+            // The emulator thread only stops if it has panicked, which will already have been reported
+            Some(commands) => { let _ = commands.send(command); }
+            None => eprintln!("{:?} isn't supported here", command),
         }
     }
 }
@@ -45,25 +302,43 @@ impl GBWindowHandler {
 impl WindowHandler<Vec<u8>> for GBWindowHandler {
     fn on_start(&mut self, helper: &mut WindowHelper<Vec<u8>>, info: WindowStartupInfo) {
         self.size = *info.viewport_size_pixels();
+
+        // Frames stop arriving while paused, so the gamepads are woken up for separately with an
+        // empty event. Stops once the window has gone.
+        if self.gamepad.is_some() {
+            let sender = helper.create_user_event_sender();
+
+            thread::spawn(move || {
+                while sender.send_event(vec!()).is_ok() {
+                    thread::sleep(GAMEPAD_POLL_INTERVAL);
+                }
+            });
+        }
     }
-    
+
     fn on_user_event(&mut self, helper: &mut WindowHelper<Vec<u8>>, user_event: Vec<u8>) {
-        self.frame = user_event;
+        self.poll_gamepads(helper);
 
-        helper.request_redraw();
+        // Empty when only checking the gamepads
+        if !user_event.is_empty() {
+            self.frame = user_event;
+            helper.request_redraw();
+        }
     }
 
-    fn on_key_down(&mut self, helper: &mut WindowHelper<Vec<u8>>, virtual_key_code: Option<VirtualKeyCode>, scancode: KeyScancode) {
-        match self.map_vkc_to_key(virtual_key_code) {
-            None => {}
-            Some(k) => self.key_reg.key_down(k)
+    fn on_key_down(&mut self, helper: &mut WindowHelper<Vec<u8>>, virtual_key_code: Option<VirtualKeyCode>, _scancode: KeyScancode) {
+        if let Some(binding) = self.map_vkc_to_binding(virtual_key_code) {
+            self.apply_binding(helper, self.player, binding, true);
         }
     }
 
-    fn on_key_up(&mut self, helper: &mut WindowHelper<Vec<u8>>, virtual_key_code: Option<VirtualKeyCode>, scancode: KeyScancode) {
-        match self.map_vkc_to_key(virtual_key_code) {
-            None => {}
-            Some(k) => self.key_reg.key_up(k)
+    fn on_key_up(&mut self, helper: &mut WindowHelper<Vec<u8>>, virtual_key_code: Option<VirtualKeyCode>, _scancode: KeyScancode) {
+        // Released on every joypad so keys don't stick down if the player changes while they are held
+        match self.map_vkc_to_binding(virtual_key_code) {
+            Some(Binding::Button(k)) => self.inputs.iter().for_each(|input| input.key_up(k)),
+            Some(Binding::Turbo(k)) => self.inputs.iter().for_each(|input| input.turbo_up(k)),
+            Some(Binding::Hotkey(h)) => self.on_hotkey(helper, h, false),
+            _ => {}
         }
     }
 
@@ -73,10 +348,56 @@ impl WindowHandler<Vec<u8>> for GBWindowHandler {
         helper.request_redraw();
     }
 
-    fn on_draw(&mut self, helper: &mut WindowHelper<Vec<u8>>, graphics: &mut Graphics2D)
+    fn on_mouse_move(&mut self, helper: &mut WindowHelper<Vec<u8>>, position: Vec2) {
+        self.mouse = position;
+
+        if !self.viewers.is_empty() {
+            self.hover(helper);
+        }
+    }
+
+    fn on_mouse_button_down(&mut self, helper: &mut WindowHelper<Vec<u8>>, button: MouseButton) {
+        let (n, pos) = match (button, self.viewer_at(self.mouse)) {
+            (MouseButton::Left, Some(at)) => at,
+            _ => return,
+        };
+
+        let vram = match self.vram.as_ref().and_then(|vram| vram.latest()) {
+            Some(vram) => vram,
+            None => return,
+        };
+
+        // Printed so it's still there once the mouse moves on
+        if let Some(description) = self.viewers[n].1.click(&vram, pos) {
+            println!("{}", description);
+        }
+
+        helper.request_redraw();
+    }
+
+    fn on_draw(&mut self, _helper: &mut WindowHelper<Vec<u8>>, graphics: &mut Graphics2D)
     {
-        let image = graphics.create_image_from_raw_pixels(ImageDataType::RGB, ImageSmoothingMode::NearestNeighbor, (160, 144), &self.frame).unwrap();
+        let scale = self.scale();
+
+        if !self.viewers.is_empty() {
+            graphics.clear_screen(Color::BLACK);
+        }
 
-        graphics.draw_rectangle_image(Rectangle::from_tuples((0.0, 0.0), (self.size.x as f32, self.size.y as f32)), &image);
+        let image = graphics.create_image_from_raw_pixels(ImageDataType::RGB, ImageSmoothingMode::NearestNeighbor, self.screen_size, &self.frame).unwrap();
+
+        graphics.draw_rectangle_image(Rectangle::from_tuples((0.0, 0.0), (self.screen_size.x as f32 * scale.x, self.screen_size.y as f32 * scale.y)), &image);
+
+        // Nothing to show until the GB has sent VRAM over
+        let vram = match self.vram.as_ref().and_then(|vram| vram.latest()) {
+            Some(vram) => vram,
+            None => return,
+        };
+
+        for (n, (_, viewer)) in self.viewers.iter().enumerate() {
+            let (x, size) = (self.viewer_x(n) as f32, viewer.size());
+            let image = graphics.create_image_from_raw_pixels(ImageDataType::RGB, ImageSmoothingMode::NearestNeighbor, size, &viewer.render(&vram)).unwrap();
+
+            graphics.draw_rectangle_image(Rectangle::from_tuples((x * scale.x, 0.0), ((x + size.x as f32) * scale.x, size.y as f32 * scale.y)), &image);
+        }
     }
-}
\ No newline at end of file
+}